@@ -0,0 +1,507 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process;
+use std::sync::Arc;
+use fefs::device::BlockDevice;
+use fefs::dir::DirEntry;
+use fefs::dir::DirError;
+use fefs::file::FileError;
+use fefs::file::WriteType;
+use fefs::host::extract;
+use fefs::host::populate;
+use fefs::host::FileDevice;
+use fefs::chacha::Key;
+use fefs::inode::INode;
+use fefs::inode::FLAG_EXTENTS;
+use fefs::quota::QuotaKind;
+use fefs::system::FileSystem;
+
+const USAGE: &str = "\
+usage: fefs [-k <id>=<hex key>]... <image> <command> [args]
+
+options:
+    -k <id>=<hex key>       add a 256-bit key to the keyring, the id is not 0
+
+commands:
+    ls [-l] [path]          list a directory
+    cat <path>              print a file
+    put <host> <guest>      copy a host file into the image
+    get <guest> <host>      copy a file out of the image
+    rm [-r] <path>          remove a file, or a directory with -r
+    mkdir [-p] <path>       make a directory, and its parents with -p
+    mv <from> <to>          move or rename an entry
+    stat <path>             print the inode of an entry
+    import <host> [guest]   copy a host directory tree into a directory
+    export <guest> <host>   copy a directory tree out to a host directory
+    snapshot [-d] <name>    freeze the current tree as a snapshot, or delete one with -d
+    snapshots               list the snapshots
+    rollback <name>         replace the current tree with a snapshot
+    checksum [-d] <path>    checksum the data of a file, or stop with -d
+    compress [-d] <path>    compress the data of a file, or store it plainly with -d
+    verify <path>           check the data of a file against its checksums
+    encrypt <path> <id>     encrypt a file, or the entries created in a directory, with a key
+    decrypt <path>          decrypt a file, or stop encrypting a directory
+    xattr <path> [key [value]]
+                            list the attributes of an entry, print one, or set it
+    xattr -d <path> <key>   remove an attribute
+    df                      print the space and entries used and free
+    quota [-u|-g <id> <soft> <hard>]
+                            list the usage in clusters, or set the limits of a user or group,
+                            0 for no limit";
+
+fn fail(msg: &str) -> ! {
+    eprintln!("fefs: {}", msg);
+    process::exit(1)
+}
+
+fn error(path: &str, err: DirError) -> ! {
+    let msg = match err {
+        DirError::NotFound => "no such file or directory",
+        DirError::NotFoundDir => "not a directory",
+        DirError::NotFoundFile => "not a file",
+        DirError::IllegalChar => "illegal character in name",
+        DirError::DirExist => "directory exists",
+        DirError::FileExist => "file exists",
+        DirError::NameTooLong => "name too long",
+        DirError::ReadOnly => "read-only snapshot",
+        DirError::Corrupted => "directory checksum mismatch",
+        DirError::NoKey => "key not in the keyring",
+        DirError::NoAttr => "no such attribute",
+        DirError::BadAttr => "attribute name or value too long",
+        DirError::QuotaExceeded => "disk quota exceeded",
+        DirError::IntoItself => "cannot move a directory into itself",
+    };
+    fail(&format!("{}: {}", path, msg))
+}
+
+fn file_error(path: &str, err: FileError) -> ! {
+    let msg = match err {
+        FileError::SeekValueOverFlow => "offset past the end of the file",
+        FileError::ReadOnly => "read-only snapshot",
+        FileError::Corrupted => "corrupted data",
+        FileError::NoKey => "key not in the keyring",
+        FileError::NoAttr => "no such attribute",
+        FileError::BadAttr => "attribute name or value too long",
+        FileError::QuotaExceeded => "disk quota exceeded",
+    };
+    fail(&format!("{}: {}", path, msg))
+}
+
+fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|c| !c.is_empty() && *c != ".").collect()
+}
+
+// split a guest path into its parent components and last name
+fn split(path: &str) -> (Vec<&str>, &str) {
+    let mut comps = components(path);
+    match comps.pop() {
+        Some(name) => (comps, name),
+        None => fail(&format!("{}: not allowed on the root directory", path)),
+    }
+}
+
+fn walk(root: DirEntry, comps: &[&str], path: &str) -> DirEntry {
+    let mut dir = root;
+    for comp in comps {
+        dir = dir.cd(comp).unwrap_or_else(|err| error(path, err));
+    }
+    dir
+}
+
+fn open_dir(fs: &FileSystem, path: &str) -> DirEntry {
+    walk(fs.root(), &components(path), path)
+}
+
+fn open_parent<'a>(fs: &FileSystem, path: &'a str) -> (DirEntry, &'a str) {
+    let (comps, name) = split(path);
+    (walk(fs.root(), &comps, path), name)
+}
+
+fn lookup(fs: &FileSystem, path: &str) -> Option<INode> {
+    let (dir, name) = open_parent(fs, path);
+    dir.lookup(name)
+}
+
+fn is_dir(fs: &FileSystem, path: &str) -> bool {
+    components(path).is_empty() || matches!(lookup(fs, path), Some(inode) if inode.is_dir())
+}
+
+fn type_char(inode: &INode) -> char {
+    if inode.is_dir() { 'd' } else { '-' }
+}
+
+fn ls(fs: &FileSystem, args: &[String]) {
+    let long = args.iter().any(|arg| arg == "-l");
+    let path = args.iter().find(|arg| !arg.starts_with('-')).map_or("/", |arg| arg.as_str());
+    let inodes = if is_dir(fs, path) {
+        let dir = open_dir(fs, path);
+        dir.verify().unwrap_or_else(|err| error(path, err));
+        dir.ls()
+    } else {
+        match lookup(fs, path) {
+            Some(inode) => vec![inode],
+            None => error(path, DirError::NotFound),
+        }
+    };
+
+    for inode in inodes.iter() {
+        if long {
+            println!(
+                "{}{:04o} {:>5} {:>5} {:>10} {:>10} {}",
+                type_char(inode),
+                inode.mode() & 0o7777,
+                inode.uid(),
+                inode.gid(),
+                inode.size(),
+                inode.mtime(),
+                inode.name()
+            );
+        } else {
+            println!("{}", inode.name());
+        }
+    }
+}
+
+fn cat(fs: &FileSystem, path: &str) {
+    let (dir, name) = open_parent(fs, path);
+    let file = dir.open_file(name).unwrap_or_else(|err| error(path, err));
+    let mut buf = Vec::new();
+    file.read_to_vec(&mut buf).unwrap_or_else(|err| file_error(path, err));
+    std::io::stdout().write_all(&buf).unwrap();
+}
+
+fn put(fs: &FileSystem, host: &str, guest: &str) {
+    let data = fs::read(host).unwrap_or_else(|err| fail(&format!("{}: {}", host, err)));
+    let (mut dir, name) = if is_dir(fs, guest) {
+        let name = host.rsplit('/').next().unwrap();
+        (open_dir(fs, guest), name)
+    } else {
+        open_parent(fs, guest)
+    };
+
+    let mut file = match dir.open_file(name) {
+        Ok(file) => file,
+        Err(_) => dir.create_file(name).unwrap_or_else(|err| error(guest, err)),
+    };
+    file.write(&data, WriteType::OverWritten).unwrap_or_else(|err| file_error(guest, err));
+}
+
+fn get(fs: &FileSystem, guest: &str, host: &str) {
+    let (dir, name) = open_parent(fs, guest);
+    let file = dir.open_file(name).unwrap_or_else(|err| error(guest, err));
+    let mut buf = Vec::new();
+    file.read_to_vec(&mut buf).unwrap_or_else(|err| file_error(guest, err));
+    fs::write(host, &buf).unwrap_or_else(|err| fail(&format!("{}: {}", host, err)));
+}
+
+fn rm(fs: &FileSystem, args: &[String]) {
+    let recursive = args.iter().any(|arg| arg == "-r" || arg == "-rf");
+    for path in args.iter().filter(|arg| !arg.starts_with('-')) {
+        let (mut dir, name) = open_parent(fs, path);
+        match dir.lookup(name) {
+            Some(inode) if inode.is_dir() && !recursive => {
+                fail(&format!("{}: is a directory, use -r", path))
+            }
+            Some(_) => dir.delete(name).unwrap_or_else(|err| error(path, err)),
+            None => error(path, DirError::NotFound),
+        }
+    }
+}
+
+fn mkdir(fs: &FileSystem, args: &[String]) {
+    let parents = args.iter().any(|arg| arg == "-p");
+    for path in args.iter().filter(|arg| !arg.starts_with('-')) {
+        let (comps, name) = split(path);
+        let mut dir = fs.root();
+        for comp in comps {
+            dir = match dir.cd(comp) {
+                Ok(sub) => sub,
+                Err(_) if parents => dir.mkdir(comp).unwrap_or_else(|err| error(path, err)),
+                Err(err) => error(path, err),
+            };
+        }
+        match dir.mkdir(name) {
+            Ok(_) => {}
+            Err(DirError::DirExist) if parents && dir.cd(name).is_ok() => {}
+            Err(err) => error(path, err),
+        }
+    }
+}
+
+fn mv(fs: &FileSystem, from: &str, to: &str) {
+    let (from_comps, from_name) = split(from);
+    let (to_comps, to_name) = if is_dir(fs, to) {
+        (components(to), from_name)
+    } else {
+        split(to)
+    };
+
+    let mut inside = from_comps.clone();
+    inside.push(from_name);
+    if to_comps.starts_with(&inside) {
+        fail(&format!("cannot move {} into itself", from))
+    }
+
+    let mut src = walk(fs.root(), &from_comps, from);
+    if from_comps == to_comps {
+        src.rename(from_name, to_name).unwrap_or_else(|err| error(to, err));
+    } else {
+        let mut dest = walk(fs.root(), &to_comps, to);
+        src.move_to(from_name, &mut dest, to_name).unwrap_or_else(|err| error(to, err));
+    }
+}
+
+fn stat(fs: &FileSystem, path: &str) {
+    if components(path).is_empty() {
+        println!("  name     /");
+        println!("  type     directory");
+        println!("  cluster  {}", fs.sblock().root_cluster());
+        return;
+    }
+
+    let inode = lookup(fs, path).unwrap_or_else(|| error(path, DirError::NotFound));
+    println!("  name     {}", inode.name());
+    println!("  type     {}", if inode.is_dir() { "directory" } else { "file" });
+    println!("  size     {}", inode.size());
+    println!("  mode     {:04o}", inode.mode() & 0o7777);
+    println!("  uid      {}", inode.uid());
+    println!("  gid      {}", inode.gid());
+    println!("  links    {}", inode.links_count());
+    println!("  atime    {}", inode.atime());
+    println!("  ctime    {}", inode.ctime());
+    println!("  mtime    {}", inode.mtime());
+    println!("  cluster  {}", inode.cluster());
+    if inode.key_id() != 0 {
+        println!("  key      {}", inode.key_id());
+    }
+    if inode.is_file() {
+        let (dir, name) = open_parent(fs, path);
+        let file = dir.open_file(name).unwrap_or_else(|err| error(path, err));
+        let mut flags = Vec::new();
+        if file.is_checksummed() {
+            flags.push("checksum");
+        }
+        if file.is_compressed() {
+            flags.push("compressed");
+        }
+        if file.is_encrypted() {
+            flags.push("encrypted");
+        }
+        if inode.flags() & FLAG_EXTENTS != 0 {
+            flags.push("extents");
+        }
+        println!("  physical {}", file.metadata().physical());
+        println!("  extents  {}", file.extents().len());
+        println!("  flags    {}", flags.join(","));
+    }
+}
+
+fn import(fs: &FileSystem, host: &str, guest: &str) {
+    let mut dir = open_dir(fs, guest);
+    let skipped = populate(&mut dir, host).unwrap_or_else(|err| fail(&format!("{}: {}", host, err)));
+    for skip in skipped.iter() {
+        eprintln!("fefs: skipped {}: {:?}", skip.path.display(), skip.reason);
+    }
+}
+
+fn export(fs: &FileSystem, guest: &str, host: &str) {
+    if !is_dir(fs, guest) {
+        error(guest, DirError::NotFoundDir)
+    }
+    extract(&open_dir(fs, guest), host).unwrap_or_else(|err| fail(&format!("{}: {}", host, err)));
+}
+
+fn checksum(fs: &FileSystem, args: &[String]) {
+    let (on, path) = match args {
+        [flag, path] if flag == "-d" => (false, path),
+        [path] => (true, path),
+        _ => fail(&format!("usage: checksum [-d] <path>\n\n{}", USAGE)),
+    };
+    let (dir, name) = open_parent(fs, path);
+    let mut file = dir.open_file(name).unwrap_or_else(|err| error(path, err));
+    file.set_checksummed(on).unwrap_or_else(|err| file_error(path, err));
+}
+
+fn compress(fs: &FileSystem, args: &[String]) {
+    let (on, path) = match args {
+        [flag, path] if flag == "-d" => (false, path),
+        [path] => (true, path),
+        _ => fail(&format!("usage: compress [-d] <path>\n\n{}", USAGE)),
+    };
+    let (dir, name) = open_parent(fs, path);
+    let mut file = dir.open_file(name).unwrap_or_else(|err| error(path, err));
+    file.set_compressed(on).unwrap_or_else(|err| file_error(path, err));
+}
+
+fn xattr(fs: &FileSystem, args: &[String]) {
+    match args {
+        [flag, path, key] if flag == "-d" => {
+            let (mut dir, name) = open_parent(fs, path);
+            dir.remove_xattr(name, key).unwrap_or_else(|err| error(path, err));
+        }
+        [path] => {
+            let (dir, name) = open_parent(fs, path);
+            for key in dir.list_xattr(name).unwrap_or_else(|err| error(path, err)) {
+                println!("{}", key);
+            }
+        }
+        [path, key] => {
+            let (dir, name) = open_parent(fs, path);
+            let value = dir.get_xattr(name, key).unwrap_or_else(|err| error(path, err));
+            std::io::stdout().write_all(&value).unwrap();
+            println!();
+        }
+        [path, key, value] => {
+            let (mut dir, name) = open_parent(fs, path);
+            dir.set_xattr(name, key, value.as_bytes()).unwrap_or_else(|err| error(path, err));
+        }
+        _ => fail(&format!("usage: xattr [-d] <path> [key [value]]\n\n{}", USAGE)),
+    }
+}
+
+fn df(fs: &FileSystem) {
+    let stat = fs.statfs();
+    let bytes = |clusters: usize| clusters * stat.cluster_size();
+    println!("{:>12} {:>12} {:>12} {:>5} {:>8} {:>8}", "size", "used", "avail", "use%", "entries", "namemax");
+    println!(
+        "{:>12} {:>12} {:>12} {:>4}% {:>8} {:>8}",
+        bytes(stat.clusters()),
+        bytes(stat.used_clusters()),
+        bytes(stat.free_clusters()),
+        stat.used_clusters() * 100 / stat.clusters().max(1),
+        stat.inodes(),
+        stat.name_len()
+    );
+}
+
+fn quota(fs: &FileSystem, args: &[String]) {
+    match args {
+        [] => {
+            for (kind, id, quota) in fs.quotas() {
+                let kind = match kind {
+                    QuotaKind::User => "user",
+                    QuotaKind::Group => "group",
+                };
+                let over = if quota.over_soft() { " over soft limit" } else { "" };
+                println!("{:<5} {:>5} {:>8} {:>8} {:>8}{}", kind, id, quota.used(), quota.soft(), quota.hard(), over);
+            }
+        }
+        [flag, id, soft, hard] if flag == "-u" || flag == "-g" => {
+            let kind = if flag == "-u" { QuotaKind::User } else { QuotaKind::Group };
+            let number = |arg: &str| arg.parse().unwrap_or_else(|_| fail(&format!("{}: bad number", arg)));
+            if !fs.set_quota(kind, number(id) as u16, number(soft), number(hard)) {
+                fail("no quota file on this volume");
+            }
+        }
+        _ => fail(&format!("usage: quota [-u|-g <id> <soft> <hard>]\n\n{}", USAGE)),
+    }
+}
+
+// `<id>=<64 hex digits>`
+fn parse_key(arg: &str) -> (u32, Key) {
+    let bad = || -> ! { fail(&format!("{}: expected <id>=<64 hex digits>", arg)) };
+    let at = arg.find('=').unwrap_or_else(|| bad());
+    let (id, hex) = (&arg[..at], &arg[at + 1..]);
+    let id = match id.parse() {
+        Ok(0) | Err(_) => bad(),
+        Ok(id) => id,
+    };
+    if hex.len() != 64 || !hex.is_ascii() {
+        bad();
+    }
+    let mut key = [0; 32];
+    for (idx, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).unwrap_or_else(|_| bad());
+    }
+    (id, key)
+}
+
+// a directory only passes its key on to the entries created in it
+fn set_key(fs: &FileSystem, path: &str, key_id: u32) {
+    let (mut dir, name) = open_parent(fs, path);
+    if is_dir(fs, path) {
+        dir.set_key(name, key_id).unwrap_or_else(|err| error(path, err));
+    } else {
+        let mut file = dir.open_file(name).unwrap_or_else(|err| error(path, err));
+        file.set_key(key_id).unwrap_or_else(|err| file_error(path, err));
+    }
+}
+
+fn verify(fs: &FileSystem, path: &str) {
+    let (dir, name) = open_parent(fs, path);
+    let file = dir.open_file(name).unwrap_or_else(|err| error(path, err));
+    if !file.is_checksummed() && !file.is_compressed() {
+        fail(&format!("{}: no data checksums", path));
+    }
+    file.verify().unwrap_or_else(|err| file_error(path, err));
+}
+
+fn snapshot(fs: &mut FileSystem, args: &[String]) {
+    let (delete, name) = match args {
+        [flag, name] if flag == "-d" => (true, name),
+        [name] => (false, name),
+        _ => fail(&format!("usage: snapshot [-d] <name>\n\n{}", USAGE)),
+    };
+    let ret = match delete {
+        true => fs.delete_snapshot(name),
+        false => fs.snapshot(name),
+    };
+    ret.unwrap_or_else(|err| error(name, err));
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut keys = Vec::new();
+    while args.len() >= 2 && args[0] == "-k" {
+        keys.push(parse_key(&args[1]));
+        args.drain(0..2);
+    }
+    if args.len() < 2 || args[0] == "-h" || args[0] == "--help" {
+        println!("{}", USAGE);
+        process::exit(if args.is_empty() { 1 } else { 0 })
+    }
+
+    let device = FileDevice::open(&args[0])
+        .unwrap_or_else(|err| fail(&format!("{}: {}", args[0], err)));
+    let device: Arc<dyn BlockDevice> = Arc::new(device);
+    let fs = FileSystem::open(device);
+    let mut fs = fs.lock();
+    for (id, key) in keys {
+        fs.add_key(id, key);
+    }
+
+    let rest = &args[2..];
+    let arg = |idx: usize| match rest.get(idx) {
+        Some(arg) => arg.as_str(),
+        None => fail(&format!("missing argument for {}\n\n{}", args[1], USAGE)),
+    };
+    match args[1].as_str() {
+        "ls" => ls(&fs, rest),
+        "cat" => cat(&fs, arg(0)),
+        "put" => put(&fs, arg(0), arg(1)),
+        "get" => get(&fs, arg(0), arg(1)),
+        "rm" => rm(&fs, rest),
+        "mkdir" => mkdir(&fs, rest),
+        "mv" => mv(&fs, arg(0), arg(1)),
+        "stat" => stat(&fs, arg(0)),
+        "import" => import(&fs, arg(0), rest.get(1).map_or("/", |arg| arg.as_str())),
+        "export" => export(&fs, arg(0), arg(1)),
+        "snapshot" => snapshot(&mut fs, rest),
+        "snapshots" => fs.snapshots().iter().for_each(|name| println!("{}", name)),
+        "rollback" => fs.rollback(arg(0)).unwrap_or_else(|err| error(arg(0), err)),
+        "checksum" => checksum(&fs, rest),
+        "compress" => compress(&fs, rest),
+        "verify" => verify(&fs, arg(0)),
+        "encrypt" => match arg(1).parse() {
+            Ok(id) if id != 0 => set_key(&fs, arg(0), id),
+            _ => fail(&format!("{}: bad key id", arg(1))),
+        },
+        "decrypt" => set_key(&fs, arg(0), 0),
+        "xattr" => xattr(&fs, rest),
+        "df" => df(&fs),
+        "quota" => quota(&fs, rest),
+        cmd => fail(&format!("unknown command {}\n\n{}", cmd, USAGE)),
+    }
+    fs.sync();
+}
@@ -0,0 +1,89 @@
+use std::env;
+use std::process;
+use std::sync::Arc;
+use fefs::device::BlockDevice;
+use fefs::fsck::check;
+use fefs::fsck::repair;
+use fefs::host::FileDevice;
+
+const USAGE: &str = "\
+usage: fsck-fefs [options] <image>
+
+options:
+    -y, --repair        fix the problems found
+    -n, --dry-run       print the fixes --repair would make, write nothing
+        --free-lost     free lost clusters instead of keeping them in /lost+found
+    -h, --help          print this message
+
+every problem and fix is printed as one line, its kind then key=value pairs,
+fixes still needed when the repair gives up are prefixed with left,
+followed by a summary line
+
+exit status, as fsck(8):
+    0   no problems
+    1   problems corrected
+    4   problems left uncorrected
+    8   operational error";
+
+fn fail(msg: &str) -> ! {
+    eprintln!("fsck-fefs: {}", msg);
+    process::exit(8)
+}
+
+fn main() {
+    let mut image = None;
+    let mut fix = false;
+    let mut dry_run = false;
+    let mut salvage = true;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "-y" | "--repair" => fix = true,
+            "-n" | "--dry-run" => dry_run = true,
+            "--free-lost" => salvage = false,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0)
+            }
+            _ if arg.starts_with('-') => fail(&format!("unknown option {}\n\n{}", arg, USAGE)),
+            _ if image.is_none() => image = Some(arg),
+            _ => fail(&format!("unexpected argument {}", arg)),
+        }
+    }
+    let image = image.unwrap_or_else(|| fail(USAGE));
+
+    let device = FileDevice::open(&image)
+        .unwrap_or_else(|err| fail(&format!("{}: {}", image, err)));
+    let device: Arc<dyn BlockDevice> = Arc::new(device);
+
+    let (report, actions, left) = if fix || dry_run {
+        repair(&device, salvage, dry_run)
+    } else {
+        (check(&device), Vec::new(), Vec::new())
+    };
+    for problem in report.problems.iter() {
+        println!("{}", problem);
+    }
+    for action in actions.iter() {
+        println!("{}", action);
+    }
+    for action in left.iter() {
+        println!("left {}", action);
+    }
+    println!(
+        "summary dirs={} files={} clusters={} problems={} fixes={}",
+        report.dirs,
+        report.files,
+        report.clusters,
+        report.problems.len(),
+        actions.len()
+    );
+
+    let status = if report.is_clean() {
+        0
+    } else if fix && !dry_run && check(&device).is_clean() {
+        1
+    } else {
+        4
+    };
+    process::exit(status)
+}
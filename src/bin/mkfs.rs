@@ -0,0 +1,190 @@
+use std::env;
+use std::process;
+use std::sync::Arc;
+use fefs::device::BlockDevice;
+use fefs::div_ceil;
+use fefs::host::FileDevice;
+use fefs::sblock::is_sector_size;
+use fefs::sblock::FEATURE_EXTENTS;
+use fefs::system::fat_sectors_for;
+use fefs::system::FileSystem;
+
+const USAGE: &str = "\
+usage: mkfs-fefs [options] <image>
+
+options:
+    -s, --size <bytes>          create or resize the image, K/M/G suffixes allowed,
+                                an existing image or block file keeps its size without it
+    -S, --sector-size <bytes>   bytes per sector, 512, 1024, 2048 or 4096 (default 512)
+    -c, --cluster-size <bytes>  bytes per cluster, a multiple of the sector size (default 4096)
+    -f, --fat-sectors <n>       sectors taken by the FAT (default: enough for the whole image)
+    -L, --label <name>          volume label, at most 16 bytes
+    -j, --journal <bytes>       size of the metadata journal, 0 for none (default 32K)
+    -e, --extents               keep the runs of each file in its inode
+    -h, --help                  print this message";
+
+struct Options {
+    image: String,
+    size: Option<usize>,
+    sector_size: usize,
+    cluster_size: usize,
+    fat_sectors: Option<usize>,
+    label: String,
+    journal: usize,
+    extents: bool,
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("mkfs-fefs: {}", msg);
+    process::exit(1)
+}
+
+fn parse_size(value: &str) -> usize {
+    let (digits, unit) = match value.char_indices().find(|(_, ch)| !ch.is_ascii_digit()) {
+        Some((idx, _)) => value.split_at(idx),
+        None => (value, ""),
+    };
+    let unit = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => fail(&format!("invalid size '{}'", value)),
+    };
+    match digits.parse::<usize>() {
+        Ok(num) => num * unit,
+        Err(_) => fail(&format!("invalid size '{}'", value)),
+    }
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        image: String::new(),
+        size: None,
+        sector_size: 512,
+        cluster_size: 4096,
+        fat_sectors: None,
+        label: String::new(),
+        journal: 32 << 10,
+        extents: false,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || match args.next() {
+            Some(value) => value,
+            None => fail(&format!("missing value for {}", arg)),
+        };
+        match arg.as_str() {
+            "-s" | "--size" => options.size = Some(parse_size(&value())),
+            "-S" | "--sector-size" => options.sector_size = parse_size(&value()),
+            "-c" | "--cluster-size" => options.cluster_size = parse_size(&value()),
+            "-f" | "--fat-sectors" => options.fat_sectors = Some(parse_size(&value())),
+            "-L" | "--label" => options.label = value(),
+            "-j" | "--journal" => options.journal = parse_size(&value()),
+            "-e" | "--extents" => options.extents = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0)
+            }
+            _ if arg.starts_with('-') => fail(&format!("unknown option {}\n\n{}", arg, USAGE)),
+            _ if options.image.is_empty() => options.image = arg,
+            _ => fail(&format!("unexpected argument {}", arg)),
+        }
+    }
+
+    if options.image.is_empty() {
+        fail(USAGE)
+    }
+    if !is_sector_size(options.sector_size) {
+        fail("sector size must be 512, 1024, 2048 or 4096")
+    }
+    if options.cluster_size == 0 || options.cluster_size % options.sector_size != 0 {
+        fail(&format!("cluster size must be a multiple of {}", options.sector_size))
+    }
+    if options.label.len() > 16 {
+        fail("label is longer than 16 bytes")
+    }
+    options
+}
+
+fn main() {
+    let options = parse_args();
+
+    let device = match options.size {
+        Some(size) => FileDevice::create(&options.image, size),
+        None => FileDevice::open(&options.image),
+    }
+    .unwrap_or_else(|err| fail(&format!("{}: {}", options.image, err)));
+
+    let size = device.size();
+    let sector_size = options.sector_size;
+    let sector_per_cluster = options.cluster_size / sector_size;
+    let fat_sectors = options
+        .fat_sectors
+        .or_else(|| fat_sectors_for(size, sector_size, sector_per_cluster))
+        .unwrap_or_else(|| fail(&format!("{} bytes is too small for this geometry", size)));
+    let sectors = size / sector_size;
+    if fat_sectors == 0 || 1 + fat_sectors + sector_per_cluster > sectors {
+        fail(&format!("{} bytes is too small for this geometry", size))
+    }
+
+    // a FAT larger than the image leaves its last entries unused,
+    // the last entry of each FAT sector holds the checksum of the sector
+    let per_sector = sector_size / 4;
+    let entries = fat_sectors * per_sector - fat_sectors - 2;
+    let data = (sectors - 1 - fat_sectors) / sector_per_cluster;
+    let clusters = entries.min(data - (data + 2) / per_sector);
+    let journal_sectors = div_ceil(options.journal, sector_size);
+    // next to the root and the quota file
+    if div_ceil(journal_sectors, sector_per_cluster) + 2 > clusters {
+        fail(&format!("a journal of {} bytes does not fit in the image", options.journal))
+    }
+
+    let device: Arc<dyn BlockDevice> = Arc::new(device);
+    let fs = FileSystem::format(
+        device,
+        sector_size,
+        sector_per_cluster,
+        1 + fat_sectors,
+        &options.label,
+        journal_sectors,
+    );
+    let mut fs = fs.lock();
+    if options.extents {
+        fs.enable_extents();
+    }
+    fs.sync();
+
+    let sblock = fs.sblock();
+    println!("{}: {} bytes", options.image, size);
+    println!("  label          {}", sblock.label());
+    println!("  sector size    {}", sblock.byte_per_sector());
+    println!(
+        "  cluster size   {} ({} sectors)",
+        sblock.byte_per_cluster(),
+        sblock.sector_per_cluster()
+    );
+    println!(
+        "  FAT            sectors 1..{} ({} sectors)",
+        sblock.sector_per_fat(),
+        sblock.sector_per_fat() - 1
+    );
+    println!(
+        "  data           from sector {}, {} clusters ({} bytes)",
+        sblock.sector_per_fat(),
+        clusters,
+        clusters * sblock.byte_per_cluster()
+    );
+    println!("  root cluster   {}", sblock.root_cluster());
+    if sblock.journal_sectors() > 0 {
+        println!(
+            "  journal        cluster {} ({} sectors)",
+            sblock.journal_cluster(),
+            sblock.journal_sectors()
+        );
+    }
+    if sblock.has_feature(FEATURE_EXTENTS) {
+        println!("  extents        in the inodes");
+    }
+}
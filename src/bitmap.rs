@@ -0,0 +1,121 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use super::div_ceil;
+use super::device::BlockDevice;
+use super::fat::fat_cache;
+use super::sblock::SuperBlock;
+
+/// one bit for each cluster, set while it is free,
+/// a FAT sector is read the first time a search reaches it,
+/// so a mount does not scan the FAT and a search near the last one stays cheap
+pub struct FreeMap {
+    device: Arc<dyn BlockDevice>,
+    sblock: SuperBlock,
+    bits: Vec<u64>,
+    // FAT sectors whose entries are in `bits`
+    loaded: Vec<bool>,
+    // entries of one FAT sector, a whole number of words
+    sector_entries: usize,
+}
+
+impl FreeMap {
+    pub fn new(sblock: &SuperBlock, device: &Arc<dyn BlockDevice>) -> Self {
+        let entries = sblock.fat_entries();
+        let sector_entries = sblock.byte_per_sector / 4;
+        Self {
+            device: Arc::clone(device),
+            sblock: *sblock,
+            bits: vec![0; div_ceil(entries, 64)],
+            loaded: vec![false; div_ceil(entries, sector_entries)],
+            sector_entries,
+        }
+    }
+
+    fn load(&mut self, sector: usize) {
+        if self.loaded[sector] {
+            return;
+        }
+        self.loaded[sector] = true;
+        let first = sector * self.sector_entries;
+        let clusters = first.max(self.sblock.root_cluster)
+            ..(first + self.sector_entries).min(self.sblock.fat_entries());
+        // the clusters of a corrupted sector stay taken, no allocation gets them
+        let cache = match fat_cache(self.sblock.fat() + sector * self.sblock.byte_per_sector, &self.device) {
+            Some(cache) => cache,
+            None => return,
+        };
+        let cache = cache.lock();
+        for cluster in clusters {
+            if !self.sblock.is_reserved(cluster)
+                && cache.read((cluster - first) * 4, |entry: &u32| *entry) == 0 {
+                self.bits[cluster / 64] |= 1 << (cluster % 64);
+            }
+        }
+    }
+
+    fn is_free(&mut self, cluster: usize) -> bool {
+        self.load(cluster / self.sector_entries);
+        self.bits[cluster / 64] & (1 << (cluster % 64)) != 0
+    }
+
+    /// follow a write to the FAT entry of `cluster`,
+    /// sectors not read yet will see it when they are
+    pub fn set(&mut self, cluster: usize, free: bool) {
+        if !self.loaded[cluster / self.sector_entries] {
+            return;
+        }
+        match free {
+            true => self.bits[cluster / 64] |= 1 << (cluster % 64),
+            false => self.bits[cluster / 64] &= !(1 << (cluster % 64)),
+        }
+    }
+
+    // first free cluster in `from..to`, a word at a time
+    fn next_in(&mut self, from: usize, to: usize) -> Option<usize> {
+        let mut cluster = from;
+        while cluster < to {
+            self.load(cluster / self.sector_entries);
+            let word = self.bits[cluster / 64] >> (cluster % 64);
+            if word == 0 {
+                cluster = (cluster / 64 + 1) * 64;
+                continue;
+            }
+            let found = cluster + word.trailing_zeros() as usize;
+            return if found < to { Some(found) } else { None };
+        }
+        None
+    }
+
+    // first run of `len` free clusters starting in `from..to`
+    fn run_in(&mut self, len: usize, from: usize, to: usize) -> Option<usize> {
+        let end = self.sblock.fat_entries();
+        let mut at = from;
+        while let Some(first) = self.next_in(at, to) {
+            let mut last = first + 1;
+            while last - first < len && last < end && self.is_free(last) {
+                last += 1;
+            }
+            if last - first == len {
+                return Some(first);
+            }
+            at = last + 1;
+        }
+        None
+    }
+
+    /// first free cluster from `near` on, wrapping around to the first cluster
+    pub fn next_free(&mut self, near: usize) -> Option<usize> {
+        let (start, end) = (self.sblock.root_cluster, self.sblock.fat_entries());
+        let near = near.clamp(start, end);
+        self.next_in(near, end).or_else(|| self.next_in(start, near))
+    }
+
+    /// first cluster of `len` contiguous free ones from `near` on,
+    /// wrapping around to the first cluster
+    pub fn find_run(&mut self, len: usize, near: usize) -> Option<usize> {
+        let (start, end) = (self.sblock.root_cluster, self.sblock.fat_entries());
+        let near = near.clamp(start, end);
+        self.run_in(len, near, end).or_else(|| self.run_in(len, start, near))
+    }
+}
@@ -1,30 +1,58 @@
 use alloc::collections::VecDeque;
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
 use super::BLOCK_SIZE;
 use super::device::BlockDevice;
+use super::crc::{
+    stamp_sector,
+    verify_sector,
+};
 
 pub struct BlockCache {
-    cache: [u8; BLOCK_SIZE],
+    // one sector of the volume
+    cache: Vec<u8>,
     addr: usize,
     device: Arc<dyn BlockDevice>,
     modified: bool,
+    // the sector ends with a checksum, stamped when written back
+    checked: bool,
+    corrupted: bool,
 }
 
 impl BlockCache {
     pub fn new(
         addr: usize,
         device: Arc<dyn BlockDevice>,
+        size: usize,
     ) -> Self {
-        let mut cache = [0; BLOCK_SIZE];
-        device.read(addr, &mut cache);
+        let mut cache = vec![0; size];
+        read_sector(&device, addr, &mut cache);
         Self {
             cache,
             addr,
             device,
-            modified: false
+            modified: false,
+            checked: false,
+            corrupted: false,
+        }
+    }
+
+    pub fn from_data(
+        addr: usize,
+        device: Arc<dyn BlockDevice>,
+        data: &[u8],
+    ) -> Self {
+        Self {
+            cache: data.to_vec(),
+            addr,
+            device,
+            modified: false,
+            checked: false,
+            corrupted: false,
         }
     }
 
@@ -34,15 +62,17 @@ impl BlockCache {
 
     pub fn get_ref<T>(&self, offset: usize) -> &T where T: Sized {
         let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SIZE);
+        assert!(offset + type_size <= self.cache.len());
         let addr = self.addr_of_offset(offset);
         unsafe { &*(addr as *const T) }
     } 
 
     pub fn get_mut<T>(&mut self, offset: usize) -> &mut T where T: Sized {
         let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SIZE);
+        assert!(offset + type_size <= self.cache.len());
         self.modified = true;
+        // what is written now gets a fresh checksum
+        self.corrupted = false;
         let addr = self.addr_of_offset(offset);
         unsafe { &mut *(addr as *mut T) }
     }
@@ -55,10 +85,60 @@ impl BlockCache {
         f(self.get_mut(offset))
     }
 
+    // replace the whole block with data already on the device
+    fn overwrite(&mut self, data: &[u8]) {
+        self.cache.copy_from_slice(data);
+        self.modified = false;
+        if self.checked {
+            self.corrupted = !verify_sector(&self.cache);
+        }
+    }
+
+    // a block modified before it was known to be checksummed
+    // will be stamped anyway, so only the device copy is verified
+    fn check(&mut self) {
+        if !self.checked {
+            self.checked = true;
+            self.corrupted = !self.modified && !verify_sector(&self.cache);
+        }
+    }
+
+    /// the checksum of the block did not match when it was loaded
+    pub fn is_corrupted(&self) -> bool {
+        self.corrupted
+    }
+
+    pub(crate) fn stamp(&mut self) {
+        if self.checked {
+            stamp_sector(&mut self.cache);
+            self.corrupted = false;
+        }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.cache
+    }
+
+    /// the whole sector, to be changed in place
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        self.modified = true;
+        self.corrupted = false;
+        &mut self.cache
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
     pub fn sync(&mut self) {
         if self.modified {
             self.modified = false;
-            self.device.write(self.addr, &self.cache);
+            self.stamp();
+            write_sector(&self.device, self.addr, &self.cache);
         }
     }
 }
@@ -69,48 +149,300 @@ impl Drop for BlockCache {
     }
 }
 
+// a sector of the volume may span several sectors of the device
+fn read_sector(device: &Arc<dyn BlockDevice>, addr: usize, buf: &mut [u8]) {
+    match buf.len() == device.sector_size() {
+        true => device.read(addr, buf),
+        false => device.read_blocks(addr, buf),
+    }
+}
+
+fn write_sector(device: &Arc<dyn BlockDevice>, addr: usize, buf: &[u8]) {
+    match buf.len() == device.sector_size() {
+        true => device.write(addr, buf),
+        false => device.write_blocks(addr, buf),
+    }
+}
+
 const BLOCK_CACHE_SIZE: usize = 16;
+// how many sectors one read-ahead may load at most
+const READ_AHEAD_SIZE: usize = BLOCK_CACHE_SIZE / 2;
+// how many sequential accesses in a row before read-ahead starts
+const READ_AHEAD_TRIGGER: usize = 2;
 
 pub struct BlockCacheManager {
     queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    last_addr: usize,
+    sequential: usize,
+    // the chain goes on from the end of a run to the start of the next one
+    jump: (usize, usize),
+    // modified blocks stay in cache until the transaction commits
+    pinned: bool,
+    // blocks below this address are checksummed, 0 when checksums are off
+    meta_end: usize,
+    // bytes of every block, the sector size of the volume
+    sector_size: usize,
+}
+
+impl Default for BlockCacheManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BlockCacheManager {
     pub fn new() -> Self {
         Self {
-            queue: VecDeque::new()
+            queue: VecDeque::new(),
+            last_addr: 0,
+            sequential: 0,
+            jump: (0, 0),
+            pinned: false,
+            meta_end: 0,
+            sector_size: BLOCK_SIZE,
+        }
+    }
+
+    /// blocks are `size` bytes from now on, the ones cached are written back and dropped
+    pub fn set_sector_size(&mut self, size: usize) {
+        if size != self.sector_size {
+            self.sync_all();
+            self.queue.clear();
+            self.sector_size = size;
         }
     }
 
+    pub fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn is_cached(&self, addr: usize) -> bool {
+        self.queue.iter().any(|&(_addr, _)| _addr == addr)
+    }
+
+    // make room for one more cache, return false if every cache is in use,
+    // while pinned the queue grows rather than write back a modified block
+    fn reserve(&mut self) -> bool {
+        while self.queue.len() >= BLOCK_CACHE_SIZE {
+            let pinned = self.pinned;
+            match self.queue
+                .iter()
+                .position(|(_, cache)| {
+                    Arc::strong_count(cache) == 1 && !(pinned && cache.lock().modified)
+                }) {
+                Some(index) => { self.queue.remove(index).unwrap(); },
+                None => return pinned,
+            }
+        }
+        true
+    }
+
+    /// the superblock and FAT take the blocks below `meta_end`,
+    /// other metadata is marked by `get_meta_cache`
+    pub fn enable_checksums(&mut self, meta_end: usize) {
+        self.meta_end = meta_end;
+        for (addr, cache) in self.queue.iter() {
+            if *addr < meta_end {
+                cache.lock().check();
+            }
+        }
+    }
+
+    // a new cache below `meta_end` is verified as it is loaded
+    fn new_cache(&self, cache: BlockCache) -> Arc<Mutex<BlockCache>> {
+        let mut cache = cache;
+        if cache.addr < self.meta_end {
+            cache.check();
+        }
+        Arc::new(Mutex::new(cache))
+    }
+
+    /// same as `get_block_cache`, for a block that ends with a checksum
+    pub fn get_meta_cache(
+        &mut self,
+        addr: usize,
+        device: &Arc<dyn BlockDevice>
+    ) -> Arc<Mutex<BlockCache>> {
+        let cache = self.get_block_cache(addr, device);
+        if self.meta_end != 0 {
+            cache.lock().check();
+        }
+        cache
+    }
+
+    pub(crate) fn pin(&mut self, pinned: bool) {
+        self.pinned = pinned;
+    }
+
+    /// every block modified but not written back yet
+    pub fn dirty(&self) -> Vec<Arc<Mutex<BlockCache>>> {
+        self.queue
+            .iter()
+            .filter(|(_, cache)| cache.lock().modified)
+            .map(|(_, cache)| Arc::clone(cache))
+            .collect()
+    }
+
+    /// prefetch at most `READ_AHEAD_SIZE` sectors along `runs`, the chain from
+    /// the sector about to be read as start addresses and sector counts,
+    /// with one device read per run, only when the recent accesses are sequential
+    pub fn read_ahead(
+        &mut self,
+        runs: impl IntoIterator<Item = (usize, usize)>,
+        device: &Arc<dyn BlockDevice>
+    ) {
+        let size = self.sector_size;
+        let mut runs = runs.into_iter();
+        let (addr, count) = match runs.next() {
+            Some(run) => run,
+            None => return,
+        };
+        // only the readers going along a chain are tracked,
+        // the metadata they look up in between does not break the sequence
+        if addr == self.last_addr + size || (self.last_addr, addr) == self.jump {
+            self.sequential += 1;
+        } else if addr != self.last_addr {
+            self.sequential = 0;
+        }
+        self.last_addr = addr;
+        let mut next = runs.next();
+        if let Some((to, _)) = next {
+            self.jump = (addr + (count - 1) * size, to);
+        }
+        if self.sequential < READ_AHEAD_TRIGGER || self.is_cached(addr) {
+            return;
+        }
+
+        let mut left = READ_AHEAD_SIZE;
+        let mut run = Some((addr, count));
+        while let Some((addr, count)) = run {
+            let mut num = 0;
+            while num < count.min(left) && !self.is_cached(addr + num * size) {
+                num += 1;
+            }
+            let mut buf = vec![0; num * size];
+            device.read_blocks(addr, &mut buf);
+            for (idx, data) in buf.chunks(size).enumerate() {
+                if !self.reserve() { return; }
+                let cache = BlockCache::from_data(
+                    addr + idx * size,
+                    Arc::clone(device),
+                    data,
+                );
+                self.queue.push_back((
+                    addr + idx * size,
+                    self.new_cache(cache)
+                ));
+            }
+            left -= num;
+            // stop at a sector already cached or at the limit
+            if num < count || left == 0 { break; }
+            run = next;
+            next = runs.next();
+        }
+    }
+
+    /// write every modified block back to the device
+    pub fn sync_all(&mut self) {
+        for (_, cache) in self.queue.iter() {
+            cache.lock().sync();
+        }
+    }
+
+    fn cached_in(&self, addr: usize, len: usize) -> impl Iterator<Item = &(usize, Arc<Mutex<BlockCache>>)> {
+        self.queue
+            .iter()
+            .filter(move |&&(_addr, _)| _addr >= addr && _addr < addr + len)
+    }
+
+    /// read contiguous blocks with one device request,
+    /// blocks still in cache may be newer than the device, so they win
+    pub fn read_blocks(
+        &mut self,
+        addr: usize,
+        buf: &mut [u8],
+        device: &Arc<dyn BlockDevice>
+    ) {
+        assert_eq!(addr % self.sector_size, 0);
+        assert_eq!(buf.len() % self.sector_size, 0);
+        device.read_blocks(addr, buf);
+        self.overlay(addr, buf);
+    }
+
+    /// write contiguous blocks with one device request,
+    /// cached copies of them are refreshed to keep the cache coherent
+    pub fn write_blocks(
+        &mut self,
+        addr: usize,
+        buf: &[u8],
+        device: &Arc<dyn BlockDevice>
+    ) {
+        assert_eq!(addr % self.sector_size, 0);
+        assert_eq!(buf.len() % self.sector_size, 0);
+        device.write_blocks(addr, buf);
+        self.refresh(addr, buf);
+    }
+
     pub fn get_block_cache(
         &mut self,
         addr: usize,
         device: &Arc<dyn BlockDevice>
     ) -> Arc<Mutex<BlockCache>> {
-        assert!(addr % BLOCK_SIZE == 0);
-        match self.queue
-                .iter()
-                .find(|&&(_addr, _)| _addr == addr) {
-            Some((_, cache)) => Arc::clone(cache),
+        match self.lookup(addr) {
+            Some(cache) => cache,
             None => {
-                if self.queue.len() == BLOCK_CACHE_SIZE {
-                    match self.queue
-                        .iter()
-                        .enumerate()
-                        .find(|(_, (_, cache))| Arc::strong_count(cache) == 1) {
-                        Some((index, _)) => { self.queue.remove(index).unwrap(); },
-                        None => panic!("Run out of BlockCache!")
-                    }
+                if !self.reserve() {
+                    panic!("Run out of BlockCache!")
                 }
 
-                let cache = Arc::new(Mutex::new(
-                    BlockCache::new(addr, Arc::clone(device))
-                ));
+                let cache = self.new_cache(BlockCache::new(addr, Arc::clone(device), self.sector_size));
                 self.queue.push_back((addr, Arc::clone(&cache)));
                 cache
             }
         }
     }
+
+    fn lookup(&mut self, addr: usize) -> Option<Arc<Mutex<BlockCache>>> {
+        assert_eq!(addr % self.sector_size, 0);
+        self.queue
+            .iter()
+            .find(|&&(_addr, _)| _addr == addr)
+            .map(|(_, cache)| Arc::clone(cache))
+    }
+
+    // insert a block read by someone else,
+    // the cache inserted in the meantime wins as it may be modified
+    fn insert(
+        &mut self,
+        addr: usize,
+        device: &Arc<dyn BlockDevice>,
+        data: &[u8]
+    ) -> Arc<Mutex<BlockCache>> {
+        if let Some((_, cache)) = self.queue.iter().find(|&&(_addr, _)| _addr == addr) {
+            return Arc::clone(cache);
+        }
+        if !self.reserve() {
+            panic!("Run out of BlockCache!")
+        }
+
+        let cache = self.new_cache(BlockCache::from_data(addr, Arc::clone(device), data));
+        self.queue.push_back((addr, Arc::clone(&cache)));
+        cache
+    }
+
+    fn overlay(&self, addr: usize, buf: &mut [u8]) {
+        for (_addr, cache) in self.cached_in(addr, buf.len()) {
+            let start = _addr - addr;
+            buf[start..start + self.sector_size].copy_from_slice(&cache.lock().cache);
+        }
+    }
+
+    fn refresh(&self, addr: usize, buf: &[u8]) {
+        for (_addr, cache) in self.cached_in(addr, buf.len()) {
+            let start = _addr - addr;
+            cache.lock().overwrite(&buf[start..start + self.sector_size]);
+        }
+    }
 }
 
 lazy_static! {
@@ -125,3 +457,112 @@ pub fn get_block_cache(
 ) -> Arc<Mutex<BlockCache>> {
     BLOCK_CACHE_MANAGER.lock().get_block_cache(addr, device)
 }
+
+pub fn get_meta_cache(
+    addr: usize,
+    device: &Arc<dyn BlockDevice>
+) -> Arc<Mutex<BlockCache>> {
+    BLOCK_CACHE_MANAGER.lock().get_meta_cache(addr, device)
+}
+
+pub fn enable_checksums(meta_end: usize) {
+    BLOCK_CACHE_MANAGER.lock().enable_checksums(meta_end)
+}
+
+pub fn set_sector_size(size: usize) {
+    BLOCK_CACHE_MANAGER.lock().set_sector_size(size)
+}
+
+pub fn sync_all() {
+    BLOCK_CACHE_MANAGER.lock().sync_all()
+}
+
+pub fn read_ahead(
+    runs: impl IntoIterator<Item = (usize, usize)>,
+    device: &Arc<dyn BlockDevice>
+) {
+    BLOCK_CACHE_MANAGER.lock().read_ahead(runs, device)
+}
+
+pub fn read_blocks(
+    addr: usize,
+    buf: &mut [u8],
+    device: &Arc<dyn BlockDevice>
+) {
+    BLOCK_CACHE_MANAGER.lock().read_blocks(addr, buf, device)
+}
+
+pub fn write_blocks(
+    addr: usize,
+    buf: &[u8],
+    device: &Arc<dyn BlockDevice>
+) {
+    BLOCK_CACHE_MANAGER.lock().write_blocks(addr, buf, device)
+}
+
+// the async paths never hold a lock while waiting on the device,
+// only write back of evicted blocks stays on the sync interface
+
+pub async fn get_block_cache_async(
+    addr: usize,
+    device: &Arc<dyn BlockDevice>
+) -> Arc<Mutex<BlockCache>> {
+    let (cached, size) = {
+        let mut manager = BLOCK_CACHE_MANAGER.lock();
+        (manager.lookup(addr), manager.sector_size)
+    };
+    if let Some(cache) = cached {
+        return cache;
+    }
+
+    let mut data = vec![0; size];
+    match device.as_async() {
+        Some(async_device) if size == async_device.sector_size() => {
+            async_device.read(addr, &mut data).await
+        }
+        Some(async_device) => async_device.read_blocks(addr, &mut data).await,
+        None => read_sector(device, addr, &mut data),
+    }
+    BLOCK_CACHE_MANAGER.lock().insert(addr, device, &data)
+}
+
+pub async fn get_meta_cache_async(
+    addr: usize,
+    device: &Arc<dyn BlockDevice>
+) -> Arc<Mutex<BlockCache>> {
+    let cache = get_block_cache_async(addr, device).await;
+    if BLOCK_CACHE_MANAGER.lock().meta_end != 0 {
+        cache.lock().check();
+    }
+    cache
+}
+
+pub async fn read_blocks_async(
+    addr: usize,
+    buf: &mut [u8],
+    device: &Arc<dyn BlockDevice>
+) {
+    let size = BLOCK_CACHE_MANAGER.lock().sector_size;
+    assert_eq!(addr % size, 0);
+    assert_eq!(buf.len() % size, 0);
+    match device.as_async() {
+        Some(async_device) => async_device.read_blocks(addr, buf).await,
+        None => device.read_blocks(addr, buf),
+    }
+    BLOCK_CACHE_MANAGER.lock().overlay(addr, buf)
+}
+
+pub async fn write_blocks_async(
+    addr: usize,
+    buf: &[u8],
+    device: &Arc<dyn BlockDevice>
+) {
+    let size = BLOCK_CACHE_MANAGER.lock().sector_size;
+    assert_eq!(addr % size, 0);
+    assert_eq!(buf.len() % size, 0);
+    match device.as_async() {
+        Some(async_device) => async_device.write_blocks(addr, buf).await,
+        None => device.write_blocks(addr, buf),
+    }
+    BLOCK_CACHE_MANAGER.lock().refresh(addr, buf)
+}
@@ -0,0 +1,68 @@
+use core::cmp::min;
+use core::convert::TryInto;
+
+/// a ChaCha20 key, 256 bits
+pub type Key = [u8; 32];
+
+// "expand 32-byte k"
+const SIGMA: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+// the names follow RFC 8439
+#[allow(clippy::many_single_char_names)]
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// one 64-byte block of keystream, as in RFC 8439
+pub fn block(key: &Key, counter: u32, nonce: &[u8; 12]) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    state[0..4].copy_from_slice(&SIGMA);
+    for idx in 0..8 {
+        state[4 + idx] = u32::from_le_bytes(key[idx * 4..idx * 4 + 4].try_into().unwrap());
+    }
+    state[12] = counter;
+    for idx in 0..3 {
+        state[13 + idx] = u32::from_le_bytes(nonce[idx * 4..idx * 4 + 4].try_into().unwrap());
+    }
+
+    let mut work = state;
+    for _ in 0..10 {
+        quarter_round(&mut work, 0, 4, 8, 12);
+        quarter_round(&mut work, 1, 5, 9, 13);
+        quarter_round(&mut work, 2, 6, 10, 14);
+        quarter_round(&mut work, 3, 7, 11, 15);
+        quarter_round(&mut work, 0, 5, 10, 15);
+        quarter_round(&mut work, 1, 6, 11, 12);
+        quarter_round(&mut work, 2, 7, 8, 13);
+        quarter_round(&mut work, 3, 4, 9, 14);
+    }
+
+    let mut out = [0; 64];
+    for idx in 0..16 {
+        out[idx * 4..idx * 4 + 4].copy_from_slice(&work[idx].wrapping_add(state[idx]).to_le_bytes());
+    }
+    out
+}
+
+/// xor `buf` with the keystream from byte `offset` on,
+/// the same call encrypts and decrypts
+pub fn apply_keystream(key: &Key, nonce: &[u8; 12], offset: usize, buf: &mut [u8]) {
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done;
+        let stream = block(key, (pos / 64) as u32, nonce);
+        let from = pos % 64;
+        let len = min(64 - from, buf.len() - done);
+        for (byte, key_byte) in buf[done..done + len].iter_mut().zip(stream[from..from + len].iter()) {
+            *byte ^= key_byte;
+        }
+        done += len;
+    }
+}
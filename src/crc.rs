@@ -0,0 +1,46 @@
+use core::convert::TryInto;
+
+/// reflected Castagnoli polynomial
+const POLY: u32 = 0x82F63B78;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        let mut crc = idx as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[idx] = crc;
+        idx += 1;
+    }
+    table
+};
+
+/// CRC32C of `data`, as used by iSCSI and ext4
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data.iter() {
+        crc = TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// the last 4 bytes of a checksummed sector hold the CRC of the rest
+const CHECKSUM_SIZE: usize = 4;
+
+/// whether the checksum of a sector matches,
+/// an empty sector is stamped as well when it is initialised
+pub fn verify_sector(data: &[u8]) -> bool {
+    let at = data.len() - CHECKSUM_SIZE;
+    let stored = u32::from_le_bytes(data[at..].try_into().unwrap());
+    stored == crc32c(&data[..at])
+}
+
+pub fn stamp_sector(data: &mut [u8]) {
+    let at = data.len() - CHECKSUM_SIZE;
+    let crc = crc32c(&data[..at]);
+    data[at..].copy_from_slice(&crc.to_le_bytes());
+}
@@ -1,4 +1,78 @@
+use core::future::Future;
+use core::pin::Pin;
+use alloc::boxed::Box;
+use super::BLOCK_SIZE;
+
 pub trait BlockDevice: Send + Sync + 'static {
     fn read(&self, addr: usize, buf: &mut [u8]);
     fn write(&self, addr: usize, buf: &[u8]);
+
+    /// bytes the device holds, the FAT is sized from it at format time,
+    /// `usize::MAX` when the device does not tell, the FAT then decides
+    /// how many clusters the volume has
+    fn size(&self) -> usize {
+        usize::MAX
+    }
+
+    /// bytes of the smallest request the device takes, `read` and `write`
+    /// move one such sector, a volume sector is a whole number of them
+    fn sector_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    /// read `buf.len() / sector_size()` contiguous sectors starting at `addr`,
+    /// drivers that can do large requests should override it
+    fn read_blocks(&self, addr: usize, buf: &mut [u8]) {
+        let size = self.sector_size();
+        for (idx, block) in buf.chunks_mut(size).enumerate() {
+            self.read(addr + idx * size, block);
+        }
+    }
+
+    /// write `buf.len() / sector_size()` contiguous sectors starting at `addr`,
+    /// drivers that can do large requests should override it
+    fn write_blocks(&self, addr: usize, buf: &[u8]) {
+        let size = self.sector_size();
+        for (idx, block) in buf.chunks(size).enumerate() {
+            self.write(addr + idx * size, block);
+        }
+    }
+
+    /// the async interface of the same device, if the driver has one,
+    /// the `*_async` paths fall back to the sync interface without it
+    fn as_async(&self) -> Option<&dyn AsyncBlockDevice> {
+        None
+    }
+}
+
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// async flavour of `BlockDevice`, a request is done when its future resolves,
+/// so the caller can yield to its executor instead of spinning
+pub trait AsyncBlockDevice: Send + Sync + 'static {
+    fn read<'a>(&'a self, addr: usize, buf: &'a mut [u8]) -> BlockFuture<'a>;
+    fn write<'a>(&'a self, addr: usize, buf: &'a [u8]) -> BlockFuture<'a>;
+
+    /// the same as `BlockDevice::sector_size`
+    fn sector_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn read_blocks<'a>(&'a self, addr: usize, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            let size = self.sector_size();
+            for (idx, block) in buf.chunks_mut(size).enumerate() {
+                self.read(addr + idx * size, block).await;
+            }
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, addr: usize, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            let size = self.sector_size();
+            for (idx, block) in buf.chunks(size).enumerate() {
+                self.write(addr + idx * size, block).await;
+            }
+        })
+    }
 }
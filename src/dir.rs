@@ -1,21 +1,55 @@
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use super::BLOCK_SIZE;
 use super::is_illegal;
-use super::cache::get_block_cache;
-use super::sblock::SuperBlock;
+use super::cache::{
+    get_meta_cache,
+    get_meta_cache_async,
+    read_ahead,
+    write_blocks,
+};
+use super::sblock::{
+    modify_sblock,
+    SuperBlock,
+};
 use super::device::BlockDevice;
+use super::extent::{
+    read_extents,
+    Extents,
+};
 use super::file::FileEntry;
 use super::iter_sector;
+use super::journal;
 use super::fat::{
     alloc_clusters,
     read_clusters,
+    read_clusters_async,
     increase_cluster,
-    dealloc_clusters
+    dealloc_clusters,
+    share_clusters,
+    can_grow,
 };
 use super::inode::{
     INode,
-    INodeType
+    INodeType,
+    FLAG_ENCRYPTED,
+    FLAG_EXTENTS,
+    NAME_LEN,
+};
+use super::xattr::{
+    self,
+    read_attrs,
+    write_attrs,
+    Attrs,
+};
+use super::keyring::{
+    alloc_nonce,
+    find_key,
+};
+use super::quota::{
+    self,
+    Owner,
 };
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -26,70 +60,201 @@ pub enum DirError {
     IllegalChar,
     DirExist,
     FileExist,
+    NameTooLong,
+    /// the directory belongs to a snapshot
+    ReadOnly,
+    /// a sector of the directory does not match its checksum
+    Corrupted,
+    /// the directory is encrypted with a key missing from the keyring
+    NoKey,
+    /// the entry has no extended attribute of that name
+    NoAttr,
+    /// the attribute name is empty or too long, or its value too long
+    BadAttr,
+    /// the owner of the file would pass its hard limit
+    QuotaExceeded,
+    /// a directory can not be moved into itself or one of its children
+    IntoItself,
 }
 
+#[derive(Clone)]
 pub struct DirEntry {
     pub(crate) device: Arc<dyn BlockDevice>,
     pub(crate) clusters: Vec<usize>,
     pub(crate) sblock: SuperBlock,
+    pub(crate) read_only: bool,
+    // key inherited by the entries created here, 0 for none
+    pub(crate) key_id: u32,
 }
 
 impl DirEntry {
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// check every sector of this directory against its checksum
+    pub fn verify(&self) -> Result<(), DirError> {
+        for &c in self.clusters.iter() {
+            for o in 0..self.sblock.sector_per_cluster {
+                let addr = self.sblock.offset(c) + o * self.sblock.byte_per_sector;
+                if get_meta_cache(addr, &self.device).lock().is_corrupted() {
+                    return Err(DirError::Corrupted);
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn cd(&self, dir: &str) -> Result<DirEntry, DirError> {
+        self.verify()?;
         match self.find(dir) {
             Some(inode) if inode.is_dir() => Ok(DirEntry {
                 device: Arc::clone(&self.device),
                 clusters: read_clusters(inode.cluster()),
                 sblock: self.sblock,
+                read_only: self.read_only,
+                key_id: inode.key_id(),
             }),
             _ => Err(DirError::NotFoundDir)
         }
     }
 
-    pub fn open_file(&self, file: &str) -> Result<FileEntry, DirError> {
-        let (inode_option, addr) = self.find_tuple(file);
-        match inode_option {
-            Some(inode) if inode.is_file() => Ok(FileEntry {
+    pub async fn cd_async(&self, dir: &str) -> Result<DirEntry, DirError> {
+        match self.lookup_async(dir).await {
+            Some(inode) if inode.is_dir() => Ok(DirEntry {
                 device: Arc::clone(&self.device),
-                clusters: read_clusters(inode.cluster()),
-                size: inode.i_size_lo as usize,
-                seek_at: 0,
-                addr,
+                clusters: read_clusters_async(inode.cluster(), &self.sblock, &self.device).await,
                 sblock: self.sblock,
+                read_only: self.read_only,
+                key_id: inode.key_id(),
             }),
+            _ => Err(DirError::NotFoundDir)
+        }
+    }
+
+    pub fn open_file(&self, file: &str) -> Result<FileEntry, DirError> {
+        self.verify()?;
+        let (inode_option, addr) = self.find_tuple(file);
+        match inode_option {
+            Some(inode) if inode.is_file() => {
+                let (clusters, extent) = self.data_chain(&inode);
+                Ok(FileEntry {
+                    device: Arc::clone(&self.device),
+                    clusters,
+                    size: inode.i_size_lo as usize,
+                    seek_at: 0,
+                    addr,
+                    sblock: self.sblock,
+                    read_only: self.read_only,
+                    flags: inode.i_flags,
+                    csum: match inode.csum_cluster() {
+                        0 => Vec::new(),
+                        cluster => read_clusters(cluster),
+                    },
+                    map: match inode.map_cluster() {
+                        0 => Vec::new(),
+                        cluster => read_clusters(cluster),
+                    },
+                    extent,
+                    key_id: inode.key_id(),
+                    nonce: inode.i_nonce,
+                    key: find_key(inode.key_id()),
+                })
+            }
+            _ => Err(DirError::NotFoundFile)
+        }
+    }
+
+    pub async fn open_file_async(&self, file: &str) -> Result<FileEntry, DirError> {
+        let (inode_option, addr) = self.find_tuple_async(file).await;
+        match inode_option {
+            Some(inode) if inode.is_file() => {
+                let (clusters, extent) = self.data_chain_async(&inode).await;
+                let csum = match inode.csum_cluster() {
+                    0 => Vec::new(),
+                    cluster => read_clusters_async(cluster, &self.sblock, &self.device).await,
+                };
+                let map = match inode.map_cluster() {
+                    0 => Vec::new(),
+                    cluster => read_clusters_async(cluster, &self.sblock, &self.device).await,
+                };
+                Ok(FileEntry {
+                    device: Arc::clone(&self.device),
+                    clusters,
+                    size: inode.i_size_lo as usize,
+                    seek_at: 0,
+                    addr,
+                    sblock: self.sblock,
+                    read_only: self.read_only,
+                    flags: inode.i_flags,
+                    csum,
+                    map,
+                    extent,
+                    key_id: inode.key_id(),
+                    nonce: inode.i_nonce,
+                    key: find_key(inode.key_id()),
+                })
+            }
             _ => Err(DirError::NotFoundFile)
         }
     }
 
     pub fn create_file(&mut self, file: &str) -> Result<FileEntry, DirError> {
+        self.writable()?;
+        self.verify()?;
+        self.growable()?;
         let (inode_option, addr) = self.find_tuple(file);
         match inode_option {
             Some(_) => Err(DirError::FileExist),
             None if is_illegal(file) => Err(DirError::IllegalChar),
-            None => Ok(FileEntry {
-                device: Arc::clone(&self.device),
-                clusters: self.create_inner(file, INodeType::FileEntry),
-                size: 0,
-                seek_at: 0,
-                addr: if addr == 0 {
-                    let cluster = *self.clusters.last().unwrap();
-                    self.sblock.offset(cluster)
-                } else {
-                    addr
-                },
-                sblock: self.sblock,
-            })
+            None if file.len() > NAME_LEN => Err(DirError::NameTooLong),
+            None => {
+                self.unlocked()?;
+                // a new file belongs to root
+                if !quota::allows(0, 0, 1) {
+                    return Err(DirError::QuotaExceeded);
+                }
+                let (clusters, inode) = self.create_inner(file, INodeType::FileEntry);
+                Ok(FileEntry {
+                    device: Arc::clone(&self.device),
+                    clusters: clusters.into(),
+                    size: 0,
+                    seek_at: 0,
+                    addr: if addr == 0 {
+                        let cluster = *self.clusters.last().unwrap();
+                        self.sblock.offset(cluster)
+                    } else {
+                        addr
+                    },
+                    sblock: self.sblock,
+                    read_only: self.read_only,
+                    flags: inode.i_flags,
+                    csum: Vec::new(),
+                    map: Vec::new(),
+                    extent: Vec::new(),
+                    key_id: inode.key_id(),
+                    nonce: inode.i_nonce,
+                    key: find_key(inode.key_id()),
+                })
+            }
         }
     }
 
     pub fn mkdir(&mut self, dir: &str) -> Result<DirEntry, DirError> {
+        self.writable()?;
+        self.verify()?;
+        self.growable()?;
         match self.find(dir) {
             Some(_) => Err(DirError::DirExist),
             None if is_illegal(dir) => Err(DirError::IllegalChar),
+            None if dir.len() > NAME_LEN => Err(DirError::NameTooLong),
+            None if self.unlocked().is_err() => Err(DirError::NoKey),
             None => Ok(DirEntry {
                 device: Arc::clone(&self.device),
-                clusters: self.create_inner(dir, INodeType::DirEntry),
+                clusters: self.create_inner(dir, INodeType::DirEntry).0,
                 sblock: self.sblock,
+                read_only: self.read_only,
+                key_id: self.key_id,
             })
         }
     }
@@ -98,91 +263,391 @@ impl DirEntry {
         let mut inodes = Vec::new();
         iter_sector!(self, |inode: &INode| -> bool {
             if inode.is_valid() { inodes.push(*inode) }
-            inode.is_none()
+            false
         });
         inodes
     }
 
     pub fn delete(&mut self, name: &str) -> Result<(), DirError> {
+        self.writable()?;
+        self.verify()?;
         let (inode_option, addr) = self.find_tuple(name);
         match inode_option {
             Some(inode) => {
+                journal::begin();
                 match inode.i_type {
                     INodeType::NoneEntry => unreachable!(),
                     INodeType::DirEntry => DirEntry {
                         device: Arc::clone(&self.device),
                         clusters: read_clusters(inode.cluster()),
                         sblock: self.sblock,
+                        read_only: self.read_only,
+                        key_id: inode.key_id(),
                     }.delete_inner(),
                     INodeType::FileEntry => FileEntry {
                         device: Arc::clone(&self.device),
-                        clusters: read_clusters(inode.cluster()),
+                        clusters: read_clusters(inode.cluster()).into(),
                         size: inode.i_size_lo as usize,
                         seek_at: 0,
                         addr: 0,
                         sblock: self.sblock,
+                        read_only: self.read_only,
+                        flags: inode.i_flags,
+                        csum: Vec::new(),
+                        map: Vec::new(),
+                        extent: Vec::new(),
+                        key_id: 0,
+                        nonce: 0,
+                        key: None,
                     }.clean_data()
                 }
                 self.clean_entry(addr);
-                dealloc_clusters(inode.cluster());
+                dealloc_chains(&inode);
+                journal::commit();
                 Ok(())
             },
             None => Err(DirError::NotFound)
         }
     }
 
+    /// rename an entry in this directory
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), DirError> {
+        self.check_new_name(to)?;
+        self.modify_inode(from, |inode| inode.set_name(to))
+    }
+
+    /// encrypt the entries created in the directory `name` from now on with the key `key_id`,
+    /// 0 to stop, the entries already there keep their key and names stay in the clear
+    pub fn set_key(&mut self, name: &str, key_id: u32) -> Result<(), DirError> {
+        match self.find(name) {
+            Some(inode) if inode.is_dir() => {},
+            Some(_) => return Err(DirError::NotFoundDir),
+            None => return Err(DirError::NotFound),
+        }
+        if key_id != 0 && find_key(key_id).is_none() {
+            return Err(DirError::NoKey);
+        }
+        self.modify_inode(name, |inode| {
+            match key_id {
+                0 => inode.i_flags &= !FLAG_ENCRYPTED,
+                _ => inode.i_flags |= FLAG_ENCRYPTED,
+            }
+            inode.i_key_id = key_id;
+        })
+    }
+
+    /// extended attribute `key` of the entry `name`
+    pub fn get_xattr(&self, name: &str, key: &str) -> Result<Vec<u8>, DirError> {
+        self.verify()?;
+        let inode = self.find(name).ok_or(DirError::NotFound)?;
+        read_attrs(&inode, &self.sblock, &self.device).remove(key).ok_or(DirError::NoAttr)
+    }
+
+    pub fn list_xattr(&self, name: &str) -> Result<Vec<String>, DirError> {
+        self.verify()?;
+        let inode = self.find(name).ok_or(DirError::NotFound)?;
+        Ok(read_attrs(&inode, &self.sblock, &self.device).keys().cloned().collect())
+    }
+
+    pub fn set_xattr(&mut self, name: &str, key: &str, value: &[u8]) -> Result<(), DirError> {
+        if !xattr::is_valid(key, value) {
+            return Err(DirError::BadAttr);
+        }
+        self.update_xattr(name, |attrs| {
+            attrs.insert(key.into(), value.to_vec());
+            Ok(())
+        })
+    }
+
+    pub fn remove_xattr(&mut self, name: &str, key: &str) -> Result<(), DirError> {
+        self.update_xattr(name, |attrs| attrs.remove(key).map(|_| ()).ok_or(DirError::NoAttr))
+    }
+
+    /// permission bits of an entry, the type is not kept in them
+    pub fn set_mode(&mut self, name: &str, mode: u16) -> Result<(), DirError> {
+        self.modify_inode(name, |inode| inode.i_mode = mode & 0o7777)
+    }
+
+    /// the clusters of a file are charged to its new owner
+    pub fn set_owner(&mut self, name: &str, uid: u16, gid: u16) -> Result<(), DirError> {
+        self.writable()?;
+        self.verify()?;
+        let (inode_option, addr) = self.find_tuple(name);
+        let inode = inode_option.ok_or(DirError::NotFound)?;
+        // the usage moves with the owner in one transaction
+        journal::begin();
+        let moved = !inode.is_file()
+            || quota::transfer((inode.uid(), inode.gid()), (uid, gid), chain_len(&inode));
+        if moved {
+            get_meta_cache(addr, &self.device).lock().modify(0, |inode: &mut INode| {
+                inode.i_uid = uid;
+                inode.i_gid = gid;
+            });
+        }
+        journal::commit();
+        if !moved {
+            return Err(DirError::QuotaExceeded);
+        }
+        Ok(())
+    }
+
+    /// times are seconds since the unix epoch
+    pub fn set_times(&mut self, name: &str, atime: u32, mtime: u32) -> Result<(), DirError> {
+        self.modify_inode(name, |inode| {
+            inode.i_atime = atime;
+            inode.i_mtime = mtime;
+        })
+    }
+
+    /// move an entry of this directory into `dest` as `new_name`,
+    /// `dest` must not be the entry itself or one of its children
+    pub fn move_to(&mut self, name: &str, dest: &mut DirEntry, new_name: &str) -> Result<(), DirError> {
+        self.writable()?;
+        self.verify()?;
+        dest.check_new_name(new_name)?;
+        dest.growable()?;
+        let (inode_option, addr) = self.find_tuple(name);
+        let mut inode = match inode_option {
+            Some(inode) => inode,
+            None => return Err(DirError::NotFound),
+        };
+        if inode.is_dir() && self.holds_dir(&inode, dest.clusters[0]) {
+            return Err(DirError::IntoItself);
+        }
+
+        inode.set_name(new_name);
+        inode.i_pre_cluster = dest.clusters[0] as u32;
+        journal::begin();
+        dest.insert_inode(inode);
+        self.clean_entry(addr);
+        journal::commit();
+        Ok(())
+    }
+
+    // whether the directory `inode` of this one starts at `cluster` or has a child that does
+    fn holds_dir(&self, inode: &INode, cluster: usize) -> bool {
+        if inode.cluster() == cluster {
+            return true;
+        }
+        let dir = DirEntry {
+            device: Arc::clone(&self.device),
+            clusters: read_clusters(inode.cluster()),
+            sblock: self.sblock,
+            read_only: self.read_only,
+            key_id: inode.key_id(),
+        };
+        dir.ls().iter().any(|child| child.is_dir() && dir.holds_dir(child, cluster))
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<INode> {
+        self.find(name)
+    }
+
+    pub async fn lookup_async(&self, name: &str) -> Option<INode> {
+        self.find_tuple_async(name).await.0
+    }
+
     pub fn exist(&self, name: &str) -> bool {
+        self.find(name).is_some()
+    }
+
+    /// fill this directory with a copy of the entries of `src`,
+    /// directories are copied, file chains are shared with `src`
+    pub(crate) fn copy_from(&mut self, src: &DirEntry) {
+        for inode in src.ls() {
+            let mut copy = inode;
+            copy.i_pre_cluster = self.clusters[0] as u32;
+            let _owner = owner(&inode);
+            if inode.xattr_cluster() != 0 {
+                share_clusters(inode.xattr_cluster());
+            }
+            if inode.is_dir() {
+                let clusters = alloc_clusters(BLOCK_SIZE);
+                self.clean_cluster(clusters[0]);
+                copy.i_cluster = clusters[0] as u32;
+                self.insert_inode(copy);
+                DirEntry {
+                    device: Arc::clone(&self.device),
+                    clusters,
+                    sblock: self.sblock,
+                    read_only: false,
+                    key_id: inode.key_id(),
+                }.copy_from(&DirEntry {
+                    device: Arc::clone(&src.device),
+                    clusters: read_clusters(inode.cluster()),
+                    sblock: src.sblock,
+                    read_only: true,
+                    key_id: inode.key_id(),
+                });
+            } else {
+                share_clusters(inode.cluster());
+                if inode.csum_cluster() != 0 {
+                    share_clusters(inode.csum_cluster());
+                }
+                if inode.map_cluster() != 0 {
+                    share_clusters(inode.map_cluster());
+                }
+                if inode.extent_cluster() != 0 {
+                    share_clusters(inode.extent_cluster());
+                }
+                self.insert_inode(copy);
+            }
+        }
+    }
+
+    fn insert_inode(&mut self, inode: INode) {
+        let sector_addr = self.alloc_slot();
+        get_meta_cache(sector_addr, &self.device).lock().modify(0, |slot: &mut INode| {
+            *slot = inode;
+        });
+    }
+
+    fn modify_inode(&mut self, name: &str, f: impl FnOnce(&mut INode)) -> Result<(), DirError> {
+        self.writable()?;
+        self.verify()?;
+        let (inode_option, addr) = self.find_tuple(name);
+        match inode_option {
+            Some(_) => {
+                get_meta_cache(addr, &self.device).lock().modify(0, f);
+                Ok(())
+            }
+            None => Err(DirError::NotFound)
+        }
+    }
+
+    fn update_xattr(
+        &mut self,
+        name: &str,
+        f: impl FnOnce(&mut Attrs) -> Result<(), DirError>,
+    ) -> Result<(), DirError> {
+        self.writable()?;
+        self.verify()?;
+        let (inode_option, addr) = self.find_tuple(name);
+        let inode = inode_option.ok_or(DirError::NotFound)?;
+        let mut attrs = read_attrs(&inode, &self.sblock, &self.device);
+        f(&mut attrs)?;
+        let growth = xattr::growth(&inode, &attrs, &self.sblock);
+        if inode.is_file() && !quota::allows(inode.uid(), inode.gid(), growth) {
+            return Err(DirError::QuotaExceeded);
+        }
+        let _owner = owner(&inode);
+        journal::begin();
+        write_attrs(addr, &attrs, &self.sblock, &self.device);
+        journal::commit();
+        Ok(())
+    }
+
+    // entries cannot be created in an encrypted directory without its key
+    fn unlocked(&self) -> Result<(), DirError> {
+        match self.key_id != 0 && find_key(self.key_id).is_none() {
+            true => Err(DirError::NoKey),
+            false => Ok(()),
+        }
+    }
+
+    // the data chain of a file, from its runs when the inode keeps them,
+    // and the chain of the runs past the inline ones
+    fn data_chain(&self, inode: &INode) -> (Extents, Vec<usize>) {
+        if inode.flags() & FLAG_EXTENTS == 0 {
+            return (read_clusters(inode.cluster()).into(), Vec::new());
+        }
+        let chain = match inode.extent_cluster() {
+            0 => Vec::new(),
+            cluster => read_clusters(cluster),
+        };
+        let runs = read_extents(inode, &chain, &self.sblock, &self.device);
+        (Extents::from_runs(&runs), chain)
+    }
+
+    // the runs past the inline ones are few, their sectors are read synchronously
+    async fn data_chain_async(&self, inode: &INode) -> (Extents, Vec<usize>) {
+        if inode.flags() & FLAG_EXTENTS == 0 {
+            return (read_clusters_async(inode.cluster(), &self.sblock, &self.device).await.into(), Vec::new());
+        }
+        let chain = match inode.extent_cluster() {
+            0 => Vec::new(),
+            cluster => read_clusters_async(cluster, &self.sblock, &self.device).await,
+        };
+        let runs = read_extents(inode, &chain, &self.sblock, &self.device);
+        (Extents::from_runs(&runs), chain)
+    }
+
+    fn writable(&self) -> Result<(), DirError> {
+        match self.read_only {
+            true => Err(DirError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    // a new entry may need one more cluster,
+    // the last one can not be linked to it while its FAT sector is corrupted
+    fn growable(&self) -> Result<(), DirError> {
+        match can_grow(*self.clusters.last().unwrap()) {
+            true => Ok(()),
+            false => Err(DirError::Corrupted),
+        }
+    }
+
+    fn check_new_name(&self, name: &str) -> Result<(), DirError> {
+        self.writable()?;
+        self.verify()?;
         match self.find(name) {
-            Some(_) => true,
-            None => false
+            Some(inode) if inode.is_dir() => Err(DirError::DirExist),
+            Some(_) => Err(DirError::FileExist),
+            None if is_illegal(name) => Err(DirError::IllegalChar),
+            None if name.len() > NAME_LEN => Err(DirError::NameTooLong),
+            None => Ok(()),
         }
     }
 
     fn clean_entry(&mut self, addr: usize) {
-        get_block_cache(addr, &self.device).lock().modify(0, |inode: &mut INode| {
+        get_meta_cache(addr, &self.device).lock().modify(0, |inode: &mut INode| {
             *inode = INode::default()
         });
+        modify_sblock(&self.device, |sblock| sblock.used_inodes = sblock.used_inodes.saturating_sub(1));
     }
 
     fn delete_inner(&mut self) {
         let inodes = self.ls();
-        for (nth, inode) in inodes.iter().enumerate() {
+        for inode in inodes.iter() {
             match inode.i_type {
                 INodeType::NoneEntry => unreachable!(),
                 INodeType::DirEntry => DirEntry {
                     device: Arc::clone(&self.device),
                     clusters: read_clusters(inode.cluster()),
                     sblock: self.sblock,
+                    read_only: self.read_only,
+                    key_id: inode.key_id(),
                 }.delete_inner(),
                 INodeType::FileEntry => FileEntry {
                     device: Arc::clone(&self.device),
-                    clusters: read_clusters(inode.cluster()),
+                    clusters: read_clusters(inode.cluster()).into(),
                     size: inode.i_size_lo as usize,
                     seek_at: 0,
                     addr: 0,
                     sblock: self.sblock,
+                    read_only: self.read_only,
+                    flags: inode.i_flags,
+                    csum: Vec::new(),
+                    map: Vec::new(),
+                    extent: Vec::new(),
+                    key_id: 0,
+                    nonce: 0,
+                    key: None,
                 }.clean_data()
             }
-            let index = nth / self.sblock.sector_per_cluster;
-            let nth = nth % self.sblock.sector_per_cluster;
-            let cluster = self.clusters[index];
-            self.clean_entry(nth * BLOCK_SIZE + self.sblock.offset(cluster));
-            dealloc_clusters(inode.cluster());
+            let (_, addr) = self.find_tuple(&inode.name());
+            self.clean_entry(addr);
+            dealloc_chains(inode);
         }
     }
 
     fn find(&self, name: &str) -> Option<INode> {
-        let mut ret = None;
-        iter_sector!(self, |inode: &INode| -> bool {
-            if inode.is_valid() && inode.name().eq(name) { 
-                ret = Some(*inode);
-                return true;
-            }
-            inode.is_none()
-        });
-        ret
+        self.find_tuple(name).0
     }
 
+    // deleted entries leave holes, so the whole directory is searched,
+    // the address is the first free slot when the name is not found
     fn find_tuple(&self, name: &str) -> (Option<INode>, usize) {
         let mut ret = INode::default();
         let addr = iter_sector!(self, |inode: &INode| -> bool {
@@ -190,16 +655,43 @@ impl DirEntry {
                 ret = *inode;
                 return true;
             }
-            inode.is_none()
+            false
         });
         if ret.is_none() {
-            (None, addr)
+            (None, iter_sector!(self, |inode: &INode| -> bool {
+                inode.is_none()
+            }))
         } else {
             (Some(ret), addr)
         }
     }
 
-    fn create_inner(&mut self, name: &str, inode_type: INodeType) -> Vec<usize> {
+    // same as find_tuple, but yields while a sector is loaded
+    async fn find_tuple_async(&self, name: &str) -> (Option<INode>, usize) {
+        let mut hole = 0;
+        for &c in self.clusters.iter() {
+            let addr = self.sblock.offset(c);
+            for o in 0..self.sblock.sector_per_cluster {
+                let sector_addr = addr + o * self.sblock.byte_per_sector;
+                let cache = get_meta_cache_async(sector_addr, &self.device).await;
+                let inode = match cache.lock() {
+                    cache if cache.is_corrupted() => continue,
+                    cache => cache.read(0, |inode: &INode| *inode),
+                };
+                if inode.is_none() {
+                    if hole == 0 { hole = sector_addr; }
+                } else if inode.name().eq(name) {
+                    return (Some(inode), sector_addr);
+                }
+            }
+        }
+        (None, hole)
+    }
+
+    // address of a free entry, the directory grows when it is full,
+    // the caller always fills it
+    fn alloc_slot(&mut self) -> usize {
+        modify_sblock(&self.device, |sblock| sblock.used_inodes += 1);
         let mut sector_addr = iter_sector!(self, |inode: &INode| -> bool {
             inode.is_none()
         });
@@ -208,19 +700,82 @@ impl DirEntry {
             let clusters_len = self.clusters.len();
             let mut new_clusters = increase_cluster(self.clusters[clusters_len - 1], BLOCK_SIZE);
             sector_addr = self.sblock.offset(new_clusters[0]);
+            self.clean_cluster(new_clusters[0]);
             self.clusters.append(&mut new_clusters);
         }
 
+        sector_addr
+    }
+
+    // a recycled cluster may hold anything, entries must start empty
+    fn clean_cluster(&self, cluster: usize) {
+        let len = self.sblock.byte_per_cluster();
+        write_blocks(self.sblock.offset(cluster), &self.sblock.empty_sectors(len), &self.device);
+    }
+
+    fn create_inner(&mut self, name: &str, inode_type: INodeType) -> (Vec<usize>, INode) {
+        journal::begin();
+        let sector_addr = self.alloc_slot();
+        let owner = (inode_type == INodeType::FileEntry).then(|| quota::enter(0, 0));
         let clusters = alloc_clusters(BLOCK_SIZE);
+        drop(owner);
+        if inode_type == INodeType::DirEntry {
+            self.clean_cluster(clusters[0]);
+        }
+        let nonce = match self.key_id != 0 && inode_type == INodeType::FileEntry {
+            true => alloc_nonce(&self.device),
+            false => 0,
+        };
 
-        get_block_cache(sector_addr, &self.device).lock().modify(0, |inode: &mut INode| {
+        let inode = get_meta_cache(sector_addr, &self.device).lock().modify(0, |inode: &mut INode| {
             inode.i_type = inode_type;
-            &inode.i_name[0..name.len()].copy_from_slice(name.as_bytes());
-            inode.i_name_len = name.len() as u8;
+            inode.set_name(name);
             inode.i_cluster = clusters[0] as u32;
             inode.i_pre_cluster = self.clusters[0] as u32;
+            if self.key_id != 0 {
+                inode.i_flags |= FLAG_ENCRYPTED;
+                inode.i_key_id = self.key_id;
+                inode.i_nonce = nonce;
+            }
+            *inode
         });
+        journal::commit();
+
+        (clusters, inode)
+    }
+}
+
+// only the chains of files are charged to their owner, directories are free
+fn owner(inode: &INode) -> Option<Owner> {
+    match inode.is_file() {
+        true => Some(quota::enter(inode.uid(), inode.gid())),
+        false => None,
+    }
+}
 
-        clusters
+// clusters charged for a file
+fn chain_len(inode: &INode) -> usize {
+    [inode.cluster(), inode.csum_cluster(), inode.map_cluster(), inode.xattr_cluster(), inode.extent_cluster()]
+        .iter()
+        .filter(|&&cluster| cluster != 0)
+        .map(|&cluster| read_clusters(cluster).len())
+        .sum()
+}
+
+// the data and attributes of an entry, and the checksums and chunk map of a file
+fn dealloc_chains(inode: &INode) {
+    let _owner = owner(inode);
+    dealloc_clusters(inode.cluster());
+    if inode.csum_cluster() != 0 {
+        dealloc_clusters(inode.csum_cluster());
+    }
+    if inode.map_cluster() != 0 {
+        dealloc_clusters(inode.map_cluster());
+    }
+    if inode.xattr_cluster() != 0 {
+        dealloc_clusters(inode.xattr_cluster());
+    }
+    if inode.extent_cluster() != 0 {
+        dealloc_clusters(inode.extent_cluster());
     }
 }
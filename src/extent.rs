@@ -0,0 +1,208 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use super::cache::{
+    get_block_cache,
+    get_meta_cache,
+};
+use super::device::BlockDevice;
+use super::fat::{
+    alloc_clusters,
+    dealloc_clusters,
+    increase_cluster,
+    is_shared,
+};
+use super::inode::{
+    INode,
+    EXTENT_INLINE,
+};
+use super::sblock::SuperBlock;
+
+/// `len` contiguous clusters from `start`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extent {
+    pub(crate) start: u32,
+    pub(crate) len: u32,
+}
+
+impl Extent {
+    pub fn start(&self) -> usize {
+        self.start as usize
+    }
+
+    pub fn clusters(&self) -> usize {
+        self.len as usize
+    }
+}
+
+const EXTENT_SIZE: usize = core::mem::size_of::<Extent>();
+
+/// the clusters of a chain as runs of contiguous ones,
+/// finding the cluster at an index searches the runs rather than the chain
+#[derive(Debug, Clone, Default)]
+pub struct Extents {
+    runs: Vec<Extent>,
+    // index in the chain of the first cluster of each run
+    firsts: Vec<usize>,
+    len: usize,
+    // runs from this one on changed since they were stored
+    dirty: usize,
+}
+
+impl Extents {
+    /// runs read back from an inode, nothing to store
+    pub fn from_runs(runs: &[Extent]) -> Self {
+        let mut extents = Self::default();
+        for run in runs.iter() {
+            extents.firsts.push(extents.len);
+            extents.len += run.clusters();
+        }
+        extents.runs = runs.to_vec();
+        extents.dirty = runs.len();
+        extents
+    }
+
+    /// number of clusters
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn runs(&self) -> &[Extent] {
+        &self.runs
+    }
+
+    pub fn first(&self) -> usize {
+        self.runs[0].start()
+    }
+
+    pub fn last(&self) -> usize {
+        let run = self.runs.last().unwrap();
+        run.start() + run.clusters() - 1
+    }
+
+    /// cluster `idx` of the chain
+    pub fn get(&self, idx: usize) -> usize {
+        self.run_at(idx).0
+    }
+
+    /// cluster `idx` of the chain, and how many contiguous ones start from it
+    pub fn run_at(&self, idx: usize) -> (usize, usize) {
+        assert!(idx < self.len, "cluster {} past the end of the chain", idx);
+        let run = match self.firsts.binary_search(&idx) {
+            Ok(run) => run,
+            Err(next) => next - 1,
+        };
+        let from = idx - self.firsts[run];
+        (self.runs[run].start() + from, self.runs[run].clusters() - from)
+    }
+
+    pub fn push(&mut self, cluster: usize) {
+        match self.runs.last_mut() {
+            Some(run) if run.start() + run.clusters() == cluster && run.len < u32::MAX => {
+                run.len += 1;
+            }
+            _ => {
+                self.runs.push(Extent { start: cluster as u32, len: 1 });
+                self.firsts.push(self.len);
+            }
+        }
+        self.dirty = min(self.dirty, self.runs.len() - 1);
+        self.len += 1;
+    }
+
+    pub fn extend(&mut self, clusters: &[usize]) {
+        for &cluster in clusters.iter() {
+            self.push(cluster);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.runs.iter().flat_map(|run| run.start()..run.start() + run.clusters())
+    }
+}
+
+/// a chain walked through the FAT, every run is still to be stored
+impl From<Vec<usize>> for Extents {
+    fn from(clusters: Vec<usize>) -> Self {
+        let mut extents = Self::default();
+        extents.extend(&clusters);
+        extents.dirty = 0;
+        extents
+    }
+}
+
+// device address of extent `idx` past the inline ones
+fn chain_addr(chain: &[usize], idx: usize, sblock: &SuperBlock) -> usize {
+    let pos = idx * EXTENT_SIZE;
+    let bpc = sblock.byte_per_cluster();
+    sblock.offset(chain[pos / bpc]) + pos % bpc
+}
+
+/// the runs an inode with `FLAG_EXTENTS` keeps, `chain` is its extent chain,
+/// or the part of it that can be trusted, runs it does not reach are left out
+pub(crate) fn read_extents(
+    inode: &INode,
+    chain: &[usize],
+    sblock: &SuperBlock,
+    device: &Arc<dyn BlockDevice>,
+) -> Vec<Extent> {
+    let count = inode.i_extent_count as usize;
+    let mut runs = inode.i_extents[0..min(count, EXTENT_INLINE)].to_vec();
+    let stored = chain.len() * sblock.byte_per_cluster() / EXTENT_SIZE;
+    for idx in 0..min(count.saturating_sub(EXTENT_INLINE), stored) {
+        let addr = chain_addr(chain, idx, sblock);
+        let in_sector = addr % sblock.byte_per_sector;
+        runs.push(get_block_cache(addr - in_sector, device)
+            .lock()
+            .read(in_sector, |extent: &Extent| *extent));
+    }
+    runs
+}
+
+/// store the runs changed since the last call in the inode at `addr`,
+/// those past the inline ones go to `chain`, which is replaced
+/// rather than written while a snapshot shares it
+pub(crate) fn write_extents(
+    addr: usize,
+    extents: &mut Extents,
+    chain: &mut Vec<usize>,
+    sblock: &SuperBlock,
+    device: &Arc<dyn BlockDevice>,
+) {
+    let rest = extents.runs.len().saturating_sub(EXTENT_INLINE);
+    if !chain.is_empty() && (rest == 0 || is_shared(chain[0])) {
+        dealloc_clusters(chain[0]);
+        chain.clear();
+        extents.dirty = 0;
+    }
+    if rest > 0 {
+        let need = rest * EXTENT_SIZE;
+        let capacity = chain.len() * sblock.byte_per_cluster();
+        match chain.last() {
+            None => *chain = alloc_clusters(need),
+            Some(&last) if need > capacity => chain.append(&mut increase_cluster(last, need - capacity)),
+            Some(_) => {}
+        }
+    }
+
+    for idx in extents.dirty.max(EXTENT_INLINE)..extents.runs.len() {
+        let at = chain_addr(chain, idx - EXTENT_INLINE, sblock);
+        let in_sector = at % sblock.byte_per_sector;
+        get_block_cache(at - in_sector, device)
+            .lock()
+            .modify(in_sector, |extent: &mut Extent| *extent = extents.runs[idx]);
+    }
+    let inline = min(extents.runs.len(), EXTENT_INLINE);
+    get_meta_cache(addr, device).lock().modify(0, |inode: &mut INode| {
+        inode.i_extents = [Extent::default(); EXTENT_INLINE];
+        inode.i_extents[0..inline].copy_from_slice(&extents.runs[0..inline]);
+        inode.i_extent_count = extents.runs.len() as u32;
+        inode.i_extent_cluster = chain.first().copied().unwrap_or(0) as u32;
+    });
+    extents.dirty = extents.runs.len();
+}
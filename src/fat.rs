@@ -1,125 +1,102 @@
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 use spin::Mutex;
 use lazy_static::lazy_static;
-use super::BLOCK_SIZE;
-use super::cache::get_block_cache;
+use super::div_ceil;
+use super::cache::{
+    BlockCache,
+    get_block_cache,
+    get_block_cache_async,
+    write_blocks,
+};
 use super::sblock::get_sblock;
+use super::sblock::modify_sblock;
 use super::sblock::SuperBlock;
 use super::device::BlockDevice;
-
-struct FATIterator {
-    current: usize,
-    end: usize,
-    fat_addr: usize,
-    device: Arc<dyn BlockDevice>,
-}
-
-impl FATIterator {
-    fn new(sblock: &SuperBlock, device: &Arc<dyn BlockDevice>) -> Self {
-        let mut cluster = 0;
-        let fat_addr = sblock.fat();
-        for offset in (0..).step_by(BLOCK_SIZE) {
-            let cache = get_block_cache(fat_addr + offset, device);
-            for location in (0..BLOCK_SIZE).step_by(4) {
-                let ret = cache.lock().read(location, |cluster_value: &u32| { *cluster_value });
-                if ret == 0 { 
-                    cluster = (offset + location) / 4;
-                    break;
-                };
-            }
-            if cluster != 0 { break; }
-        }
-
-        let end = (sblock.root_cluster * sblock.sector_per_cluster * BLOCK_SIZE - fat_addr) / 4;
-
-        Self {
-            current: cluster,
-            end,
-            fat_addr,
-            device: Arc::clone(device),
-        }
-    }
-}
-
-impl Iterator for FATIterator {
-    type Item = usize;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let cluster_offset = self.current * 4 / BLOCK_SIZE * BLOCK_SIZE;
-        let base_addr = self.fat_addr + cluster_offset;
-        let mut exit = false;
-        for offset in (0..).step_by(BLOCK_SIZE) {
-            let addr = base_addr + offset;
-            let cache = get_block_cache(addr, &self.device);
-            for location in (0..BLOCK_SIZE).step_by(4) {
-                let ret = cache.lock().read(location, |cluster_value: &u32| { *cluster_value });
-                if ret == 0 { 
-                    self.current = (cluster_offset + offset + location) / 4;
-                    exit = true;
-                    break;
-                };
-            }
-            if exit { break; }
-        }
-
-        if self.current > self.end {
-            None
-        } else {
-            Some(self.current)
-        }
+use super::bitmap::FreeMap;
+use super::quota;
+
+// what FAT32 puts in the entry of a cluster not to be used,
+// reads through a corrupted FAT sector get it instead of its garbage
+const BAD_CLUSTER: usize = 0x0FFFFFF7;
+
+// none for a FAT sector whose checksum does not match,
+// its clusters are neither followed nor handed out, fsck has to fix it
+pub(crate) fn fat_cache(addr: usize, device: &Arc<dyn BlockDevice>) -> Option<Arc<Mutex<BlockCache>>> {
+    let cache = get_block_cache(addr, device);
+    let corrupted = cache.lock().is_corrupted();
+    match corrupted {
+        true => None,
+        false => Some(cache),
     }
 }
 
 struct FAT {
-    iterator: FATIterator,
+    device: Arc<dyn BlockDevice>,
     sblock: SuperBlock,
-    recycled: Vec<usize>,
+    map: FreeMap,
+    // past the clusters taken last, new chains start from it
+    cursor: usize,
+    // chain of the refcount table, empty when nothing was ever shared
+    refcounts: Vec<usize>,
 }
 
 impl FAT {
     fn new(device: &Arc<dyn BlockDevice>) -> Self {
-        let sblock = get_sblock(device);
-        let fat = Self {
-            iterator: FATIterator::new(&sblock, device),
+        let mut sblock = get_sblock(device);
+        // the counters may be stale after a crash, the FAT is counted again
+        if !sblock.is_clean() {
+            let is_free = |&c: &usize| !sblock.is_reserved(c) && read_fat(c, &sblock, device) == 0;
+            // the first free cluster, then the rest of them counted on from it
+            let mut clusters = sblock.root_cluster..sblock.fat_entries();
+            let first = clusters.find(is_free);
+            let free = first.map_or(0, |_| 1 + clusters.filter(is_free).count());
+            modify_sblock(device, |s| {
+                s.free_clusters = free;
+                s.next_free = first.unwrap_or(0);
+            });
+            sblock = get_sblock(device);
+        }
+        let mut fat = Self {
+            device: Arc::clone(device),
             sblock,
-            recycled: Vec::new(),
+            map: FreeMap::new(&sblock, device),
+            cursor: sblock.next_free,
+            refcounts: Vec::new(),
         };
-        fat
-    }
-
-    fn free_clusters(&mut self, size: usize) -> Vec<usize> {
-        let spc = self.sblock.sector_per_cluster;
-        let num_sector = if size % BLOCK_SIZE == 0 {
-            size / BLOCK_SIZE
-        } else {
-            size / BLOCK_SIZE + 1
-        };
-
-        let num_cluster = if num_sector % spc == 0 {
-            num_sector / spc
-        } else {
-            num_sector / spc + 1
-        };
-
-        let mut clusters = Vec::new();
-        for _ in 0..num_cluster {
-            clusters.push(self.free_cluster());
+        if sblock.refcount_cluster != 0 {
+            fat.refcounts = fat.allocated_clusters(sblock.refcount_cluster);
         }
-        clusters
+        fat
     }
 
-    fn free_cluster(&mut self) -> usize {
-        let cluster = match self.recycled.pop() {
-            Some(cluster) => cluster,
-            None => match self.iterator.next() {
-                Some(cluster) => cluster,
-                None => panic!("no fat can be allocated"),
+    // one run from `near` on if there is one that long,
+    // the free clusters nearest after `near` otherwise
+    fn free_clusters(&mut self, size: usize, near: usize) -> Vec<usize> {
+        let num_cluster = div_ceil(size, self.sblock.byte_per_cluster());
+
+        let clusters: Vec<usize> = match self.map.find_run(num_cluster, near) {
+            Some(first) => {
+                for cluster in first..first + num_cluster {
+                    self.write(cluster, 0x0FFFFFFF);
+                }
+                (first..first + num_cluster).collect()
+            }
+            None => {
+                let mut clusters = Vec::new();
+                let mut at = near;
+                while clusters.len() < num_cluster {
+                    let cluster = self.map.next_free(at).expect("no fat can be allocated");
+                    // taken at once, so the search goes on past it
+                    self.write(cluster, 0x0FFFFFFF);
+                    clusters.push(cluster);
+                    at = cluster + 1;
+                }
+                clusters
             }
         };
-
-        self.write(cluster, 0x0FFFFFFF);
-        cluster
+        clusters
     }
 
     fn allocated_clusters(&self, cluster: usize) -> Vec<usize> {
@@ -127,9 +104,10 @@ impl FAT {
         let mut clusters = Vec::new();
         clusters.push(cluster);
 
+        // a corrupted FAT sector cuts the chain short
         loop {
             cluster = self.read(cluster);
-            if cluster == 0x0FFFFFFF {
+            if cluster >= BAD_CLUSTER {
                 break;
             } else {
                 clusters.push(cluster);
@@ -142,23 +120,47 @@ impl FAT {
     fn read(&self, cluster: usize) -> usize {
         let (addr, offset) = self.get_block_offset(cluster);
 
-        get_block_cache(addr, &self.iterator.device)
-            .lock().read(offset, &|cluster: &u32| {
-            *cluster
-        }) as usize
+        match fat_cache(addr, &self.device) {
+            Some(cache) => cache.lock().read(offset, |cluster: &u32| {
+                *cluster
+            }) as usize,
+            None => BAD_CLUSTER,
+        }
     }
 
+    // a corrupted sector is left as it is, a write would stamp its garbage
     fn write(&mut self, cluster: usize, value: usize) {
         let (addr, offset) = self.get_block_offset(cluster);
 
-        get_block_cache(addr, &self.iterator.device)
-            .lock().modify(offset, |cluster: &mut u32| {
+        let cache = match fat_cache(addr, &self.device) {
+            Some(cache) => cache,
+            None => return,
+        };
+        cache.lock().modify(offset, |cluster: &mut u32| {
             *cluster = value as u32;
         });
+        self.map.set(cluster, value == 0);
+    }
+
+    fn can_grow(&self, end_cluster: usize) -> bool {
+        let (addr, _) = self.get_block_offset(end_cluster);
+        fat_cache(addr, &self.device).is_some()
     }
 
+    // a new chain goes past the clusters taken last,
+    // chains growing in the holes behind do not pull it back
     fn alloc(&mut self, size: usize) -> Vec<usize> {
-        let clusters = self.free_clusters(size);
+        let clusters = self.alloc_near(size, self.cursor);
+        self.cursor = clusters.last().unwrap() + 1;
+        clusters
+    }
+
+    fn alloc_near(&mut self, size: usize, near: usize) -> Vec<usize> {
+        let clusters = self.free_clusters(size, near);
+        let taken = clusters.len();
+        modify_sblock(&self.device, |sblock| {
+            sblock.free_clusters = sblock.free_clusters.saturating_sub(taken)
+        });
         for idx in 0..clusters.len() {
             if idx != clusters.len() - 1 {
                 self.write(clusters[idx], clusters[idx + 1]);
@@ -169,24 +171,98 @@ impl FAT {
         clusters
     }
 
-    fn dealloc(&mut self, cluster: usize) {
-        let mut clusters = self.allocated_clusters(cluster);
+    // a shared cluster only loses one reference
+    fn dealloc(&mut self, cluster: usize) -> usize {
+        let clusters = self.allocated_clusters(cluster);
+        let mut freed = 0;
         for &c in clusters.iter() {
-            self.write(c, 0x00000000);
+            match self.refcount(c) {
+                0 => {
+                    self.write(c, 0x00000000);
+                    freed += 1;
+                }
+                refs => self.set_refcount(c, refs - 1),
+            }
         }
-        self.recycled.append(&mut clusters);
+        modify_sblock(&self.device, |sblock| sblock.free_clusters += freed);
+        clusters.len()
+    }
+
+    fn refcount(&self, cluster: usize) -> usize {
+        if self.refcounts.is_empty() {
+            return 0;
+        }
+        read_refcount(&self.refcounts, cluster, &self.sblock, &self.device)
+    }
+
+    fn set_refcount(&mut self, cluster: usize, refs: usize) {
+        write_refcount(&self.refcounts, cluster, refs, &self.sblock, &self.device)
+    }
+
+    fn create_refcounts(&mut self) -> usize {
+        if self.refcounts.is_empty() {
+            let size = self.sblock.fat_entries() * REFCOUNT_SIZE;
+            let clusters = self.alloc(size);
+            let bpc = self.sblock.byte_per_cluster();
+            for &c in clusters.iter() {
+                write_blocks(self.sblock.offset(c), &vec![0; bpc], &self.device);
+            }
+            self.refcounts = clusters;
+        }
+        self.refcounts[0]
+    }
+
+    // one more reference to every cluster of the chain
+    fn share(&mut self, cluster: usize) -> usize {
+        assert!(!self.refcounts.is_empty(), "no refcount table");
+        let clusters = self.allocated_clusters(cluster);
+        for &c in clusters.iter() {
+            let refs = self.refcount(c);
+            self.set_refcount(c, refs + 1);
+        }
+        clusters.len()
     }
 
     fn increase(&mut self, end_cluster: usize, size: usize) -> Vec<usize> {
-        let new_clusters = self.alloc(size);
+        // right after the chain if there is room
+        let new_clusters = self.alloc_near(size, end_cluster + 1);
+        self.cursor = self.cursor.max(new_clusters.last().unwrap() + 1);
         self.write(end_cluster, new_clusters[0]);
         new_clusters
     }
 
+    // free clusters the device has free as well, none is taken,
+    // the FAT sectors modified by a transaction are read back from the device
+    fn spare(&mut self, count: usize) -> Vec<usize> {
+        let mut spare = Vec::new();
+        let mut sector = vec![0; self.sblock.byte_per_sector];
+        let mut loaded = 0;
+        let mut at = self.sblock.root_cluster;
+        while spare.len() < count {
+            let cluster = match self.map.next_free(at) {
+                Some(cluster) if cluster >= at => cluster,
+                _ => break,
+            };
+            let (addr, offset) = self.get_block_offset(cluster);
+            if addr != loaded {
+                self.device.read_blocks(addr, &mut sector);
+                loaded = addr;
+            }
+            if sector[offset..offset + 4].iter().all(|&b| b == 0) {
+                spare.push(cluster);
+            }
+            at = cluster + 1;
+        }
+        spare
+    }
+
+    // the next mount goes on from here
+    fn next_free(&self) -> usize {
+        self.cursor
+    }
+
     fn get_block_offset(&self, cluster: usize) -> (usize, usize) {
-        let addr = self.iterator.fat_addr;
-        let loc = cluster * 4;
-        (addr + loc / BLOCK_SIZE * BLOCK_SIZE, loc % BLOCK_SIZE) 
+        self.sblock.fat_entry(cluster)
     }
 }
 
@@ -212,7 +288,9 @@ impl FATManager {
         self.inner.push(fat);
     }
 
+    // a remount replaces the FAT of the last mount
     fn init(&mut self, device: &Arc<dyn BlockDevice>) {
+        self.inner.clear();
         self.push(FAT::new(device));
     }
 
@@ -230,10 +308,53 @@ impl FATManager {
         clusters
     }
 
-    fn dealloc(&mut self, cluster: usize) {
+    fn dealloc(&mut self, cluster: usize) -> usize {
+        let mut fat = self.inner();
+        let len = fat.dealloc(cluster);
+        self.push(fat);
+        len
+    }
+
+    fn create_refcounts(&mut self) -> usize {
+        let mut fat = self.inner();
+        let cluster = fat.create_refcounts();
+        self.push(fat);
+        cluster
+    }
+
+    fn share(&mut self, cluster: usize) -> usize {
         let mut fat = self.inner();
-        fat.dealloc(cluster);
+        let len = fat.share(cluster);
+        self.push(fat);
+        len
+    }
+
+    fn refcount(&mut self, cluster: usize) -> usize {
+        let fat = self.inner();
+        let refs = fat.refcount(cluster);
+        self.push(fat);
+        refs
+    }
+
+    fn spare(&mut self, count: usize) -> Vec<usize> {
+        let mut fat = self.inner();
+        let clusters = fat.spare(count);
+        self.push(fat);
+        clusters
+    }
+
+    fn next_free(&mut self) -> usize {
+        let fat = self.inner();
+        let cluster = fat.next_free();
+        self.push(fat);
+        cluster
+    }
+
+    fn can_grow(&mut self, end_cluster: usize) -> bool {
+        let fat = self.inner();
+        let ok = fat.can_grow(end_cluster);
         self.push(fat);
+        ok
     }
 
     fn increase(&mut self, end_cluster: usize, size: usize) -> Vec<usize> {
@@ -261,12 +382,17 @@ pub fn init_fat_manager(device: &Arc<dyn BlockDevice>) {
     FAT_MANAGER.lock().init(device)
 }
 
+// chains allocated, shared or freed are charged to the current owner
+// once the FAT is unlocked, the quota file may have to grow
 pub fn alloc_clusters(size: usize) -> Vec<usize> {
-    FAT_MANAGER.lock().alloc(size)
+    let clusters = FAT_MANAGER.lock().alloc(size);
+    quota::charge(clusters.len() as isize);
+    clusters
 }
 
 pub fn dealloc_clusters(cluster: usize) {
-    FAT_MANAGER.lock().dealloc(cluster)
+    let len = FAT_MANAGER.lock().dealloc(cluster);
+    quota::charge(-(len as isize));
 }
 
 pub fn read_clusters(cluster: usize) -> Vec<usize> {
@@ -274,5 +400,131 @@ pub fn read_clusters(cluster: usize) -> Vec<usize> {
 }
 
 pub fn increase_cluster(cluster: usize, size: usize) -> Vec<usize> {
-    FAT_MANAGER.lock().increase(cluster, size)
+    let clusters = FAT_MANAGER.lock().increase(cluster, size);
+    quota::charge(clusters.len() as isize);
+    clusters
+}
+
+/// whether more clusters can be linked after `end_cluster`,
+/// they can not while its FAT sector is corrupted
+pub(crate) fn can_grow(end_cluster: usize) -> bool {
+    FAT_MANAGER.lock().can_grow(end_cluster)
+}
+
+/// up to `count` clusters free both in the FAT and on the device,
+/// for the journal to keep copies in while it commits
+pub(crate) fn spare_clusters(count: usize) -> Vec<usize> {
+    FAT_MANAGER.lock().spare(count)
+}
+
+/// where the next mount starts looking for a free cluster
+pub(crate) fn next_free() -> usize {
+    FAT_MANAGER.lock().next_free()
+}
+
+/// allocate the refcount table if there is none yet, return its first cluster
+pub fn create_refcounts() -> usize {
+    FAT_MANAGER.lock().create_refcounts()
+}
+
+/// add a reference to every cluster of the chain of `cluster`,
+/// `dealloc_clusters` then drops one reference instead of freeing them
+pub fn share_clusters(cluster: usize) {
+    let len = FAT_MANAGER.lock().share(cluster);
+    quota::charge(len as isize);
+}
+
+/// whether another chain still references `cluster`
+pub fn is_shared(cluster: usize) -> bool {
+    FAT_MANAGER.lock().refcount(cluster) > 0
+}
+
+/// bytes of one refcount entry, it counts the references beyond the first one
+pub(crate) const REFCOUNT_SIZE: usize = 2;
+
+fn refcount_entry(table: &[usize], cluster: usize, sblock: &SuperBlock) -> (usize, usize) {
+    let loc = cluster * REFCOUNT_SIZE;
+    let bpc = sblock.byte_per_cluster();
+    let addr = sblock.offset(table[loc / bpc]) + loc % bpc;
+    let bps = sblock.byte_per_sector;
+    (addr / bps * bps, addr % bps)
+}
+
+/// extra references to `cluster`, `table` is the chain of the refcount table
+pub fn read_refcount(
+    table: &[usize],
+    cluster: usize,
+    sblock: &SuperBlock,
+    device: &Arc<dyn BlockDevice>,
+) -> usize {
+    let (addr, offset) = refcount_entry(table, cluster, sblock);
+    get_block_cache(addr, device)
+        .lock()
+        .read(offset, |refs: &u16| *refs) as usize
+}
+
+pub(crate) fn write_refcount(
+    table: &[usize],
+    cluster: usize,
+    refs: usize,
+    sblock: &SuperBlock,
+    device: &Arc<dyn BlockDevice>,
+) {
+    let (addr, offset) = refcount_entry(table, cluster, sblock);
+    get_block_cache(addr, device)
+        .lock()
+        .modify(offset, |value: &mut u16| *value = refs as u16);
+}
+
+/// raw FAT entry of `cluster`, read without the FAT manager
+pub fn read_fat(
+    cluster: usize,
+    sblock: &SuperBlock,
+    device: &Arc<dyn BlockDevice>,
+) -> usize {
+    let (addr, offset) = sblock.fat_entry(cluster);
+    get_block_cache(addr, device)
+        .lock()
+        .read(offset, |cluster: &u32| *cluster) as usize
+}
+
+/// set the raw FAT entry of `cluster`, without the FAT manager
+pub(crate) fn write_fat(
+    cluster: usize,
+    value: usize,
+    sblock: &SuperBlock,
+    device: &Arc<dyn BlockDevice>,
+) {
+    let (addr, offset) = sblock.fat_entry(cluster);
+    get_block_cache(addr, device)
+        .lock()
+        .modify(offset, |cluster: &mut u32| *cluster = value as u32);
+}
+
+/// walk the chain of `cluster` without spinning on the device
+pub async fn read_clusters_async(
+    cluster: usize,
+    sblock: &SuperBlock,
+    device: &Arc<dyn BlockDevice>,
+) -> Vec<usize> {
+    let mut cluster = cluster;
+    let mut clusters = Vec::new();
+    clusters.push(cluster);
+
+    loop {
+        let (addr, offset) = sblock.fat_entry(cluster);
+        let cache = get_block_cache_async(addr, device).await;
+        let cache = cache.lock();
+        cluster = match cache.is_corrupted() {
+            true => BAD_CLUSTER,
+            false => cache.read(offset, |cluster: &u32| *cluster) as usize,
+        };
+        if cluster >= BAD_CLUSTER {
+            break;
+        } else {
+            clusters.push(cluster);
+        }
+    }
+
+    clusters
 }
@@ -1,23 +1,90 @@
-use super::cache::get_block_cache;
+use super::cache::{
+    get_block_cache,
+    get_block_cache_async,
+    get_meta_cache,
+    get_meta_cache_async,
+    read_ahead,
+    read_blocks,
+    read_blocks_async,
+    write_blocks,
+    write_blocks_async,
+};
 use super::device::BlockDevice;
-use super::inode::INode;
-use super::sblock::SuperBlock;
-use super::BLOCK_SIZE;
-use super::{
-    iter_sector, 
-    iter_sector_mut
+use super::chacha::{
+    apply_keystream,
+    Key,
+};
+use super::crc::crc32c;
+use super::extent::{
+    write_extents,
+    Extent,
+    Extents,
+};
+use super::inode::{
+    INode,
+    FLAG_CHECKSUM,
+    FLAG_COMPRESSED,
+    FLAG_ENCRYPTED,
+    FLAG_EXTENTS,
+};
+use super::journal;
+use super::keyring::{
+    alloc_nonce,
+    find_key,
+};
+use super::lz4::{
+    compress,
+    decompress,
+};
+use super::quota::{
+    self,
+    Owner,
+};
+use super::sblock::{
+    SuperBlock,
+    FEATURE_EXTENTS,
+};
+use super::xattr::{
+    self,
+    read_attrs,
+    write_attrs,
+    Attrs,
 };
+use super::BLOCK_SIZE;
+use super::div_ceil;
 use super::fat::{
     alloc_clusters, 
-    dealloc_clusters
+    dealloc_clusters,
+    increase_cluster,
+    is_shared,
+    can_grow,
 };
+use alloc::borrow::Cow;
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
-use core::cmp::min;
+use core::cmp::{
+    max,
+    min,
+};
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum FileError {
     SeekValueOverFlow,
+    /// the file belongs to a snapshot
+    ReadOnly,
+    /// a data cluster does not match its checksum,
+    /// or a chunk of a compressed file cannot be decoded
+    Corrupted,
+    /// the file is encrypted with a key missing from the keyring
+    NoKey,
+    /// the file has no extended attribute of that name
+    NoAttr,
+    /// the attribute name is empty or too long, or its value too long
+    BadAttr,
+    /// the owner of the file would pass its hard limit
+    QuotaExceeded,
 }
 
 pub enum WriteType {
@@ -25,36 +92,66 @@ pub enum WriteType {
     Append,
 }
 
-#[repr(C)]
-struct Data {
-    inner: [u8; BLOCK_SIZE],
+pub struct FileEntry {
+    pub(crate) device: Arc<dyn BlockDevice>,
+    pub(crate) clusters: Extents,
+    pub(crate) size: usize,
+    pub(crate) seek_at: usize,
+    pub(crate) addr: usize,
+    pub(crate) sblock: SuperBlock,
+    pub(crate) read_only: bool,
+    pub(crate) flags: u32,
+    // chain of the data checksums, empty without `FLAG_CHECKSUM`
+    pub(crate) csum: Vec<usize>,
+    // chain of the chunk map, empty without `FLAG_COMPRESSED`
+    pub(crate) map: Vec<usize>,
+    // chain of the runs past the inline ones, empty while they all fit
+    pub(crate) extent: Vec<usize>,
+    pub(crate) key_id: u32,
+    pub(crate) nonce: u64,
+    // none when the file is not encrypted or its key is not in the keyring
+    pub(crate) key: Option<Key>,
 }
 
-impl Data {
-    fn empty() -> Self {
-        Data {
-            inner: [0; BLOCK_SIZE],
-        }
-    }
+/// the sizes of a file, `physical` is what its data clusters take on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub(crate) size: usize,
+    pub(crate) physical: usize,
+}
 
-    fn self_copy_from_slice(&mut self, offset: usize, buf: &[u8]) {
-        self.inner[offset..offset + buf.len()].copy_from_slice(buf);
+impl Metadata {
+    pub fn size(&self) -> usize {
+        self.size
     }
 
-    fn copy_from_slice(buf: &[u8]) -> Self {
-        let mut data = Data::empty();
-        data.inner[0..buf.len()].copy_from_slice(buf);
-        data
+    pub fn physical(&self) -> usize {
+        self.physical
     }
 }
 
-pub struct FileEntry {
-    pub(crate) device: Arc<dyn BlockDevice>,
-    pub(crate) clusters: Vec<usize>,
-    pub(crate) size: usize,
-    pub(crate) seek_at: usize,
-    pub(crate) addr: usize,
-    pub(crate) sblock: SuperBlock,
+// bytes of the checksum of one data cluster
+const CSUM_SIZE: usize = 4;
+
+// where a cluster-sized chunk of a compressed file is kept,
+// a chunk taking as many bytes as its data is stored as is
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Chunk {
+    // first sector in the data chain
+    sector: u32,
+    len: u32,
+}
+
+const CHUNK_SIZE: usize = core::mem::size_of::<Chunk>();
+
+// make sure `chain` can hold `end` bytes
+fn grow(chain: &mut Vec<usize>, end: usize, bpc: usize) {
+    let capacity = chain.len() * bpc;
+    if end > capacity {
+        let mut append_clusters = increase_cluster(*chain.last().unwrap(), end - capacity);
+        chain.append(&mut append_clusters);
+    }
 }
 
 impl FileEntry {
@@ -62,6 +159,18 @@ impl FileEntry {
         self.size
     }
 
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            size: self.size,
+            physical: self.clusters.len() * self.bpc(),
+        }
+    }
+
+    /// runs of contiguous clusters holding the data
+    pub fn extents(&self) -> &[Extent] {
+        self.clusters.runs()
+    }
+
     pub fn seek(&mut self, at: usize) -> Result<(), FileError> {
         if at > self.size {
             return Err(FileError::SeekValueOverFlow);
@@ -72,161 +181,813 @@ impl FileEntry {
 
     pub fn read_to_vec(&self, buf: &mut Vec<u8>) -> Result<usize, FileError> {
         buf.clear();
-
-        let mut idx = 0;
-        let mut len = 0;
-        let size = self.size;
-        let seek_at = self.seek_at;
-
-        iter_sector!(self, |data: &Data| {
-            let start = idx * BLOCK_SIZE;
-            let end = min((idx + 1) * BLOCK_SIZE, size - seek_at);
-            buf.append(&mut data.inner[seek_at..end - start + seek_at].to_vec());
-            idx += 1;
-            len += end - start;
-            end == size - seek_at
-        });
-
-        Ok(if len < size { len } else { size })
+        buf.resize(self.size - self.seek_at, 0);
+        self.read_data(self.seek_at, buf)
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FileError> {
-        let mut idx = 0;
-        let mut len = 0;
-        let size = self.size;
-        let seek_at = self.seek_at;
-        let buf_len = buf.len();
-
-        if buf_len == 0 {
+        if buf.is_empty() {
             panic!("if you use vec, you need use read_to_vec()")
         };
 
-        iter_sector!(self, |data: &Data| {
-            let start = idx * BLOCK_SIZE;
-            let end = min(min((idx + 1) * BLOCK_SIZE, buf_len), size - seek_at);
-            buf[start..end].copy_from_slice(&data.inner[seek_at..end - start + seek_at]);
-            idx += 1;
-            len += end - start;
-            end == buf_len || end == size - seek_at
-        });
-
-        let ret = if len < size { len } else { size };
-        self.seek_at = ret;
+        let len = min(buf.len(), self.size - self.seek_at);
+        let ret = self.read_data(self.seek_at, &mut buf[0..len])?;
+        self.seek_at += ret;
         Ok(ret)
     }
 
     pub fn write(&mut self, buf: &[u8], write_type: WriteType) -> Result<(), FileError> {
+        if self.read_only {
+            return Err(FileError::ReadOnly);
+        }
         if buf.is_empty() {
             return Ok(());
         }
+        self.unlocked()?;
+        self.growable()?;
+        let _owner = match write_type {
+            WriteType::OverWritten => self.owner(self.growth(max(buf.len(), 1)))?,
+            WriteType::Append => self.owner(self.growth(self.size + buf.len()))?,
+        };
 
-        let mut idx = 0;
-        let len = buf.len();
+        if self.is_compressed() && matches!(write_type, WriteType::Append) {
+            return self.write_compressed(self.size, buf).map(|_| ());
+        }
 
+        let len = buf.len();
+        journal::begin();
         match write_type {
-            WriteType::OverWritten => {
-                self.clean_data();
-                dealloc_clusters(self.clusters[0]);
-                self.clusters.clear();
-                self.clusters = alloc_clusters(len);
-                iter_sector_mut!(self, |data: &mut Data| {
-                    let start = idx * BLOCK_SIZE;
-                    let end = min(start + BLOCK_SIZE, len);
-                    *data = Data::copy_from_slice(&buf[start..end]);
-                    idx += 1;
-                    end == len
-                });
-                self.size = len;
-            }
+            WriteType::OverWritten => self.overwrite(buf),
             WriteType::Append => {
-                let spc = self.sblock.sector_per_cluster;
-                let bps = self.sblock.byte_per_sector;
-                let bpc = bps * spc;
+                self.unshare();
+                self.reserve(self.size + len);
+                self.write_inner(self.size, buf);
+                self.update_csums(self.size, len);
+                self.size += len;
+            }
+        }
 
-                let (cluster_from, wrote_size) = (self.size / bpc, self.size % bpc);
-                let (sector_from, offset) = (wrote_size / bps, wrote_size % bps);
+        self.update();
+        journal::commit();
+        Ok(())
+    }
 
-                let left_byte = min(BLOCK_SIZE - offset, len);
+    /// read from `offset` without moving the seek position
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FileError> {
+        if offset > self.size {
+            return Err(FileError::SeekValueOverFlow);
+        }
+        let len = min(buf.len(), self.size - offset);
+        self.read_data(offset, &mut buf[0..len])
+    }
 
-                get_block_cache(
-                    self.sblock.offset(self.clusters[cluster_from]),
-                    &self.device,
-                )
-                .lock()
-                .modify(sector_from * BLOCK_SIZE, |data: &mut Data| {
-                    data.self_copy_from_slice(offset, &buf[0..left_byte])
-                });
-
-                let mut done = left_byte == len;
-
-                if !done {
-                    for sector in sector_from + 1..spc {
-                        let start = idx * BLOCK_SIZE + left_byte;
-                        let end = min(start + BLOCK_SIZE, len);
-                        idx += 1;
-    
-                        get_block_cache(
-                            self.sblock.offset(self.clusters[cluster_from]),
-                            &self.device,
-                        )
-                        .lock()
-                        .modify(sector * BLOCK_SIZE, |data: &mut Data| {
-                            *data = Data::copy_from_slice(&buf[start..end]);
-                        });
-    
-                        if end == len {
-                            done = true;
-                            break;
-                        }
-                    }
-                }
-                
-                if !done {
-                    idx = 0;
-                    let last_write = bpc - wrote_size;
-                    let mut append_clusters = alloc_clusters(len - last_write);
-                    for &cluster in append_clusters.iter() {
-                        for sector in 0..spc {
-                            let start = idx * BLOCK_SIZE + last_write;
-                            let end = min(start + BLOCK_SIZE, len);
-                            idx += 1;
-
-                            get_block_cache(self.sblock.offset(cluster), &self.device)
-                                .lock()
-                                .modify(sector * BLOCK_SIZE, |data: &mut Data| {
-                                    *data = Data::copy_from_slice(&buf[start..end]);
-                                });
-                            if end == len {
-                                done = true;
-                                break;
-                            }
-                        }
-                        if done { break; }
-                    }
-                    self.clusters.append(&mut append_clusters);
-                }
+    /// write at `offset`, the file grows if the data passes its end
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<usize, FileError> {
+        if self.read_only {
+            return Err(FileError::ReadOnly);
+        }
+        if offset > self.size {
+            return Err(FileError::SeekValueOverFlow);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.unlocked()?;
+        self.growable()?;
+        let _owner = self.owner(self.growth(offset + buf.len()))?;
+        if self.key.is_some() && offset < self.size {
+            return self.rewrite_sealed(offset, buf);
+        }
+        if self.is_compressed() {
+            return self.write_compressed(offset, buf);
+        }
+        journal::begin();
+        self.unshare();
+        self.reserve(offset + buf.len());
+        self.write_inner(offset, buf);
+        self.update_csums(offset, buf.len());
+        self.size = max(self.size, offset + buf.len());
+        self.update();
+        journal::commit();
+        Ok(buf.len())
+    }
 
-                self.size += len;
+    pub async fn read_at_async(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FileError> {
+        if offset > self.size {
+            return Err(FileError::SeekValueOverFlow);
+        }
+        let len = min(buf.len(), self.size - offset);
+        self.read_data_async(offset, &mut buf[0..len]).await
+    }
+
+    /// cluster allocation still goes through the FAT synchronously,
+    /// data and the inode sector are written without spinning on the device,
+    /// compressed files are written synchronously,
+    /// as are encrypted files written over their bytes
+    pub async fn write_at_async(&mut self, offset: usize, buf: &[u8]) -> Result<usize, FileError> {
+        if self.read_only {
+            return Err(FileError::ReadOnly);
+        }
+        if offset > self.size {
+            return Err(FileError::SeekValueOverFlow);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.unlocked()?;
+        self.growable()?;
+        let _owner = self.owner(self.growth(offset + buf.len()))?;
+        if self.key.is_some() && offset < self.size {
+            return self.rewrite_sealed(offset, buf);
+        }
+        if self.is_compressed() {
+            return self.write_compressed(offset, buf);
+        }
+        journal::begin();
+        self.unshare();
+        self.reserve(offset + buf.len());
+        self.write_inner_async(offset, buf).await;
+        self.update_csums(offset, buf.len());
+        self.size = max(self.size, offset + buf.len());
+        // load the inode sector first and hold it, so update() only hits the cache
+        let _inode = get_meta_cache_async(self.addr, &self.device).await;
+        self.update();
+        journal::commit();
+        Ok(buf.len())
+    }
+
+    // a snapshot may still read the data
+    pub(crate) fn clean_data(&mut self) {
+        if is_shared(self.clusters.first()) {
+            return;
+        }
+        let mut idx = 0;
+        while idx < self.clusters.len() {
+            let (addr, len) = self.run_at(idx * self.bpc());
+            write_blocks(addr, &vec![0; len], &self.device);
+            idx += len / self.bpc();
+        }
+    }
+
+    // copy the chains shared with a snapshot before writing to them
+    fn unshare(&mut self) {
+        if is_shared(self.clusters.first()) {
+            let chain: Vec<usize> = self.clusters.iter().collect();
+            self.clusters = self.copy_chain(&chain).into();
+        }
+        if !self.csum.is_empty() && is_shared(self.csum[0]) {
+            self.csum = self.copy_chain(&self.csum);
+        }
+        if !self.map.is_empty() && is_shared(self.map[0]) {
+            self.map = self.copy_chain(&self.map);
+        }
+    }
+
+    // drop the data and write `buf` from the start
+    fn overwrite(&mut self, buf: &[u8]) {
+        // a fresh keystream, the old data was encrypted with the old one
+        if self.key.is_some() {
+            self.nonce = alloc_nonce(&self.device);
+        }
+        self.clean_data();
+        dealloc_clusters(self.clusters.first());
+        self.clusters = match self.is_compressed() {
+            true => alloc_clusters(BLOCK_SIZE),
+            false => alloc_clusters(max(buf.len(), 1)),
+        }.into();
+        self.unshare();
+        if self.is_compressed() {
+            self.write_chunks(0, 0, buf);
+        } else {
+            self.write_inner(0, buf);
+            self.update_csums(0, buf.len());
+            self.size = buf.len();
+        }
+    }
+
+    fn copy_chain(&self, chain: &[usize]) -> Vec<usize> {
+        let bpc = self.bpc();
+        let clusters = alloc_clusters(chain.len() * bpc);
+        let mut buf = vec![0; bpc];
+        for (&from, &to) in chain.iter().zip(clusters.iter()) {
+            read_blocks(self.sblock.offset(from), &mut buf, &self.device);
+            write_blocks(self.sblock.offset(to), &buf, &self.device);
+        }
+        dealloc_clusters(chain[0]);
+        clusters
+    }
+
+    pub fn is_checksummed(&self) -> bool {
+        self.flags & FLAG_CHECKSUM != 0
+    }
+
+    /// keep a checksum of every data cluster, verified on each read
+    pub fn set_checksummed(&mut self, checksummed: bool) -> Result<(), FileError> {
+        if self.read_only {
+            return Err(FileError::ReadOnly);
+        }
+        if checksummed == self.is_checksummed() {
+            return Ok(());
+        }
+        let csum_clusters = match checksummed {
+            true => div_ceil(self.clusters.len() * CSUM_SIZE, self.bpc()),
+            false => 0,
+        };
+        self.growable()?;
+        let _owner = self.owner(csum_clusters)?;
+
+        journal::begin();
+        if checksummed {
+            self.flags |= FLAG_CHECKSUM;
+            self.csum = alloc_clusters(self.clusters.len() * CSUM_SIZE);
+            self.update_csums(0, self.clusters.len() * self.bpc());
+        } else {
+            self.flags &= !FLAG_CHECKSUM;
+            dealloc_clusters(self.csum[0]);
+            self.csum.clear();
+        }
+        self.update();
+        journal::commit();
+        Ok(())
+    }
+
+    pub fn get_xattr(&self, key: &str) -> Result<Vec<u8>, FileError> {
+        self.attrs().remove(key).ok_or(FileError::NoAttr)
+    }
+
+    pub fn list_xattr(&self) -> Vec<String> {
+        self.attrs().keys().cloned().collect()
+    }
+
+    pub fn set_xattr(&mut self, key: &str, value: &[u8]) -> Result<(), FileError> {
+        if self.read_only {
+            return Err(FileError::ReadOnly);
+        }
+        if !xattr::is_valid(key, value) {
+            return Err(FileError::BadAttr);
+        }
+        self.update_xattr(|attrs| {
+            attrs.insert(key.into(), value.to_vec());
+            Ok(())
+        })
+    }
+
+    pub fn remove_xattr(&mut self, key: &str) -> Result<(), FileError> {
+        if self.read_only {
+            return Err(FileError::ReadOnly);
+        }
+        self.update_xattr(|attrs| attrs.remove(key).map(|_| ()).ok_or(FileError::NoAttr))
+    }
+
+    fn attrs(&self) -> Attrs {
+        read_attrs(&self.inode(), &self.sblock, &self.device)
+    }
+
+    // the owner is only charged for the clusters the attributes take beyond the inode
+    fn update_xattr(&mut self, f: impl FnOnce(&mut Attrs) -> Result<(), FileError>) -> Result<(), FileError> {
+        let inode = self.inode();
+        let mut attrs = read_attrs(&inode, &self.sblock, &self.device);
+        f(&mut attrs)?;
+        let _owner = self.owner(xattr::growth(&inode, &attrs, &self.sblock))?;
+        journal::begin();
+        write_attrs(self.addr, &attrs, &self.sblock, &self.device);
+        journal::commit();
+        Ok(())
+    }
+
+    fn inode(&self) -> INode {
+        get_meta_cache(self.addr, &self.device).lock().read(0, |inode: &INode| *inode)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    /// id of the key of the file in the keyring, 0 when it is not encrypted
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// encrypt the data with the key `key_id` of the keyring, 0 to decrypt it,
+    /// the file is written again
+    pub fn set_key(&mut self, key_id: u32) -> Result<(), FileError> {
+        if self.read_only {
+            return Err(FileError::ReadOnly);
+        }
+        if key_id == self.key_id {
+            return Ok(());
+        }
+        let key = match key_id {
+            0 => None,
+            id => Some(find_key(id).ok_or(FileError::NoKey)?),
+        };
+        let mut data = vec![0; self.size];
+        self.read_data(0, &mut data)?;
+        self.growable()?;
+        let _owner = self.owner(0)?;
+
+        journal::begin();
+        match key_id {
+            0 => self.flags &= !FLAG_ENCRYPTED,
+            _ => self.flags |= FLAG_ENCRYPTED,
+        }
+        self.key_id = key_id;
+        self.key = key;
+        self.overwrite(&data);
+        self.update();
+        journal::commit();
+        Ok(())
+    }
+
+    // clusters taken or freed while the guard lives are charged to the owner of the file,
+    // who must have room for `clusters` more
+    fn owner(&self, clusters: usize) -> Result<Owner, FileError> {
+        let inode = self.inode();
+        match quota::allows(inode.uid(), inode.gid(), clusters) {
+            true => Ok(quota::enter(inode.uid(), inode.gid())),
+            false => Err(FileError::QuotaExceeded),
+        }
+    }
+
+    // clusters the data chain needs beyond the ones it has to hold `end` bytes
+    fn growth(&self, end: usize) -> usize {
+        div_ceil(end, self.bpc()).saturating_sub(self.clusters.len())
+    }
+
+    fn unlocked(&self) -> Result<(), FileError> {
+        match self.is_encrypted() && self.key.is_none() {
+            true => Err(FileError::NoKey),
+            false => Ok(()),
+        }
+    }
+
+    // a corrupted FAT sector cuts a chain short,
+    // the data is not read past the cut
+    fn intact(&self) -> Result<(), FileError> {
+        match !self.is_compressed() && self.clusters.len() * self.bpc() < self.size {
+            true => Err(FileError::Corrupted),
+            false => Ok(()),
+        }
+    }
+
+    // nor is a chain grown from a cluster whose FAT sector is corrupted
+    fn growable(&self) -> Result<(), FileError> {
+        self.intact()?;
+        let ends = [self.csum.last(), self.map.last(), self.extent.last()];
+        match can_grow(self.clusters.last()) && ends.iter().flatten().all(|&&c| can_grow(c)) {
+            true => Ok(()),
+            false => Err(FileError::Corrupted),
+        }
+    }
+
+    // the keystream runs over the data chain, from the nonce of the file
+    fn nonce_bytes(&self) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[4..12].copy_from_slice(&self.nonce.to_le_bytes());
+        nonce
+    }
+
+    // encrypt `buf` for byte `offset` of the data chain
+    fn seal<'a>(&self, offset: usize, buf: &'a [u8]) -> Cow<'a, [u8]> {
+        match &self.key {
+            Some(key) => {
+                let mut data = buf.to_vec();
+                apply_keystream(key, &self.nonce_bytes(), offset, &mut data);
+                Cow::Owned(data)
             }
+            None => Cow::Borrowed(buf),
+        }
+    }
+
+    fn unseal(&self, offset: usize, buf: &mut [u8]) {
+        if let Some(key) = &self.key {
+            apply_keystream(key, &self.nonce_bytes(), offset, buf);
         }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
 
+    /// keep the data as LZ4 chunks of a cluster each, the file is written again
+    pub fn set_compressed(&mut self, compressed: bool) -> Result<(), FileError> {
+        if self.read_only {
+            return Err(FileError::ReadOnly);
+        }
+        if compressed == self.is_compressed() {
+            return Ok(());
+        }
+        let mut data = vec![0; self.size];
+        self.read_data(0, &mut data)?;
+        self.growable()?;
+        // the map takes a cluster, the data may not shrink
+        let _owner = match compressed {
+            true => self.owner(1)?,
+            false => self.owner(self.growth(self.size))?,
+        };
+
+        journal::begin();
+        if compressed {
+            self.flags |= FLAG_COMPRESSED;
+            self.map = alloc_clusters(BLOCK_SIZE);
+            self.overwrite(&data);
+        } else {
+            self.flags &= !FLAG_COMPRESSED;
+            self.overwrite(&data);
+            dealloc_clusters(self.map[0]);
+            self.map.clear();
+        }
         self.update();
+        journal::commit();
         Ok(())
     }
 
-    pub(crate) fn clean_data(&mut self) {
-        iter_sector_mut!(self, |data: &mut Data| {
-            *data = Data::empty();
-            false
-        });
+    /// check the whole file against its checksums,
+    /// and that every chunk of a compressed file decodes
+    pub fn verify(&self) -> Result<(), FileError> {
+        if self.is_compressed() {
+            return self.read_data(0, &mut vec![0; self.size]).map(|_| ());
+        }
+        self.verify_range(0, self.size)
+    }
+
+    // data clusters, or chunks of a compressed file, holding the bytes `offset..offset + len`
+    fn clusters_of(&self, offset: usize, len: usize) -> core::ops::Range<usize> {
+        match len {
+            0 => 0..0,
+            _ => offset / self.bpc()..(offset + len - 1) / self.bpc() + 1,
+        }
+    }
+
+    // device address of byte `loc` of `chain`, none past its end
+    fn chain_addr(&self, chain: &[usize], loc: usize) -> Option<usize> {
+        let cluster = chain.get(loc / self.bpc())?;
+        Some(self.sblock.offset(*cluster) + loc % self.bpc())
+    }
+
+    // device address of the checksum of data cluster `idx`,
+    // none when the chain was cut short
+    fn csum_addr(&self, idx: usize) -> Option<usize> {
+        self.chain_addr(&self.csum, idx * CSUM_SIZE)
+    }
+
+    fn stored_csum(&self, idx: usize) -> Option<u32> {
+        let addr = self.csum_addr(idx)?;
+        let in_sector = addr % self.sblock.byte_per_sector;
+        Some(get_block_cache(addr - in_sector, &self.device)
+            .lock()
+            .read(in_sector, |csum: &u32| *csum))
+    }
+
+    fn cluster_csum(&self, idx: usize) -> u32 {
+        let mut buf = vec![0; self.bpc()];
+        read_blocks(self.sblock.offset(self.clusters.get(idx)), &mut buf, &self.device);
+        crc32c(&buf)
+    }
+
+    // `offset` and `len` are in the data chain, as in the file unless it is compressed
+    fn verify_range(&self, offset: usize, len: usize) -> Result<(), FileError> {
+        if !self.is_checksummed() {
+            return Ok(());
+        }
+        for idx in self.clusters_of(offset, len) {
+            if self.stored_csum(idx) != Some(self.cluster_csum(idx)) {
+                return Err(FileError::Corrupted);
+            }
+        }
+        Ok(())
+    }
+
+    async fn verify_range_async(&self, offset: usize, len: usize) -> Result<(), FileError> {
+        if !self.is_checksummed() {
+            return Ok(());
+        }
+        let mut buf = vec![0; self.bpc()];
+        for idx in self.clusters_of(offset, len) {
+            read_blocks_async(self.sblock.offset(self.clusters.get(idx)), &mut buf, &self.device).await;
+            if self.stored_csum(idx) != Some(crc32c(&buf)) {
+                return Err(FileError::Corrupted);
+            }
+        }
+        Ok(())
+    }
+
+    // checksum the data clusters written again
+    fn update_csums(&mut self, offset: usize, len: usize) {
+        if !self.is_checksummed() {
+            return;
+        }
+        let bpc = self.bpc();
+        grow(&mut self.csum, self.clusters.len() * CSUM_SIZE, bpc);
+        for idx in self.clusters_of(offset, len) {
+            let csum = self.cluster_csum(idx);
+            let addr = self.csum_addr(idx).unwrap();
+            let in_sector = addr % self.sblock.byte_per_sector;
+            get_block_cache(addr - in_sector, &self.device)
+                .lock()
+                .modify(in_sector, |value: &mut u32| *value = csum);
+        }
+    }
+
+    // verify and read the bytes at `offset` of the file
+    fn read_data(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FileError> {
+        self.unlocked()?;
+        self.intact()?;
+        if !self.is_compressed() {
+            self.verify_range(offset, buf.len())?;
+            return Ok(self.read_inner(offset, buf));
+        }
+        let mut chunk = vec![0; self.bpc()];
+        let mut done = 0;
+        for idx in self.clusters_of(offset, buf.len()) {
+            let (pos, stored) = self.chunk_at(idx)?;
+            self.verify_range(pos, stored)?;
+            let mut packed = vec![0; stored];
+            self.read_inner(pos, &mut packed);
+            let len = self.unpack(idx, &packed, &mut chunk)?;
+            let from = offset + done - idx * self.bpc();
+            let num = min(len - from, buf.len() - done);
+            buf[done..done + num].copy_from_slice(&chunk[from..from + num]);
+            done += num;
+        }
+        Ok(done)
+    }
+
+    async fn read_data_async(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FileError> {
+        self.unlocked()?;
+        self.intact()?;
+        if !self.is_compressed() {
+            self.verify_range_async(offset, buf.len()).await?;
+            return Ok(self.read_inner_async(offset, buf).await);
+        }
+        let mut chunk = vec![0; self.bpc()];
+        let mut done = 0;
+        for idx in self.clusters_of(offset, buf.len()) {
+            let (pos, stored) = self.chunk_at(idx)?;
+            self.verify_range_async(pos, stored).await?;
+            let mut packed = vec![0; stored];
+            self.read_inner_async(pos, &mut packed).await;
+            let len = self.unpack(idx, &packed, &mut chunk)?;
+            let from = offset + done - idx * self.bpc();
+            let num = min(len - from, buf.len() - done);
+            buf[done..done + num].copy_from_slice(&chunk[from..from + num]);
+            done += num;
+        }
+        Ok(done)
+    }
+
+    // where chunk `idx` is kept in the data chain, and the bytes it takes there
+    fn chunk_at(&self, idx: usize) -> Result<(usize, usize), FileError> {
+        let addr = self.chain_addr(&self.map, idx * CHUNK_SIZE).ok_or(FileError::Corrupted)?;
+        let in_sector = addr % self.sblock.byte_per_sector;
+        let chunk = get_block_cache(addr - in_sector, &self.device)
+            .lock()
+            .read(in_sector, |chunk: &Chunk| *chunk);
+        let (pos, len) = (chunk.sector as usize * self.sblock.byte_per_sector, chunk.len as usize);
+        if len > self.bpc() || pos + len > self.clusters.len() * self.bpc() {
+            return Err(FileError::Corrupted);
+        }
+        Ok((pos, len))
+    }
+
+    fn set_chunk(&self, idx: usize, pos: usize, len: usize) {
+        let addr = self.chain_addr(&self.map, idx * CHUNK_SIZE).unwrap();
+        let in_sector = addr % self.sblock.byte_per_sector;
+        get_block_cache(addr - in_sector, &self.device)
+            .lock()
+            .modify(in_sector, |chunk: &mut Chunk| {
+                *chunk = Chunk { sector: (pos / self.sblock.byte_per_sector) as u32, len: len as u32 }
+            });
+    }
+
+    // decode chunk `idx` into `out`, return the bytes of the file it holds
+    fn unpack(&self, idx: usize, packed: &[u8], out: &mut [u8]) -> Result<usize, FileError> {
+        let len = min(self.bpc(), self.size - idx * self.bpc());
+        if packed.len() == len {
+            out[0..len].copy_from_slice(packed);
+        } else if decompress(packed, &mut out[0..len]) != Some(len) {
+            return Err(FileError::Corrupted);
+        }
+        Ok(len)
+    }
+
+    // compress again from the chunk holding `offset` to the end of the file,
+    // the chunks before it stay where they are
+    fn write_compressed(&mut self, offset: usize, buf: &[u8]) -> Result<usize, FileError> {
+        // the last chunk is stored again where it was, appending included
+        if self.key.is_some() {
+            return self.rewrite_sealed(offset, buf);
+        }
+        let bpc = self.bpc();
+        let first = offset / bpc;
+        let start = match first {
+            0 => 0,
+            _ => {
+                let (pos, len) = self.chunk_at(first - 1)?;
+                div_ceil(pos + len, self.sblock.byte_per_sector) * self.sblock.byte_per_sector
+            }
+        };
+        let mut data = vec![0; self.size - first * bpc];
+        self.read_data(first * bpc, &mut data)?;
+        let from = offset - first * bpc;
+        if data.len() < from + buf.len() {
+            data.resize(from + buf.len(), 0);
+        }
+        data[from..from + buf.len()].copy_from_slice(buf);
+
+        journal::begin();
+        self.unshare();
+        self.write_chunks(first, start, &data);
+        self.update();
+        journal::commit();
+        Ok(buf.len())
+    }
+
+    // bytes are never stored again under the keystream that sealed them,
+    // the xor of the two ciphertexts would be the xor of the two plain texts,
+    // so the whole file is sealed again from a new nonce
+    fn rewrite_sealed(&mut self, offset: usize, buf: &[u8]) -> Result<usize, FileError> {
+        let mut data = vec![0; max(self.size, offset + buf.len())];
+        self.read_data(0, &mut data[..self.size])?;
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        journal::begin();
+        self.overwrite(&data);
+        self.update();
+        journal::commit();
+        Ok(buf.len())
+    }
+
+    // store `data` as the chunks from `first` on, packed from byte `start`
+    // of the data chain, each chunk from a new sector
+    fn write_chunks(&mut self, first: usize, start: usize, data: &[u8]) {
+        let bpc = self.bpc();
+        grow(&mut self.map, (first + div_ceil(data.len(), bpc)) * CHUNK_SIZE, bpc);
+        let mut pos = start;
+        for (idx, chunk) in data.chunks(bpc).enumerate() {
+            let packed = compress(chunk);
+            let stored = match packed.len() < chunk.len() {
+                true => &packed[..],
+                false => chunk,
+            };
+            self.reserve(pos + stored.len());
+            self.write_inner(pos, stored);
+            self.set_chunk(first + idx, pos, stored.len());
+            let bps = self.sblock.byte_per_sector;
+            pos = div_ceil(pos + stored.len(), bps) * bps;
+        }
+        self.update_csums(start, pos - start);
+        self.size = first * bpc + data.len();
+    }
+
+    // make sure the clusters can hold `end` bytes
+    fn reserve(&mut self, end: usize) {
+        let capacity = self.clusters.len() * self.bpc();
+        if end > capacity {
+            self.clusters.extend(&increase_cluster(self.clusters.last(), end - capacity));
+        }
+    }
+
+    fn bpc(&self) -> usize {
+        self.sblock.byte_per_sector * self.sblock.sector_per_cluster
+    }
+
+    // device address of byte `offset` of the file,
+    // and how many bytes from there are contiguous on the device
+    fn run_at(&self, offset: usize) -> (usize, usize) {
+        let bpc = self.bpc();
+        let (first, num) = self.clusters.run_at(offset / bpc);
+        (self.sblock.offset(first) + offset % bpc, num * bpc - offset % bpc)
+    }
+
+    // the sectors of the chain from the one holding byte `offset`,
+    // as runs of contiguous ones
+    fn sector_runs(&self, offset: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let bps = self.sblock.byte_per_sector;
+        let spc = self.sblock.sector_per_cluster;
+        let mut idx = offset / self.bpc();
+        let mut skip = offset % self.bpc() / bps;
+        core::iter::from_fn(move || {
+            if idx >= self.clusters.len() {
+                return None;
+            }
+            let (first, num) = self.clusters.run_at(idx);
+            let run = (self.sblock.offset(first) + skip * bps, num * spc - skip);
+            idx += num;
+            skip = 0;
+            Some(run)
+        })
+    }
+
+    // whole sectors of a cluster run go to the device in one request,
+    // partial sectors go through the block cache, read ahead along the chain
+    fn read_inner(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut done = 0;
+        while done < buf.len() {
+            let (addr, contiguous) = self.run_at(offset + done);
+            let len = min(contiguous, buf.len() - done);
+            let bps = self.sblock.byte_per_sector;
+            let in_sector = addr % bps;
+            if in_sector == 0 && len >= bps {
+                let len = len / bps * bps;
+                read_blocks(addr, &mut buf[done..done + len], &self.device);
+                done += len;
+            } else {
+                let len = min(len, bps - in_sector);
+                read_ahead(self.sector_runs(offset + done), &self.device);
+                let cache = get_block_cache(addr - in_sector, &self.device);
+                buf[done..done + len].copy_from_slice(&cache.lock().data()[in_sector..in_sector + len]);
+                done += len;
+            }
+        }
+        self.unseal(offset, buf);
+        done
+    }
+
+    fn write_inner(&self, offset: usize, buf: &[u8]) {
+        let buf = &self.seal(offset, buf)[..];
+        let mut done = 0;
+        while done < buf.len() {
+            let (addr, contiguous) = self.run_at(offset + done);
+            let len = min(contiguous, buf.len() - done);
+            let bps = self.sblock.byte_per_sector;
+            let in_sector = addr % bps;
+            if in_sector == 0 && len >= bps {
+                let len = len / bps * bps;
+                write_blocks(addr, &buf[done..done + len], &self.device);
+                done += len;
+            } else {
+                let len = min(len, bps - in_sector);
+                get_block_cache(addr - in_sector, &self.device)
+                    .lock()
+                    .data_mut()[in_sector..in_sector + len]
+                    .copy_from_slice(&buf[done..done + len]);
+                done += len;
+            }
+        }
+    }
+
+    async fn read_inner_async(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut done = 0;
+        while done < buf.len() {
+            let (addr, contiguous) = self.run_at(offset + done);
+            let len = min(contiguous, buf.len() - done);
+            let bps = self.sblock.byte_per_sector;
+            let in_sector = addr % bps;
+            if in_sector == 0 && len >= bps {
+                let len = len / bps * bps;
+                read_blocks_async(addr, &mut buf[done..done + len], &self.device).await;
+                done += len;
+            } else {
+                let len = min(len, bps - in_sector);
+                let cache = get_block_cache_async(addr - in_sector, &self.device).await;
+                buf[done..done + len].copy_from_slice(&cache.lock().data()[in_sector..in_sector + len]);
+                done += len;
+            }
+        }
+        self.unseal(offset, buf);
+        done
+    }
+
+    async fn write_inner_async(&self, offset: usize, buf: &[u8]) {
+        let buf = &self.seal(offset, buf)[..];
+        let mut done = 0;
+        while done < buf.len() {
+            let (addr, contiguous) = self.run_at(offset + done);
+            let len = min(contiguous, buf.len() - done);
+            let bps = self.sblock.byte_per_sector;
+            let in_sector = addr % bps;
+            if in_sector == 0 && len >= bps {
+                let len = len / bps * bps;
+                write_blocks_async(addr, &buf[done..done + len], &self.device).await;
+                done += len;
+            } else {
+                let len = min(len, bps - in_sector);
+                get_block_cache_async(addr - in_sector, &self.device)
+                    .await
+                    .lock()
+                    .data_mut()[in_sector..in_sector + len]
+                    .copy_from_slice(&buf[done..done + len]);
+                done += len;
+            }
+        }
     }
 
     fn update(&mut self) {
-        get_block_cache(self.addr, &self.device)
+        // a file keeps its runs once it has them
+        if self.sblock.has_feature(FEATURE_EXTENTS) || self.flags & FLAG_EXTENTS != 0 {
+            self.flags |= FLAG_EXTENTS;
+            write_extents(self.addr, &mut self.clusters, &mut self.extent, &self.sblock, &self.device);
+        }
+        get_meta_cache(self.addr, &self.device)
             .lock()
             .modify(0, |inode: &mut INode| {
                 inode.i_size_lo = self.size as u32;
-                inode.i_cluster = self.clusters[0] as u32;
+                inode.i_cluster = self.clusters.first() as u32;
+                inode.i_flags = self.flags;
+                inode.i_csum_cluster = self.csum.first().copied().unwrap_or(0) as u32;
+                inode.i_map_cluster = self.map.first().copied().unwrap_or(0) as u32;
+                inode.i_key_id = self.key_id;
+                inode.i_nonce = self.nonce;
             })
     }
 }
@@ -0,0 +1,941 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use super::div_ceil;
+use super::cache::enable_checksums;
+use super::cache::get_block_cache;
+use super::cache::get_meta_cache;
+use super::cache::set_sector_size;
+use super::crc::verify_sector;
+use super::cache::sync_all;
+use super::cache::write_blocks;
+use super::device::BlockDevice;
+use super::extent::read_extents;
+use super::extent::Extents;
+use super::fat::read_fat;
+use super::fat::write_fat;
+use super::fat::read_refcount;
+use super::fat::write_refcount;
+use super::fat::REFCOUNT_SIZE;
+use super::journal::pending;
+use super::journal::replay;
+use super::inode::INode;
+use super::inode::INodeType;
+use super::inode::FLAG_CHECKSUM;
+use super::inode::FLAG_COMPRESSED;
+use super::inode::FLAG_EXTENTS;
+use super::inode::NAME_LEN;
+use super::sblock::get_sblock;
+use super::sblock::modify_sblock;
+use super::sblock::probe_sector_size;
+use super::sblock::SuperBlock;
+use super::sblock::FEATURE_CHECKSUMS;
+
+const LOST_FOUND: &str = "lost+found";
+// owners reported for the clusters of the volume itself
+const JOURNAL_PATH: &str = "<journal>";
+const REFCOUNT_PATH: &str = "<refcounts>";
+const SNAPSHOT_PATH: &str = "<snapshots>";
+const QUOTA_PATH: &str = "<quota>";
+// a pass can leave work for the next one, truncated tails become lost clusters
+const REPAIR_PASSES: usize = 4;
+
+/// an inconsistency between the FAT and the directory tree,
+/// `path` names the entry, `addr` is the sector holding its inode,
+/// 0 for the root directory and chains without an inode of their own,
+/// such as the journal or the attributes of an entry, `last` is the last
+/// cluster of a chain that can be trusted, none if the first one is bad
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// `cluster` is also used by `owner`
+    CrossLinked { path: String, addr: usize, last: Option<usize>, cluster: usize, owner: String },
+    /// the chain comes back to `cluster`, which is in it already
+    ChainLoop { path: String, addr: usize, last: Option<usize>, cluster: usize },
+    /// the chain runs to `cluster`, which is outside the FAT
+    OutOfRange { path: String, addr: usize, last: Option<usize>, cluster: usize },
+    /// the chain runs through `cluster`, which the FAT says is free
+    FreeInChain { path: String, addr: usize, last: Option<usize>, cluster: usize },
+    /// `cluster` is allocated, but no chain reaches it
+    LostCluster { cluster: usize },
+    /// `size` bytes do not fit in, or leave unused, `clusters` clusters
+    SizeMismatch { path: String, addr: usize, size: usize, clusters: usize },
+    /// `parent` should be `expected`, the first cluster of the directory
+    BadParent { path: String, addr: usize, parent: usize, expected: usize },
+    /// the name is too long or not UTF-8
+    InvalidName { path: String, addr: usize },
+    /// the entry type is `value`, which is not a known type
+    InvalidType { path: String, addr: usize, value: u8 },
+    /// the refcount table says `refs` extra references, `expected` were found
+    BadRefCount { cluster: usize, refs: usize, expected: usize },
+    /// the FAT or directory sector at `addr` does not match its checksum
+    BadChecksum { addr: usize },
+    /// the superblock counts `stored` free clusters, `counted` were found
+    BadFreeCount { stored: usize, counted: usize },
+    /// the superblock counts `stored` entries, `counted` were found
+    BadInodeCount { stored: usize, counted: usize },
+    /// the runs kept in the inode do not match the data chain
+    BadExtents { path: String, addr: usize },
+    /// a committed transaction of `blocks` blocks is still to be written home,
+    /// nothing else is checked until it is
+    PendingJournal { blocks: usize },
+}
+
+/// one line per problem: its kind, then `key=value` pairs
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::CrossLinked { path, addr, last, cluster, owner } => write!(
+                f, "cross_linked path={:?} addr={} last={} cluster={} owner={:?}",
+                path, addr, Last(*last), cluster, owner
+            ),
+            Problem::ChainLoop { path, addr, last, cluster } => write!(
+                f, "chain_loop path={:?} addr={} last={} cluster={}",
+                path, addr, Last(*last), cluster
+            ),
+            Problem::OutOfRange { path, addr, last, cluster } => write!(
+                f, "out_of_range path={:?} addr={} last={} cluster={}",
+                path, addr, Last(*last), cluster
+            ),
+            Problem::FreeInChain { path, addr, last, cluster } => write!(
+                f, "free_in_chain path={:?} addr={} last={} cluster={}",
+                path, addr, Last(*last), cluster
+            ),
+            Problem::LostCluster { cluster } => write!(
+                f, "lost_cluster cluster={}",
+                cluster
+            ),
+            Problem::SizeMismatch { path, addr, size, clusters } => write!(
+                f, "size_mismatch path={:?} addr={} size={} clusters={}",
+                path, addr, size, clusters
+            ),
+            Problem::BadParent { path, addr, parent, expected } => write!(
+                f, "bad_parent path={:?} addr={} parent={} expected={}",
+                path, addr, parent, expected
+            ),
+            Problem::InvalidName { path, addr } => write!(
+                f, "invalid_name path={:?} addr={}",
+                path, addr
+            ),
+            Problem::InvalidType { path, addr, value } => write!(
+                f, "invalid_type path={:?} addr={} value={}",
+                path, addr, value
+            ),
+            Problem::BadRefCount { cluster, refs, expected } => write!(
+                f, "bad_refcount cluster={} refs={} expected={}",
+                cluster, refs, expected
+            ),
+            Problem::BadChecksum { addr } => write!(
+                f, "bad_checksum addr={}",
+                addr
+            ),
+            Problem::BadFreeCount { stored, counted } => write!(
+                f, "bad_free_count stored={} counted={}",
+                stored, counted
+            ),
+            Problem::BadInodeCount { stored, counted } => write!(
+                f, "bad_inode_count stored={} counted={}",
+                stored, counted
+            ),
+            Problem::BadExtents { path, addr } => write!(
+                f, "bad_extents path={:?} addr={}",
+                path, addr
+            ),
+            Problem::PendingJournal { blocks } => write!(
+                f, "pending_journal blocks={}",
+                blocks
+            ),
+        }
+    }
+}
+
+// `none` when there is no trusted cluster
+struct Last(Option<usize>);
+
+impl fmt::Display for Last {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(cluster) => write!(f, "{}", cluster),
+            None => write!(f, "none"),
+        }
+    }
+}
+
+/// a change `repair` makes, or would make on a dry run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// zero the entry at `addr`, its clusters are lost from then on
+    ClearEntry { path: String, addr: usize },
+    /// give the entry at `addr` a valid name
+    Rename { path: String, addr: usize, name: String },
+    /// point the entry at `addr` to the directory holding it
+    SetParent { path: String, addr: usize, parent: usize },
+    /// end the chain at `cluster`, the rest of it is lost from then on
+    EndChain { path: String, cluster: usize },
+    /// rewrite the size of the entry at `addr`
+    SetSize { path: String, addr: usize, size: usize },
+    /// free a lost cluster
+    FreeCluster { cluster: usize },
+    /// keep the lost chain of `clusters` clusters from `cluster`
+    /// as a file named `name` in `/lost+found`
+    Salvage { cluster: usize, clusters: usize, name: String },
+    /// rewrite the refcount table entry of `cluster`
+    SetRefCount { cluster: usize, refs: usize },
+    /// checksum the sector at `addr` again, as it is after the other fixes
+    Restamp { addr: usize },
+    /// rewrite the free cluster count of the superblock
+    SetFreeCount { count: usize },
+    /// rewrite the entry count of the superblock
+    SetInodeCount { count: usize },
+    /// forget the runs of the entry at `addr`, the data chain is walked again,
+    /// the chain of the runs is lost from then on
+    DropExtents { path: String, addr: usize },
+    /// write the committed transaction of the journal home
+    ReplayJournal,
+}
+
+/// one line per action: its kind, then `key=value` pairs
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::ClearEntry { path, addr } => write!(
+                f, "clear_entry path={:?} addr={}",
+                path, addr
+            ),
+            Action::Rename { path, addr, name } => write!(
+                f, "rename path={:?} addr={} name={:?}",
+                path, addr, name
+            ),
+            Action::SetParent { path, addr, parent } => write!(
+                f, "set_parent path={:?} addr={} parent={}",
+                path, addr, parent
+            ),
+            Action::EndChain { path, cluster } => write!(
+                f, "end_chain path={:?} cluster={}",
+                path, cluster
+            ),
+            Action::SetSize { path, addr, size } => write!(
+                f, "set_size path={:?} addr={} size={}",
+                path, addr, size
+            ),
+            Action::FreeCluster { cluster } => write!(
+                f, "free_cluster cluster={}",
+                cluster
+            ),
+            Action::Salvage { cluster, clusters, name } => write!(
+                f, "salvage cluster={} clusters={} name={:?}",
+                cluster, clusters, name
+            ),
+            Action::SetRefCount { cluster, refs } => write!(
+                f, "set_refcount cluster={} refs={}",
+                cluster, refs
+            ),
+            Action::Restamp { addr } => write!(
+                f, "restamp addr={}",
+                addr
+            ),
+            Action::SetFreeCount { count } => write!(
+                f, "set_free_count count={}",
+                count
+            ),
+            Action::SetInodeCount { count } => write!(
+                f, "set_inode_count count={}",
+                count
+            ),
+            Action::DropExtents { path, addr } => write!(
+                f, "drop_extents path={:?} addr={}",
+                path, addr
+            ),
+            Action::ReplayJournal => write!(f, "replay_journal"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub dirs: usize,
+    pub files: usize,
+    /// clusters reached from the root directory and the volume structures
+    pub clusters: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+struct Checker {
+    device: Arc<dyn BlockDevice>,
+    sblock: SuperBlock,
+    // every cluster reached so far, and the path of its first owner
+    owners: BTreeMap<usize, String>,
+    // chain of the refcount table, and how many more times
+    // each shared cluster was reached after its first owner
+    table: Vec<usize>,
+    shares: BTreeMap<usize, usize>,
+    report: Report,
+}
+
+impl Checker {
+    fn in_range(&self, cluster: usize) -> bool {
+        cluster >= self.sblock.root_cluster
+            && cluster < self.sblock.fat_entries()
+            && !self.sblock.is_reserved(cluster)
+    }
+
+    fn verify(&mut self, addr: usize) {
+        if self.sblock.has_feature(FEATURE_CHECKSUMS)
+            && !verify_sector(get_block_cache(addr, &self.device).lock().data()) {
+            self.report.problems.push(Problem::BadChecksum { addr });
+        }
+    }
+
+    fn check_fat(&mut self) {
+        for addr in (self.sblock.fat()..self.sblock.meta_end()).step_by(self.sblock.byte_per_sector) {
+            self.verify(addr);
+        }
+    }
+
+    fn refcount(&self, cluster: usize) -> usize {
+        refcount_of(&self.table, cluster, &self.sblock, &self.device)
+    }
+
+    // a cluster may be reached once more for each reference it counts
+    fn share(&mut self, cluster: usize) -> bool {
+        let refs = self.refcount(cluster);
+        let shares = self.shares.entry(cluster).or_insert(0);
+        if *shares < refs {
+            *shares += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // follow the chain from `cluster`, stop where it goes wrong,
+    // the clusters returned are the part that can be trusted,
+    // and whether the chain reached its end properly
+    fn follow(&mut self, cluster: usize, path: &str, addr: usize) -> (Vec<usize>, bool) {
+        let mut clusters = Vec::new();
+        let mut cluster = cluster;
+        let complete = loop {
+            if !self.in_range(cluster) {
+                self.report.problems.push(Problem::OutOfRange {
+                    path: path.into(), addr, last: clusters.last().copied(), cluster
+                });
+                break false;
+            }
+            if clusters.contains(&cluster) {
+                self.report.problems.push(Problem::ChainLoop {
+                    path: path.into(), addr, last: clusters.last().copied(), cluster
+                });
+                break false;
+            }
+            if let Some(owner) = self.owners.get(&cluster).cloned() {
+                if self.share(cluster) {
+                    clusters.push(cluster);
+                    match read_fat(cluster, &self.sblock, &self.device) {
+                        0x0FFFFFFF => break true,
+                        next => { cluster = next; continue; }
+                    }
+                }
+                self.report.problems.push(Problem::CrossLinked {
+                    path: path.into(),
+                    addr,
+                    last: clusters.last().copied(),
+                    cluster,
+                    owner,
+                });
+                break false;
+            }
+
+            let next = read_fat(cluster, &self.sblock, &self.device);
+            if next == 0 {
+                self.report.problems.push(Problem::FreeInChain {
+                    path: path.into(), addr, last: clusters.last().copied(), cluster
+                });
+                break false;
+            }
+            self.owners.insert(cluster, path.into());
+            self.report.clusters += 1;
+            clusters.push(cluster);
+            if next == 0x0FFFFFFF {
+                break true;
+            }
+            cluster = next;
+        };
+        (clusters, complete)
+    }
+
+    fn check_dir(&mut self, clusters: &[usize], path: &str, stack: &mut Vec<(Vec<usize>, String)>) {
+        let parent = clusters[0];
+        for &c in clusters.iter() {
+            for o in 0..self.sblock.sector_per_cluster {
+                let addr = self.sblock.offset(c) + o * self.sblock.byte_per_sector;
+                self.verify(addr);
+                let cache = get_block_cache(addr, &self.device);
+                // the type byte is checked before the sector is read as an INode
+                let value = cache.lock().read(0, |value: &u8| *value);
+                if value == INodeType::NoneEntry as u8 {
+                    continue;
+                }
+                if value != INodeType::DirEntry as u8 && value != INodeType::FileEntry as u8 {
+                    self.report.problems.push(Problem::InvalidType {
+                        path: format!("{}/#{}", path, addr), addr, value
+                    });
+                    continue;
+                }
+                let inode = cache.lock().read(0, |inode: &INode| *inode);
+                drop(cache);
+                self.check_entry(&inode, addr, parent, path, stack);
+            }
+        }
+    }
+
+    fn check_entry(
+        &mut self,
+        inode: &INode,
+        addr: usize,
+        parent: usize,
+        dir_path: &str,
+        stack: &mut Vec<(Vec<usize>, String)>,
+    ) {
+        let len = inode.i_name_len as usize;
+        let path = match core::str::from_utf8(&inode.i_name[0..len.min(NAME_LEN)]) {
+            Ok(name) if len <= NAME_LEN && !name.is_empty() => format!("{}/{}", dir_path, name),
+            _ => {
+                let path = format!("{}/#{}", dir_path, addr);
+                self.report.problems.push(Problem::InvalidName { path: path.clone(), addr });
+                path
+            }
+        };
+
+        if inode.i_pre_cluster as usize != parent {
+            self.report.problems.push(Problem::BadParent {
+                path: path.clone(),
+                addr,
+                parent: inode.i_pre_cluster as usize,
+                expected: parent,
+            });
+        }
+
+        let (clusters, complete) = self.follow(inode.cluster(), &path, addr);
+        if inode.flags() & FLAG_CHECKSUM != 0 && inode.csum_cluster() != 0 {
+            self.follow(inode.csum_cluster(), &format!("{}:csum", path), 0);
+        }
+        if inode.flags() & FLAG_COMPRESSED != 0 && inode.map_cluster() != 0 {
+            self.follow(inode.map_cluster(), &format!("{}:map", path), 0);
+        }
+        if inode.xattr_cluster() != 0 {
+            self.follow(inode.xattr_cluster(), &format!("{}:xattr", path), 0);
+        }
+        if inode.flags() & FLAG_EXTENTS != 0 {
+            let chain = match inode.extent_cluster() {
+                0 => Vec::new(),
+                cluster => self.follow(cluster, &format!("{}:extents", path), 0).0,
+            };
+            let runs = read_extents(inode, &chain, &self.sblock, &self.device);
+            if complete && !Extents::from_runs(&runs).iter().eq(clusters.iter().copied()) {
+                self.report.problems.push(Problem::BadExtents { path: path.clone(), addr });
+            }
+        }
+        if inode.is_dir() {
+            self.report.dirs += 1;
+            if !clusters.is_empty() {
+                stack.push((clusters, path));
+            }
+        } else {
+            self.report.files += 1;
+            let size = inode.i_size_lo as usize;
+            let need = div_ceil(size, self.sblock.byte_per_cluster()).max(1);
+            // the chunks of a compressed file take fewer clusters than its size
+            let compressed = inode.flags() & FLAG_COMPRESSED != 0;
+            if complete && !compressed && clusters.len() != need {
+                self.report.problems.push(Problem::SizeMismatch {
+                    path, addr, size, clusters: clusters.len()
+                });
+            }
+        }
+    }
+
+    fn check_lost(&mut self) {
+        let mut free = 0;
+        for cluster in self.sblock.root_cluster..self.sblock.fat_entries() {
+            if self.sblock.is_reserved(cluster) {
+                continue;
+            }
+            let next = read_fat(cluster, &self.sblock, &self.device);
+            if next == 0 {
+                free += 1;
+            } else if !self.owners.contains_key(&cluster) {
+                self.report.problems.push(Problem::LostCluster { cluster });
+            }
+            if self.table.is_empty() {
+                continue;
+            }
+            let refs = self.refcount(cluster);
+            let expected = self.shares.get(&cluster).copied().unwrap_or(0);
+            if refs != expected {
+                self.report.problems.push(Problem::BadRefCount { cluster, refs, expected });
+            }
+        }
+        if self.sblock.free_clusters != free {
+            self.report.problems.push(Problem::BadFreeCount {
+                stored: self.sblock.free_clusters, counted: free
+            });
+        }
+    }
+
+    fn check_counts(&mut self) {
+        let entries = self.report.dirs + self.report.files;
+        if self.sblock.used_inodes != entries {
+            self.report.problems.push(Problem::BadInodeCount {
+                stored: self.sblock.used_inodes, counted: entries
+            });
+        }
+    }
+}
+
+// whether the refcount table reaches the entry of `cluster`
+fn covers(table: &[usize], cluster: usize, sblock: &SuperBlock) -> bool {
+    let size = table.len() * sblock.byte_per_cluster();
+    cluster * REFCOUNT_SIZE < size
+}
+
+// extra references of `cluster`, none where the table is missing or cut short
+fn refcount_of(
+    table: &[usize],
+    cluster: usize,
+    sblock: &SuperBlock,
+    device: &Arc<dyn BlockDevice>,
+) -> usize {
+    match covers(table, cluster, sblock) {
+        true => read_refcount(table, cluster, sblock, device),
+        false => 0,
+    }
+}
+
+// the part of the chain from `cluster` that can be trusted
+fn trusted_chain(cluster: usize, sblock: &SuperBlock, device: &Arc<dyn BlockDevice>) -> Vec<usize> {
+    let mut chain = Vec::new();
+    let mut cluster = cluster;
+    while cluster >= sblock.root_cluster
+        && cluster < sblock.fat_entries()
+        && !chain.contains(&cluster) {
+        chain.push(cluster);
+        cluster = read_fat(cluster, sblock, device);
+    }
+    chain
+}
+
+// the part of the refcount table chain that can be trusted
+fn refcount_table(sblock: &SuperBlock, device: &Arc<dyn BlockDevice>) -> Vec<usize> {
+    trusted_chain(sblock.refcount_cluster, sblock, device)
+}
+
+/// walk the whole volume on `device` and report where the FAT
+/// and the directory tree disagree, nothing is written,
+/// a volume with a committed transaction left in its journal is not walked
+pub fn check(device: &Arc<dyn BlockDevice>) -> Report {
+    set_sector_size(probe_sector_size(device));
+    let sblock = get_sblock(device);
+    let mut checker = Checker {
+        device: Arc::clone(device),
+        sblock,
+        owners: BTreeMap::new(),
+        table: Vec::new(),
+        shares: BTreeMap::new(),
+        report: Report::default(),
+    };
+
+    // the tree is only consistent once the transaction is written home
+    let blocks = pending(&sblock, device);
+    if blocks > 0 {
+        checker.report.problems.push(Problem::PendingJournal { blocks });
+        return checker.report;
+    }
+    checker.check_fat();
+    // these are owned by the volume rather than by an entry
+    if sblock.journal_sectors > 0 {
+        checker.follow(sblock.journal_cluster, JOURNAL_PATH, 0);
+    }
+    if sblock.refcount_cluster != 0 {
+        checker.table = checker.follow(sblock.refcount_cluster, REFCOUNT_PATH, 0).0;
+    }
+    if sblock.quota_cluster != 0 {
+        checker.follow(sblock.quota_cluster, QUOTA_PATH, 0);
+    }
+    let (root, _) = checker.follow(sblock.root_cluster, "/", 0);
+    let mut stack = Vec::new();
+    if !root.is_empty() {
+        stack.push((root, String::new()));
+    }
+    if sblock.snapshot_cluster != 0 {
+        let (snapshots, _) = checker.follow(sblock.snapshot_cluster, SNAPSHOT_PATH, 0);
+        if !snapshots.is_empty() {
+            stack.push((snapshots, SNAPSHOT_PATH.into()));
+        }
+    }
+    while let Some((clusters, path)) = stack.pop() {
+        checker.check_dir(&clusters, &path, &mut stack);
+    }
+    checker.check_lost();
+    checker.check_counts();
+    checker.report
+}
+
+struct Repairer {
+    device: Arc<dyn BlockDevice>,
+    sblock: SuperBlock,
+    salvage: bool,
+    // first cluster of /lost+found, once it is found or made
+    lost_found: Option<usize>,
+    // chain of the refcount table
+    table: Vec<usize>,
+}
+
+impl Repairer {
+    fn inode(&self, addr: usize) -> INode {
+        get_block_cache(addr, &self.device).lock().read(0, |inode: &INode| *inode)
+    }
+
+    fn modify(&self, addr: usize, f: impl FnOnce(&mut INode)) {
+        get_meta_cache(addr, &self.device).lock().modify(0, f)
+    }
+
+    // the cluster `nth` steps down a chain that is known to be long enough
+    fn nth(&self, cluster: usize, nth: usize) -> usize {
+        let mut cluster = cluster;
+        for _ in 0..nth {
+            cluster = read_fat(cluster, &self.sblock, &self.device);
+        }
+        cluster
+    }
+
+    // how many clusters from `cluster` down to `last`
+    fn count_to(&self, cluster: usize, last: usize) -> usize {
+        let mut cluster = cluster;
+        let mut count = 1;
+        while cluster != last {
+            cluster = read_fat(cluster, &self.sblock, &self.device);
+            count += 1;
+        }
+        count
+    }
+
+    fn plan(&self, report: &Report) -> Vec<Action> {
+        let bpc = self.sblock.byte_per_cluster();
+        let mut actions = Vec::new();
+        let mut lost = Vec::new();
+
+        for problem in report.problems.iter() {
+            match problem {
+                Problem::CrossLinked { path, addr, last, .. }
+                | Problem::ChainLoop { path, addr, last, .. }
+                | Problem::OutOfRange { path, addr, last, .. }
+                | Problem::FreeInChain { path, addr, last, .. } => match (last, *addr) {
+                    (Some(last), 0) => actions.push(Action::EndChain {
+                        path: path.clone(), cluster: *last
+                    }),
+                    (Some(last), addr) => {
+                        actions.push(Action::EndChain { path: path.clone(), cluster: *last });
+                        let inode = self.inode(addr);
+                        let capacity = self.count_to(inode.cluster(), *last) * bpc;
+                        if inode.is_file() && inode.size() > capacity {
+                            actions.push(Action::SetSize {
+                                path: path.clone(), addr, size: capacity
+                            });
+                        }
+                    }
+                    // only the root can be replaced by an empty chain
+                    (None, 0) if path != "/" => {},
+                    (None, 0) => actions.push(Action::EndChain {
+                        path: path.clone(), cluster: self.sblock.root_cluster
+                    }),
+                    (None, addr) => actions.push(Action::ClearEntry {
+                        path: path.clone(), addr
+                    }),
+                },
+                Problem::SizeMismatch { path, addr, size, clusters } => {
+                    let need = div_ceil(*size, bpc).max(1);
+                    if *clusters > need {
+                        let inode = self.inode(*addr);
+                        actions.push(Action::EndChain {
+                            path: path.clone(), cluster: self.nth(inode.cluster(), need - 1)
+                        });
+                    } else {
+                        actions.push(Action::SetSize {
+                            path: path.clone(), addr: *addr, size: clusters * bpc
+                        });
+                    }
+                }
+                Problem::BadParent { path, addr, expected, .. } => actions.push(Action::SetParent {
+                    path: path.clone(), addr: *addr, parent: *expected
+                }),
+                Problem::InvalidName { path, addr } => actions.push(Action::Rename {
+                    path: path.clone(), addr: *addr, name: format!("fsck{}", addr / self.sblock.byte_per_sector)
+                }),
+                Problem::InvalidType { path, addr, .. } => actions.push(Action::ClearEntry {
+                    path: path.clone(), addr: *addr
+                }),
+                Problem::LostCluster { cluster } => lost.push(*cluster),
+                Problem::BadRefCount { cluster, expected, .. } => actions.push(Action::SetRefCount {
+                    cluster: *cluster, refs: *expected
+                }),
+                Problem::BadChecksum { addr } => actions.push(Action::Restamp { addr: *addr }),
+                Problem::BadFreeCount { counted, .. } => actions.push(Action::SetFreeCount {
+                    count: *counted
+                }),
+                Problem::BadInodeCount { counted, .. } => actions.push(Action::SetInodeCount {
+                    count: *counted
+                }),
+                Problem::BadExtents { path, addr } => actions.push(Action::DropExtents {
+                    path: path.clone(), addr: *addr
+                }),
+                Problem::PendingJournal { .. } => actions.push(Action::ReplayJournal),
+            }
+        }
+
+        // nothing else is done to an entry that goes away
+        let cleared: Vec<usize> = actions.iter().filter_map(|action| match action {
+            Action::ClearEntry { addr, .. } => Some(*addr),
+            _ => None,
+        }).collect();
+        actions.retain(|action| match action {
+            Action::Rename { addr, .. }
+            | Action::SetParent { addr, .. }
+            | Action::SetSize { addr, .. }
+            | Action::DropExtents { addr, .. } => !cleared.contains(addr),
+            _ => true,
+        });
+
+        self.plan_lost(&lost, &mut actions);
+        actions
+    }
+
+    // a lost chain starts from a lost cluster no other lost cluster points to,
+    // lost clusters only reachable from each other form loops and are freed
+    fn plan_lost(&self, lost: &[usize], actions: &mut Vec<Action>) {
+        let next: BTreeMap<usize, usize> = lost
+            .iter()
+            .map(|&c| (c, read_fat(c, &self.sblock, &self.device)))
+            .collect();
+        let mut done: Vec<usize> = Vec::new();
+
+        if self.salvage {
+            for &head in lost.iter() {
+                if next.values().any(|&n| n == head) {
+                    continue;
+                }
+                let mut cluster = head;
+                let mut clusters = 0;
+                while next.contains_key(&cluster) && !done.contains(&cluster) {
+                    done.push(cluster);
+                    clusters += 1;
+                    cluster = next[&cluster];
+                }
+                actions.push(Action::Salvage { cluster: head, clusters, name: format!("#{}", head) });
+            }
+        }
+
+        for &cluster in lost.iter() {
+            if !done.contains(&cluster) {
+                actions.push(Action::FreeCluster { cluster });
+            }
+        }
+    }
+
+    fn apply(&mut self, actions: &[Action]) {
+        for action in actions.iter() {
+            match action {
+                Action::ClearEntry { addr, .. } => {
+                    get_meta_cache(*addr, &self.device).lock().data_mut().fill(0);
+                }
+                Action::Rename { addr, name, .. } => self.modify(*addr, |inode| inode.set_name(name)),
+                Action::SetParent { addr, parent, .. } => {
+                    self.modify(*addr, |inode| inode.i_pre_cluster = *parent as u32)
+                }
+                Action::EndChain { cluster, .. } => {
+                    write_fat(*cluster, 0x0FFFFFFF, &self.sblock, &self.device)
+                }
+                Action::SetSize { addr, size, .. } => {
+                    self.modify(*addr, |inode| inode.i_size_lo = *size as u32)
+                }
+                Action::FreeCluster { cluster } => write_fat(*cluster, 0, &self.sblock, &self.device),
+                Action::Salvage { cluster, clusters, name } => self.salvage(*cluster, *clusters, name),
+                Action::Restamp { addr } => {
+                    get_meta_cache(*addr, &self.device).lock().modify(0, |_: &mut u8| {})
+                }
+                Action::SetRefCount { cluster, refs } => {
+                    if covers(&self.table, *cluster, &self.sblock) {
+                        write_refcount(&self.table, *cluster, *refs, &self.sblock, &self.device)
+                    }
+                }
+                Action::SetFreeCount { count } => {
+                    modify_sblock(&self.device, |sblock| sblock.free_clusters = *count)
+                }
+                Action::SetInodeCount { count } => {
+                    modify_sblock(&self.device, |sblock| sblock.used_inodes = *count)
+                }
+                Action::DropExtents { addr, .. } => self.modify(*addr, |inode| {
+                    inode.i_flags &= !FLAG_EXTENTS;
+                    inode.i_extent_count = 0;
+                    inode.i_extent_cluster = 0;
+                }),
+                Action::ReplayJournal => {
+                    replay(&self.sblock, &self.device);
+                }
+            }
+        }
+    }
+
+    // the volume is not mounted while it is repaired,
+    // so lost+found is made from the FAT and the inodes directly
+    fn salvage(&mut self, cluster: usize, clusters: usize, name: &str) {
+        let last = self.nth(cluster, clusters - 1);
+        write_fat(last, 0x0FFFFFFF, &self.sblock, &self.device);
+
+        if self.lost_found.is_none() {
+            self.lost_found = self.find(self.sblock.root_cluster, LOST_FOUND)
+                .or_else(|| self.make_dir(self.sblock.root_cluster, LOST_FOUND));
+        }
+        let size = clusters * self.sblock.byte_per_cluster();
+        let linked = match self.lost_found {
+            Some(dir) => match self.free_slot(dir) {
+                Some(addr) => {
+                    self.modify(addr, |inode| {
+                        inode.i_type = INodeType::FileEntry;
+                        inode.set_name(name);
+                        inode.i_size_lo = size as u32;
+                        inode.i_cluster = cluster as u32;
+                        inode.i_pre_cluster = dir as u32;
+                    });
+                    true
+                }
+                None => false,
+            },
+            None => false,
+        };
+        // with nowhere to keep it, the chain is freed
+        if !linked {
+            let mut cluster = cluster;
+            for _ in 0..clusters {
+                let next = read_fat(cluster, &self.sblock, &self.device);
+                write_fat(cluster, 0, &self.sblock, &self.device);
+                cluster = next;
+            }
+        }
+    }
+
+    // the inode sectors of the directory from `cluster`
+    fn dir_sectors(&self, cluster: usize) -> Vec<usize> {
+        let bps = self.sblock.byte_per_sector;
+        trusted_chain(cluster, &self.sblock, &self.device)
+            .iter()
+            .flat_map(|&c| {
+                let addr = self.sblock.offset(c);
+                (0..self.sblock.sector_per_cluster).map(move |o| addr + o * bps)
+            })
+            .collect()
+    }
+
+    // the first cluster of the directory `name` in the directory from `cluster`
+    fn find(&self, cluster: usize, name: &str) -> Option<usize> {
+        self.dir_sectors(cluster)
+            .iter()
+            .map(|&addr| self.inode(addr))
+            .find(|inode| inode.is_dir() && inode.name() == name)
+            .map(|inode| inode.cluster())
+    }
+
+    fn make_dir(&self, cluster: usize, name: &str) -> Option<usize> {
+        let addr = self.free_slot(cluster)?;
+        let dir = self.alloc_dir_cluster(None)?;
+        self.modify(addr, |inode| {
+            inode.i_type = INodeType::DirEntry;
+            inode.set_name(name);
+            inode.i_cluster = dir as u32;
+            inode.i_pre_cluster = cluster as u32;
+        });
+        Some(dir)
+    }
+
+    // an empty inode sector of the directory from `cluster`,
+    // which grows by a cluster when every sector is taken
+    fn free_slot(&self, cluster: usize) -> Option<usize> {
+        let sectors = self.dir_sectors(cluster);
+        match sectors.iter().find(|&&addr| self.inode(addr).is_none()) {
+            Some(&addr) => Some(addr),
+            None => {
+                let last = *trusted_chain(cluster, &self.sblock, &self.device).last()?;
+                self.alloc_dir_cluster(Some(last)).map(|c| self.sblock.offset(c))
+            }
+        }
+    }
+
+    // the first free cluster, emptied and linked after `last`,
+    // the free count is set again by the next pass
+    fn alloc_dir_cluster(&self, last: Option<usize>) -> Option<usize> {
+        let cluster = (self.sblock.root_cluster..self.sblock.fat_entries()).find(|&c| {
+            !self.sblock.is_reserved(c) && read_fat(c, &self.sblock, &self.device) == 0
+        })?;
+        write_fat(cluster, 0x0FFFFFFF, &self.sblock, &self.device);
+        if let Some(last) = last {
+            write_fat(last, cluster, &self.sblock, &self.device);
+        }
+        let len = self.sblock.byte_per_cluster();
+        write_blocks(self.sblock.offset(cluster), &self.sblock.empty_sectors(len), &self.device);
+        Some(cluster)
+    }
+}
+
+/// check the volume on `device` and fix what was found,
+/// lost chains go to `/lost+found` with `salvage`, or are freed without it,
+/// a dry run writes nothing and plans from the first check only,
+/// the report is the one of the first check, the actions made follow it,
+/// then those still planned when the passes run out, which were not made
+pub fn repair(
+    device: &Arc<dyn BlockDevice>,
+    salvage: bool,
+    dry_run: bool,
+) -> (Report, Vec<Action>, Vec<Action>) {
+    set_sector_size(probe_sector_size(device));
+    let sblock = get_sblock(device);
+    // what is written below is checksummed as the volume expects
+    if sblock.has_feature(FEATURE_CHECKSUMS) {
+        enable_checksums(sblock.meta_end());
+    }
+    // a committed transaction is part of the volume
+    if !dry_run {
+        replay(&sblock, device);
+    }
+    let report = check(device);
+    let mut repairer = Repairer {
+        device: Arc::clone(device),
+        sblock: get_sblock(device),
+        salvage,
+        lost_found: None,
+        table: refcount_table(&get_sblock(device), device),
+    };
+
+    let mut pending = repairer.plan(&report);
+    if dry_run {
+        return (report, pending, Vec::new());
+    }
+
+    let mut actions = Vec::new();
+    for _ in 0..REPAIR_PASSES {
+        if pending.is_empty() {
+            break;
+        }
+        repairer.apply(&pending);
+        actions.append(&mut pending);
+        pending = repairer.plan(&check(device));
+    }
+    sync_all();
+    (report, actions, pending)
+}
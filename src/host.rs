@@ -0,0 +1,346 @@
+use std::fs;
+use std::fs::File;
+use std::fs::Metadata;
+use std::fs::OpenOptions;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::io::Result;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use spin::Mutex;
+use super::device::BlockDevice;
+use super::dir::DirEntry;
+use super::dir::DirError;
+use super::file::FileError;
+use super::file::WriteType;
+use super::inode::INode;
+
+/// a host image file or block file used as a `BlockDevice`
+pub struct FileDevice {
+    file: Mutex<File>,
+    size: usize,
+}
+
+impl FileDevice {
+    /// open an existing image, its size is taken from the file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with(path, false, None)
+    }
+
+    /// create an image of `size` bytes, an existing file is truncated
+    pub fn create<P: AsRef<Path>>(path: P, size: usize) -> Result<Self> {
+        Self::open_with(path, true, Some(size))
+    }
+
+    /// `create` makes the file when it is missing,
+    /// `size` resizes it, otherwise its current length is used
+    pub fn open_with<P: AsRef<Path>>(
+        path: P,
+        create: bool,
+        size: Option<usize>,
+    ) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(create && size.is_some())
+            .open(path)?;
+        let size = match size {
+            Some(size) => {
+                file.set_len(size as u64)?;
+                size
+            }
+            // block files report 0 in metadata, seeking to the end works for both
+            None => file.seek(SeekFrom::End(0))? as usize,
+        };
+        Ok(Self {
+            file: Mutex::new(file),
+            size,
+        })
+    }
+
+}
+
+impl BlockDevice for FileDevice {
+    fn read(&self, addr: usize, buf: &mut [u8]) {
+        let mut file = self.file.lock();
+        file.seek(SeekFrom::Start(addr as u64))
+            .and_then(|_| file.read_exact(buf))
+            .unwrap_or_else(|err| panic!("read {:#x} failed: {}", addr, err));
+    }
+
+    fn write(&self, addr: usize, buf: &[u8]) {
+        let mut file = self.file.lock();
+        file.seek(SeekFrom::Start(addr as u64))
+            .and_then(|_| file.write_all(buf))
+            .unwrap_or_else(|err| panic!("write {:#x} failed: {}", addr, err));
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    // one request covers the whole run
+    fn read_blocks(&self, addr: usize, buf: &mut [u8]) {
+        self.read(addr, buf)
+    }
+
+    fn write_blocks(&self, addr: usize, buf: &[u8]) {
+        self.write(addr, buf)
+    }
+}
+
+/// a `Vec` backed `BlockDevice`
+pub struct RamDisk {
+    data: Mutex<Vec<u8>>,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// a zeroed disk of `size` bytes
+    pub fn new(size: usize) -> Self {
+        Self::from_vec(vec![0; size])
+    }
+
+    pub fn from_vec(data: Vec<u8>) -> Self {
+        Self {
+            data: Mutex::new(data),
+            read_only: false,
+        }
+    }
+
+    /// writes to a read-only disk panic
+    pub fn read_only(data: Vec<u8>) -> Self {
+        Self {
+            data: Mutex::new(data),
+            read_only: true,
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// copy of the whole disk
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.lock().clone()
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn read(&self, addr: usize, buf: &mut [u8]) {
+        let data = self.data.lock();
+        buf.copy_from_slice(&data[addr..addr + buf.len()]);
+    }
+
+    fn write(&self, addr: usize, buf: &[u8]) {
+        assert!(!self.read_only, "write {:#x} to read-only RamDisk", addr);
+        let mut data = self.data.lock();
+        data[addr..addr + buf.len()].copy_from_slice(buf);
+    }
+
+    fn size(&self) -> usize {
+        self.data.lock().len()
+    }
+
+    fn read_blocks(&self, addr: usize, buf: &mut [u8]) {
+        self.read(addr, buf)
+    }
+
+    fn write_blocks(&self, addr: usize, buf: &[u8]) {
+        self.write(addr, buf)
+    }
+}
+
+/// a host entry `populate` could not put into the volume
+#[derive(Debug)]
+pub struct Skipped {
+    pub path: PathBuf,
+    pub reason: DirError,
+}
+
+/// recreate the tree under the host directory `path` inside `dir`,
+/// existing directories are merged and existing files overwritten,
+/// names FEFS cannot represent are left out and reported
+pub fn populate<P: AsRef<Path>>(dir: &mut DirEntry, path: P) -> Result<Vec<Skipped>> {
+    let mut skipped = Vec::new();
+    populate_inner(dir, path.as_ref(), &mut skipped)?;
+    Ok(skipped)
+}
+
+fn populate_inner(dir: &mut DirEntry, path: &Path, skipped: &mut Vec<Skipped>) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let host = entry?.path();
+        let meta = fs::metadata(&host)?;
+        let name = match host.file_name().and_then(|name| name.to_str()) {
+            Some(name) => String::from(name),
+            None => {
+                skipped.push(Skipped { path: host, reason: DirError::IllegalChar });
+                continue;
+            }
+        };
+
+        let ret = if meta.is_dir() {
+            let sub = match dir.cd(&name) {
+                Ok(sub) => Ok(sub),
+                Err(_) => dir.mkdir(&name),
+            };
+            match sub {
+                Ok(mut sub) => {
+                    populate_inner(&mut sub, &host, skipped)?;
+                    Ok(())
+                }
+                Err(err) => Err(err),
+            }
+        } else if meta.is_file() {
+            let file = match dir.open_file(&name) {
+                Ok(file) => Ok(file),
+                Err(_) => dir.create_file(&name),
+            };
+            match file {
+                Ok(mut file) => match file.write(&fs::read(&host)?, WriteType::OverWritten) {
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(skip_reason(err)?),
+                },
+                Err(err) => Err(err),
+            }
+        } else {
+            continue;
+        };
+
+        let ret = ret.and_then(|_| {
+            dir.set_mode(&name, host_mode(&meta))?;
+            let mtime = unix_time(meta.modified());
+            let atime = unix_time(meta.accessed());
+            dir.set_times(&name, atime, mtime)
+        });
+        if let Err(reason) = ret {
+            skipped.push(Skipped { path: host, reason });
+        }
+    }
+    Ok(())
+}
+
+// a file that cannot be written is skipped like an entry that cannot be made,
+// errors a write cannot return stop the copy
+fn skip_reason(err: FileError) -> Result<DirError> {
+    match err {
+        FileError::ReadOnly => Ok(DirError::ReadOnly),
+        FileError::Corrupted => Ok(DirError::Corrupted),
+        FileError::NoKey => Ok(DirError::NoKey),
+        FileError::QuotaExceeded => Ok(DirError::QuotaExceeded),
+        err => Err(io_error(err)),
+    }
+}
+
+// a volume error as seen by the host, named after the variant
+fn io_error<E: Debug>(err: E) -> Error {
+    Error::new(ErrorKind::Other, format!("{:?}", err))
+}
+
+/// copy every directory and file under `dir` into the host directory `path`,
+/// which is created when missing, modes and times are restored when they were set,
+/// an entry that cannot be read stops the copy with its error
+pub fn extract<P: AsRef<Path>>(dir: &DirEntry, path: P) -> Result<()> {
+    let path = path.as_ref();
+    fs::create_dir_all(path)?;
+    for inode in dir.ls().iter() {
+        let host = path.join(inode.name());
+        if inode.is_dir() {
+            extract(&dir.cd(&inode.name()).map_err(io_error)?, &host)?;
+        } else {
+            let file = dir.open_file(&inode.name()).map_err(io_error)?;
+            let mut buf = Vec::new();
+            file.read_to_vec(&mut buf).map_err(io_error)?;
+            fs::write(&host, &buf)?;
+        }
+        restore(&host, inode)?;
+    }
+    Ok(())
+}
+
+// an entry made without mode or times has them zeroed, keep the host defaults then
+fn restore(host: &Path, inode: &INode) -> Result<()> {
+    if inode.mtime() != 0 {
+        set_host_times(host, inode.atime(), inode.mtime())?;
+    }
+    if inode.mode() != 0 {
+        set_host_mode(host, inode.mode())?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_host_mode(host: &Path, mode: u16) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(host, fs::Permissions::from_mode(mode as u32))
+}
+
+#[cfg(not(unix))]
+fn set_host_mode(host: &Path, mode: u16) -> Result<()> {
+    let mut permissions = fs::metadata(host)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(host, permissions)
+}
+
+// std sets file times only from Rust 1.75 on, older toolchains go through utimes(2),
+// kept to Linux, where a timeval is two longs on every target
+#[cfg(target_os = "linux")]
+fn set_host_times(host: &Path, atime: u32, mtime: u32) -> Result<()> {
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_int, c_long};
+    use std::os::unix::ffi::OsStrExt;
+
+    #[repr(C)]
+    struct TimeVal {
+        sec: c_long,
+        usec: c_long,
+    }
+    extern "C" {
+        fn utimes(path: *const c_char, times: *const TimeVal) -> c_int;
+    }
+
+    let path = CString::new(host.as_os_str().as_bytes()).map_err(Error::from)?;
+    let times = [
+        TimeVal { sec: atime as c_long, usec: 0 },
+        TimeVal { sec: mtime as c_long, usec: 0 },
+    ];
+    match unsafe { utimes(path.as_ptr(), times.as_ptr()) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+// elsewhere the times are left as written
+#[cfg(not(target_os = "linux"))]
+fn set_host_times(_host: &Path, _atime: u32, _mtime: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn host_mode(meta: &Metadata) -> u16 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() as u16
+}
+
+#[cfg(not(unix))]
+fn host_mode(meta: &Metadata) -> u16 {
+    if meta.permissions().readonly() { 0o444 } else { 0o644 }
+}
+
+fn unix_time(time: Result<SystemTime>) -> u32 {
+    time.ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |time| time.as_secs() as u32)
+}
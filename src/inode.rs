@@ -1,5 +1,30 @@
 use core::fmt::Debug;
 use alloc::string::String;
+use super::extent::Extent;
+
+/// longest name an entry can hold, in bytes
+pub const NAME_LEN: usize = 16;
+
+/// bytes of extended attributes kept in the inode itself
+pub const XATTR_INLINE: usize = 32;
+
+/// runs of the data chain kept in the inode itself
+pub const EXTENT_INLINE: usize = 8;
+
+/// each data cluster has a CRC32C, kept in the chain of `i_csum_cluster`
+pub const FLAG_CHECKSUM: u32 = 1;
+
+/// the data is kept as LZ4 chunks, located through the chunk map of `i_map_cluster`
+pub const FLAG_COMPRESSED: u32 = 2;
+
+/// the data of a file is encrypted with the key `i_key_id`,
+/// entries created in a directory inherit its key,
+/// names, sizes and extended attributes are not encrypted
+pub const FLAG_ENCRYPTED: u32 = 4;
+
+/// the runs of the data chain are kept in `i_extents` and the chain of `i_extent_cluster`,
+/// so opening the file does not walk the FAT
+pub const FLAG_EXTENTS: u32 = 8;
 
 #[repr(u8)]
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
@@ -19,7 +44,7 @@ impl Default for INodeType {
 #[derive(Clone, Copy, Default)]
 pub struct INode {
     pub(crate) i_type: INodeType,
-    pub(crate) i_name: [u8; 16],
+    pub(crate) i_name: [u8; NAME_LEN],
     pub(crate) i_name_len: u8,
     pub(crate) i_mode: u16,
     pub(crate) i_uid: u16,
@@ -35,6 +60,15 @@ pub struct INode {
     pub(crate) i_cluster: u32,
     pub(crate) i_pre_cluster: u32,
     pub(crate) i_offset: u32,
+    pub(crate) i_csum_cluster: u32,
+    pub(crate) i_map_cluster: u32,
+    pub(crate) i_key_id: u32,
+    pub(crate) i_nonce: u64,
+    pub(crate) i_xattr_cluster: u32,
+    pub(crate) i_xattr: [u8; XATTR_INLINE],
+    pub(crate) i_extent_count: u32,
+    pub(crate) i_extent_cluster: u32,
+    pub(crate) i_extents: [Extent; EXTENT_INLINE],
 }
 
 impl INode {
@@ -66,6 +100,73 @@ impl INode {
         let len = self.i_name_len as usize;
         core::str::from_utf8(&self.i_name[0..len]).unwrap().into()
     }
+
+    pub fn size(&self) -> usize {
+        self.i_size_lo as usize
+    }
+
+    pub fn mode(&self) -> u16 {
+        self.i_mode
+    }
+
+    pub fn uid(&self) -> u16 {
+        self.i_uid
+    }
+
+    pub fn gid(&self) -> u16 {
+        self.i_gid
+    }
+
+    pub fn atime(&self) -> u32 {
+        self.i_atime
+    }
+
+    pub fn ctime(&self) -> u32 {
+        self.i_ctime
+    }
+
+    pub fn mtime(&self) -> u32 {
+        self.i_mtime
+    }
+
+    pub fn links_count(&self) -> u16 {
+        self.i_links_count
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.i_flags
+    }
+
+    /// first cluster of the data checksums, 0 without `FLAG_CHECKSUM`
+    pub fn csum_cluster(&self) -> usize {
+        self.i_csum_cluster as usize
+    }
+
+    /// id of the key in the keyring, 0 without `FLAG_ENCRYPTED`
+    pub fn key_id(&self) -> u32 {
+        self.i_key_id
+    }
+
+    /// first cluster of the attributes that do not fit in the inode, 0 when they all do
+    pub fn xattr_cluster(&self) -> usize {
+        self.i_xattr_cluster as usize
+    }
+
+    /// first cluster of the chunk map, 0 without `FLAG_COMPRESSED`
+    pub fn map_cluster(&self) -> usize {
+        self.i_map_cluster as usize
+    }
+
+    /// first cluster of the runs that do not fit in the inode, 0 when they all do
+    pub fn extent_cluster(&self) -> usize {
+        self.i_extent_cluster as usize
+    }
+
+    pub(crate) fn set_name(&mut self, name: &str) {
+        self.i_name = [0; NAME_LEN];
+        self.i_name[0..name.len()].copy_from_slice(name.as_bytes());
+        self.i_name_len = name.len() as u8;
+    }
 }
 
 
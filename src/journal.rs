@@ -0,0 +1,290 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use lazy_static::lazy_static;
+
+use super::BLOCK_SIZE;
+use super::div_ceil;
+use super::cache::{
+    BlockCache,
+    BLOCK_CACHE_MANAGER,
+    write_blocks,
+};
+use super::device::BlockDevice;
+use super::fat::spare_clusters;
+use super::sblock::SuperBlock;
+
+const JOURNAL_MAGIC: [u8; 4] = [0x6A, 0x72, 0x6E, 0x6C];
+// addresses the header has room for, it fits the smallest sector
+const JOURNAL_BLOCKS: usize = (BLOCK_SIZE - 16) / 8;
+/// sectors a journal can make use of, the header and the block copies
+pub const JOURNAL_SECTORS: usize = JOURNAL_BLOCKS + 1;
+
+/// the first sector of the journal area, the block copies follow it,
+/// `count` and `overflow` are nonzero only between a commit and its checkpoint
+#[repr(C)]
+struct Header {
+    magic: [u8; 4],
+    // first cluster of the copies that did not fit in the journal area, 0 for none
+    overflow: u32,
+    count: u64,
+    addrs: [u64; JOURNAL_BLOCKS],
+}
+
+impl Header {
+    fn new(addrs: &[usize], overflow: usize) -> Self {
+        let mut header = Self {
+            magic: JOURNAL_MAGIC,
+            overflow: overflow as u32,
+            count: addrs.len() as u64,
+            addrs: [0; JOURNAL_BLOCKS],
+        };
+        for (idx, &addr) in addrs.iter().enumerate() {
+            header.addrs[idx] = addr as u64;
+        }
+        header
+    }
+
+    fn from_bytes(buf: &[u8]) -> Self {
+        assert!(buf.len() >= core::mem::size_of::<Self>());
+        unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const Self) }
+    }
+
+    // the header padded to a whole sector of `size` bytes
+    fn to_sector(&self, size: usize) -> Vec<u8> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(self as *const _ as *const u8, core::mem::size_of::<Self>())
+        };
+        let mut sector = vec![0; size];
+        sector[0..bytes.len()].copy_from_slice(bytes);
+        sector
+    }
+
+    fn is_committed(&self) -> bool {
+        self.magic == JOURNAL_MAGIC && (self.count > 0 || self.overflow != 0)
+    }
+}
+
+/// a sector of the overflow, the address of the next one, 0 for none,
+/// then how many blocks follow as their home address and the address of their copy
+#[repr(C)]
+struct Overflow {
+    next: u64,
+    count: u64,
+}
+
+const OVERFLOW_SIZE: usize = core::mem::size_of::<Overflow>();
+
+pub struct Journal {
+    device: Arc<dyn BlockDevice>,
+    sblock: SuperBlock,
+    // address of the header sector
+    start: usize,
+    // bytes of the header and of each block copy
+    sector: usize,
+    // how many block copies the journal area holds, the rest go to the overflow
+    capacity: usize,
+    // nested transactions only commit with the outermost one
+    depth: usize,
+}
+
+impl Journal {
+    pub fn new(sblock: &SuperBlock, device: &Arc<dyn BlockDevice>) -> Option<Self> {
+        if sblock.journal_sectors < 2 {
+            return None;
+        }
+        Some(Self {
+            device: Arc::clone(device),
+            sblock: *sblock,
+            start: sblock.offset(sblock.journal_cluster),
+            sector: sblock.byte_per_sector,
+            capacity: (sblock.journal_sectors - 1).min(JOURNAL_BLOCKS),
+            depth: 0,
+        })
+    }
+
+    pub fn begin(&mut self) {
+        if self.depth == 0 {
+            BLOCK_CACHE_MANAGER.lock().pin(true);
+        }
+        self.depth += 1;
+    }
+
+    pub fn commit(&mut self) {
+        assert!(self.depth > 0, "commit without a transaction");
+        self.depth -= 1;
+        if self.depth == 0 {
+            self.flush();
+            BLOCK_CACHE_MANAGER.lock().pin(false);
+        }
+    }
+
+    // the whole transaction is committed at once, blocks past the capacity
+    // of the journal are copied to clusters free on the device as well,
+    // so neither the home blocks nor the copies are overwritten before the commit
+    fn flush(&self) {
+        let dirty = BLOCK_CACHE_MANAGER.lock().dirty();
+        if dirty.is_empty() {
+            return;
+        }
+        let sector = self.sector;
+        let inline = dirty.len().min(self.capacity);
+        let mut addrs = Vec::new();
+        let mut buf = vec![0; inline * sector];
+        for (idx, cache) in dirty[0..inline].iter().enumerate() {
+            let mut cache = cache.lock();
+            // the copy must carry the checksum written home
+            cache.stamp();
+            addrs.push(cache.addr());
+            buf[idx * sector..(idx + 1) * sector].copy_from_slice(cache.data());
+        }
+        self.device.write_blocks(self.start + sector, &buf);
+        let overflow = self.write_overflow(&dirty[inline..]);
+        // the transaction counts from here
+        self.device.write_blocks(self.start, &Header::new(&addrs, overflow).to_sector(sector));
+        for cache in dirty.iter() {
+            cache.lock().sync();
+        }
+        self.device.write_blocks(self.start, &Header::new(&[], 0).to_sector(sector));
+    }
+
+    // copy `caches` to spare clusters, each overflow sector followed by the copies
+    // it lists, return the first cluster, 0 when there is nothing to copy
+    fn write_overflow(&self, caches: &[Arc<Mutex<BlockCache>>]) -> usize {
+        if caches.is_empty() {
+            return 0;
+        }
+        let sector = self.sector;
+        let per_sector = (sector - OVERFLOW_SIZE) / 16;
+        let sectors = caches.len() + div_ceil(caches.len(), per_sector);
+        let spc = self.sblock.sector_per_cluster;
+        let clusters = spare_clusters(div_ceil(sectors, spc));
+        assert!(
+            clusters.len() * spc >= sectors,
+            "no room to commit a transaction of {} blocks",
+            self.capacity + caches.len()
+        );
+        let addrs: Vec<usize> = clusters
+            .iter()
+            .flat_map(|&c| (0..spc).map(move |o| (c, o)))
+            .map(|(c, o)| self.sblock.offset(c) + o * sector)
+            .take(sectors)
+            .collect();
+
+        for (idx, chunk) in caches.chunks(per_sector).enumerate() {
+            let at = idx * (per_sector + 1);
+            let next = match at + per_sector + 1 < sectors {
+                true => addrs[at + per_sector + 1],
+                false => 0,
+            };
+            let mut head = vec![0; sector];
+            head[0..8].copy_from_slice(&(next as u64).to_le_bytes());
+            head[8..16].copy_from_slice(&(chunk.len() as u64).to_le_bytes());
+            for (num, cache) in chunk.iter().enumerate() {
+                let mut cache = cache.lock();
+                cache.stamp();
+                let copy = addrs[at + 1 + num];
+                let entry = OVERFLOW_SIZE + num * 16;
+                head[entry..entry + 8].copy_from_slice(&(cache.addr() as u64).to_le_bytes());
+                head[entry + 8..entry + 16].copy_from_slice(&(copy as u64).to_le_bytes());
+                self.device.write_blocks(copy, cache.data());
+            }
+            self.device.write_blocks(addrs[at], &head);
+        }
+        clusters[0]
+    }
+}
+
+lazy_static! {
+    pub static ref JOURNAL: Mutex<Option<Journal>> = Mutex::new(None);
+}
+
+/// replay the transaction left by a crash, then start journaling,
+/// return how many blocks were replayed
+pub fn init_journal(sblock: &SuperBlock, device: &Arc<dyn BlockDevice>) -> usize {
+    let replayed = replay(sblock, device);
+    *JOURNAL.lock() = Journal::new(sblock, device);
+    replayed
+}
+
+// the journal and its header, none when the volume has no journal
+fn read_header(sblock: &SuperBlock, device: &Arc<dyn BlockDevice>) -> Option<(Journal, Header)> {
+    let journal = Journal::new(sblock, device)?;
+    let mut buf = vec![0; journal.sector];
+    device.read_blocks(journal.start, &mut buf);
+    let header = Header::from_bytes(&buf);
+    Some((journal, header))
+}
+
+// the home address and the copy address of every block in the overflow
+fn read_overflow(journal: &Journal, header: &Header) -> Vec<(usize, usize)> {
+    let mut blocks = Vec::new();
+    let mut buf = vec![0; journal.sector];
+    let mut addr = match header.overflow {
+        0 => 0,
+        cluster => journal.sblock.offset(cluster as usize),
+    };
+    while addr != 0 {
+        journal.device.read_blocks(addr, &mut buf);
+        let field = |at: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&buf[at..at + 8]);
+            u64::from_le_bytes(bytes) as usize
+        };
+        let count = field(8).min((journal.sector - OVERFLOW_SIZE) / 16);
+        for num in 0..count {
+            let entry = OVERFLOW_SIZE + num * 16;
+            blocks.push((field(entry), field(entry + 8)));
+        }
+        addr = field(0);
+    }
+    blocks
+}
+
+/// how many blocks a committed transaction still has to write home, nothing is written
+pub fn pending(sblock: &SuperBlock, device: &Arc<dyn BlockDevice>) -> usize {
+    match read_header(sblock, device) {
+        Some((journal, header)) if header.is_committed() => {
+            (header.count as usize).min(journal.capacity) + read_overflow(&journal, &header).len()
+        }
+        _ => 0,
+    }
+}
+
+/// write a committed transaction home, it is fine to do it twice
+pub fn replay(sblock: &SuperBlock, device: &Arc<dyn BlockDevice>) -> usize {
+    let (journal, header) = match read_header(sblock, device) {
+        Some((journal, header)) if header.is_committed() => (journal, header),
+        _ => return 0,
+    };
+    let sector = journal.sector;
+    let count = (header.count as usize).min(journal.capacity);
+    let mut blocks = vec![0; count * sector];
+    device.read_blocks(journal.start + sector, &mut blocks);
+    for (idx, data) in blocks.chunks(sector).enumerate() {
+        write_blocks(header.addrs[idx] as usize, data, device);
+    }
+    let overflow = read_overflow(&journal, &header);
+    let mut data = vec![0; sector];
+    for &(home, copy) in overflow.iter() {
+        device.read_blocks(copy, &mut data);
+        write_blocks(home, &data, device);
+    }
+    device.write_blocks(journal.start, &Header::new(&[], 0).to_sector(sector));
+    count + overflow.len()
+}
+
+/// group the following block writes into one transaction,
+/// every `begin` must be paired with a `commit`
+pub fn begin() {
+    if let Some(journal) = JOURNAL.lock().as_mut() {
+        journal.begin()
+    }
+}
+
+pub fn commit() {
+    if let Some(journal) = JOURNAL.lock().as_mut() {
+        journal.commit()
+    }
+}
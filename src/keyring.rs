@@ -0,0 +1,35 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use super::chacha::Key;
+use super::device::BlockDevice;
+use super::sblock::modify_sblock;
+
+lazy_static! {
+    static ref KEYRING: Mutex<BTreeMap<u32, Key>> = Mutex::new(BTreeMap::new());
+}
+
+/// make `key` usable for the entries encrypted with `id`, id 0 means no key
+pub fn add_key(id: u32, key: Key) {
+    assert_ne!(id, 0, "key id 0 is reserved");
+    KEYRING.lock().insert(id, key);
+}
+
+/// entries opened from now on cannot be read or written without it
+pub fn remove_key(id: u32) {
+    KEYRING.lock().remove(&id);
+}
+
+pub fn find_key(id: u32) -> Option<Key> {
+    KEYRING.lock().get(&id).copied()
+}
+
+// every file encrypted gets a nonce of its own, so no two files share a keystream,
+// the counter is taken in place so the other fields cached are not written back stale
+pub(crate) fn alloc_nonce(device: &Arc<dyn BlockDevice>) -> u64 {
+    modify_sblock(device, |sblock| {
+        sblock.next_nonce += 1;
+        sblock.next_nonce
+    })
+}
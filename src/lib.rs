@@ -1,19 +1,44 @@
 #![no_std]
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
 pub mod device;
 pub mod sblock;
 pub mod system;
 pub mod fat;
+pub mod bitmap;
+pub mod extent;
 pub mod cache;
+pub mod crc;
+pub mod lz4;
+pub mod chacha;
+pub mod keyring;
+pub mod xattr;
+pub mod quota;
+pub mod journal;
 pub mod inode;
 pub mod dir;
 pub mod file;
 pub mod macros;
+pub mod tar;
+pub mod fsck;
+#[cfg(feature = "std")]
+pub mod host;
 
+/// the smallest sector size, and the one of a device that does not tell,
+/// a volume takes its own from `SuperBlock::byte_per_sector`
 pub const BLOCK_SIZE: usize = 512;
 
+/// the largest sector size a volume can have
+pub const MAX_SECTOR_SIZE: usize = 4096;
+
+/// `value / divisor` rounded up
+pub fn div_ceil(value: usize, divisor: usize) -> usize {
+    value / divisor + (value % divisor != 0) as usize
+}
+
 pub(crate) fn is_illegal(chs: &str) -> bool {
     let illegal_char = "\\/:*?\"<>|";
     for ch in illegal_char.chars() {
@@ -0,0 +1,121 @@
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+// a match is at least this long
+const MIN_MATCH: usize = 4;
+// the last match starts this far from the end at least
+const MF_LIMIT: usize = 12;
+// and the last bytes are always literals
+const LAST_LITERALS: usize = 5;
+const MAX_OFFSET: usize = 0xFFFF;
+const HASH_LOG: u32 = 12;
+
+fn read_u32(src: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(src[pos..pos + 4].try_into().unwrap())
+}
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+// lengths past 15 go on in bytes of 255
+fn push_len(dst: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        dst.push(255);
+        len -= 255;
+    }
+    dst.push(len as u8);
+}
+
+fn push_sequence(dst: &mut Vec<u8>, literals: &[u8], match_: Option<(usize, usize)>) {
+    let lit_len = literals.len();
+    let match_len = match_.map_or(0, |(_, len)| len - MIN_MATCH);
+    dst.push(((lit_len.min(15) as u8) << 4) | match_len.min(15) as u8);
+    if lit_len >= 15 {
+        push_len(dst, lit_len - 15);
+    }
+    dst.extend_from_slice(literals);
+    if let Some((offset, _)) = match_ {
+        dst.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            push_len(dst, match_len - 15);
+        }
+    }
+}
+
+/// compress `src` into an LZ4 block
+pub fn compress(src: &[u8]) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(src.len());
+    let mut table = [0u32; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+    if src.len() > MF_LIMIT {
+        let limit = src.len() - MF_LIMIT;
+        let match_end = src.len() - LAST_LITERALS;
+        while pos < limit {
+            let seq = read_u32(src, pos);
+            let slot = hash(seq);
+            let candidate = table[slot] as usize;
+            table[slot] = pos as u32;
+            if candidate >= pos || pos - candidate > MAX_OFFSET || read_u32(src, candidate) != seq {
+                pos += 1;
+                continue;
+            }
+            let mut len = MIN_MATCH;
+            while pos + len < match_end && src[candidate + len] == src[pos + len] {
+                len += 1;
+            }
+            push_sequence(&mut dst, &src[anchor..pos], Some((pos - candidate, len)));
+            pos += len;
+            anchor = pos;
+        }
+    }
+    push_sequence(&mut dst, &src[anchor..], None);
+    dst
+}
+
+fn read_len(src: &[u8], idx: &mut usize, mut len: usize) -> Option<usize> {
+    if len == 15 {
+        loop {
+            let byte = *src.get(*idx)?;
+            *idx += 1;
+            len += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Some(len)
+}
+
+/// decompress the LZ4 block `src` into `dst`,
+/// none if the block is malformed or does not fit
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut idx = 0;
+    let mut out = 0;
+    loop {
+        let token = *src.get(idx)?;
+        idx += 1;
+
+        let lit_len = read_len(src, &mut idx, (token >> 4) as usize)?;
+        let literals = src.get(idx..idx.checked_add(lit_len)?)?;
+        dst.get_mut(out..out + lit_len)?.copy_from_slice(literals);
+        idx += lit_len;
+        out += lit_len;
+        if idx == src.len() {
+            return Some(out);
+        }
+
+        let offset = u16::from_le_bytes(src.get(idx..idx + 2)?.try_into().unwrap()) as usize;
+        idx += 2;
+        let match_len = read_len(src, &mut idx, (token & 15) as usize)? + MIN_MATCH;
+        if offset == 0 || offset > out || out + match_len > dst.len() {
+            return None;
+        }
+        // the match may overlap the bytes it produces
+        for pos in out..out + match_len {
+            dst[pos] = dst[pos - offset];
+        }
+        out += match_len;
+    }
+}
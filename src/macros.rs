@@ -3,11 +3,23 @@ macro_rules! iter_sector {
     ($self: ident, $f: expr) => {{
         let mut exit = false;
         let mut sector_addr = 0;
-        for &c in $self.clusters.iter() {
+        let spc = $self.sblock.sector_per_cluster;
+        for (i, &c) in $self.clusters.iter().enumerate() {
             let addr = $self.sblock.offset(c);
-            for o in (0..$self.sblock.sector_per_cluster) {
-                sector_addr = addr + o * BLOCK_SIZE;
-                exit = get_block_cache(sector_addr, &$self.device).lock().read(0, $f);
+            for o in (0..spc) {
+                sector_addr = addr + o * $self.sblock.byte_per_sector;
+                // the rest of this cluster, then the clusters after it
+                read_ahead(
+                    core::iter::once((sector_addr, spc - o)).chain(
+                        $self.clusters[i + 1..].iter().map(|&c| ($self.sblock.offset(c), spc))
+                    ),
+                    &$self.device
+                );
+                let cache = get_meta_cache(sector_addr, &$self.device);
+                let cache = cache.lock();
+                // a corrupted sector is neither searched nor reused
+                if cache.is_corrupted() { continue; }
+                exit = cache.read(0, $f);
                 if exit { break; }
             }
             if exit { break; }
@@ -24,8 +36,11 @@ macro_rules! iter_sector_mut {
         for &c in $self.clusters.iter() {
             let addr = $self.sblock.offset(c);
             for o in (0..$self.sblock.sector_per_cluster) {
-                sector_addr = addr + o * BLOCK_SIZE;
-                exit = get_block_cache(sector_addr, &$self.device).lock().modify(0, $f);
+                sector_addr = addr + o * $self.sblock.byte_per_sector;
+                let cache = get_meta_cache(sector_addr, &$self.device);
+                let mut cache = cache.lock();
+                if cache.is_corrupted() { continue; }
+                exit = cache.modify(0, $f);
                 if exit { break; }
             }
             if exit { break; }
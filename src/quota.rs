@@ -0,0 +1,250 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use super::cache::{
+    get_block_cache,
+    write_blocks,
+};
+use super::device::BlockDevice;
+use super::fat::{
+    can_grow,
+    increase_cluster,
+    read_clusters,
+};
+use super::sblock::SuperBlock;
+
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaKind {
+    User = 1,
+    Group = 2,
+}
+
+/// usage and limits of one owner, in clusters, a limit of 0 means none
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quota {
+    pub(crate) used: u32,
+    pub(crate) soft: u32,
+    pub(crate) hard: u32,
+}
+
+impl Quota {
+    pub fn used(&self) -> usize {
+        self.used as usize
+    }
+
+    pub fn soft(&self) -> usize {
+        self.soft as usize
+    }
+
+    pub fn hard(&self) -> usize {
+        self.hard as usize
+    }
+
+    /// passing the soft limit is allowed, it is only reported
+    pub fn over_soft(&self) -> bool {
+        self.soft != 0 && self.used > self.soft
+    }
+
+    fn allows(&self, clusters: usize) -> bool {
+        self.hard == 0 || self.used as usize + clusters <= self.hard as usize
+    }
+}
+
+// one entry of the quota file, a zero kind ends the entries
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Record {
+    kind: u16,
+    id: u16,
+    quota: Quota,
+}
+
+const RECORD_SIZE: usize = core::mem::size_of::<Record>();
+
+pub struct Quotas {
+    device: Arc<dyn BlockDevice>,
+    sblock: SuperBlock,
+    chain: Vec<usize>,
+    records: Vec<Record>,
+}
+
+impl Quotas {
+    fn new(sblock: &SuperBlock, device: &Arc<dyn BlockDevice>) -> Option<Self> {
+        if sblock.quota_cluster == 0 {
+            return None;
+        }
+        let mut quotas = Self {
+            device: Arc::clone(device),
+            sblock: *sblock,
+            chain: read_clusters(sblock.quota_cluster),
+            records: Vec::new(),
+        };
+        while let Some(addr) = quotas.record_addr(quotas.records.len()) {
+            let in_sector = addr % sblock.byte_per_sector;
+            let record = get_block_cache(addr - in_sector, device)
+                .lock()
+                .read(in_sector, |r: &Record| *r);
+            if record.kind == 0 {
+                break;
+            }
+            quotas.records.push(record);
+        }
+        Some(quotas)
+    }
+
+    fn record_addr(&self, idx: usize) -> Option<usize> {
+        let bpc = self.sblock.byte_per_cluster();
+        let loc = idx * RECORD_SIZE;
+        let cluster = self.chain.get(loc / bpc)?;
+        Some(self.sblock.offset(*cluster) + loc % bpc)
+    }
+
+    fn find(&self, kind: QuotaKind, id: u16) -> Option<usize> {
+        self.records.iter().position(|r| r.kind == kind as u16 && r.id == id)
+    }
+
+    fn get(&self, kind: QuotaKind, id: u16) -> Quota {
+        self.find(kind, id).map_or(Quota::default(), |idx| self.records[idx].quota)
+    }
+
+    fn update(&mut self, kind: QuotaKind, id: u16, f: impl FnOnce(&mut Quota)) {
+        let idx = match self.find(kind, id) {
+            Some(idx) => idx,
+            None => {
+                self.records.push(Record { kind: kind as u16, id, quota: Quota::default() });
+                self.records.len() - 1
+            }
+        };
+        f(&mut self.records[idx].quota);
+        self.store(idx);
+    }
+
+    // the quota file grows uncharged, the caller holds the lock
+    fn store(&mut self, idx: usize) {
+        let bpc = self.sblock.byte_per_cluster();
+        // the record only stays in memory while the quota file can not grow
+        if self.record_addr(idx).is_none() && !can_grow(*self.chain.last().unwrap()) {
+            return;
+        }
+        if self.record_addr(idx).is_none() {
+            let saved = OWNER.lock().take();
+            let mut clusters = increase_cluster(*self.chain.last().unwrap(), bpc);
+            *OWNER.lock() = saved;
+            for &cluster in clusters.iter() {
+                write_blocks(self.sblock.offset(cluster), &vec![0; bpc], &self.device);
+            }
+            self.chain.append(&mut clusters);
+        }
+        let addr = self.record_addr(idx).unwrap();
+        let in_sector = addr % self.sblock.byte_per_sector;
+        let record = self.records[idx];
+        get_block_cache(addr - in_sector, &self.device)
+            .lock()
+            .modify(in_sector, |r: &mut Record| *r = record);
+    }
+}
+
+lazy_static! {
+    pub static ref QUOTAS: Mutex<Option<Quotas>> = Mutex::new(None);
+    // whom the clusters allocated and freed now are charged to
+    static ref OWNER: Mutex<Option<(u16, u16)>> = Mutex::new(None);
+}
+
+/// load the quota file, usage is not tracked on a volume without one
+pub fn init_quota(sblock: &SuperBlock, device: &Arc<dyn BlockDevice>) {
+    *QUOTAS.lock() = Quotas::new(sblock, device);
+}
+
+pub fn quota(kind: QuotaKind, id: u16) -> Quota {
+    QUOTAS.lock().as_ref().map_or(Quota::default(), |q| q.get(kind, id))
+}
+
+/// every owner charged so far, with its quota
+pub fn quotas() -> Vec<(QuotaKind, u16, Quota)> {
+    match QUOTAS.lock().as_ref() {
+        Some(q) => q.records.iter().map(|r| {
+            let kind = match r.kind {
+                1 => QuotaKind::User,
+                _ => QuotaKind::Group,
+            };
+            (kind, r.id, r.quota)
+        }).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// limits in clusters, 0 for none, false without a quota file
+pub fn set_quota(kind: QuotaKind, id: u16, soft: usize, hard: usize) -> bool {
+    match QUOTAS.lock().as_mut() {
+        Some(q) => {
+            q.update(kind, id, |quota| {
+                quota.soft = soft as u32;
+                quota.hard = hard as u32;
+            });
+            true
+        }
+        None => false,
+    }
+}
+
+/// whether `uid` and `gid` can take `clusters` more without passing a hard limit
+pub fn allows(uid: u16, gid: u16, clusters: usize) -> bool {
+    match QUOTAS.lock().as_ref() {
+        Some(q) => q.get(QuotaKind::User, uid).allows(clusters)
+            && q.get(QuotaKind::Group, gid).allows(clusters),
+        None => true,
+    }
+}
+
+/// clusters allocated, shared or freed while it lives are charged to its owner
+pub struct Owner {
+    saved: Option<(u16, u16)>,
+}
+
+pub fn enter(uid: u16, gid: u16) -> Owner {
+    Owner { saved: OWNER.lock().replace((uid, gid)) }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        *OWNER.lock() = self.saved;
+    }
+}
+
+// called by the FAT for every chain it touches, a negative count gives clusters back
+pub(crate) fn charge(clusters: isize) {
+    let (uid, gid) = match *OWNER.lock() {
+        Some(owner) => owner,
+        None => return,
+    };
+    if let Some(q) = QUOTAS.lock().as_mut() {
+        for &(kind, id) in [(QuotaKind::User, uid), (QuotaKind::Group, gid)].iter() {
+            q.update(kind, id, |quota| {
+                quota.used = (quota.used as isize + clusters).max(0) as u32;
+            });
+        }
+    }
+}
+
+/// move `clusters` from one owner to another, false if the new owner has no room for them
+pub fn transfer(from: (u16, u16), to: (u16, u16), clusters: usize) -> bool {
+    let mut quotas = QUOTAS.lock();
+    let q = match quotas.as_mut() {
+        Some(q) => q,
+        None => return true,
+    };
+    let moves = [(QuotaKind::User, from.0, to.0), (QuotaKind::Group, from.1, to.1)];
+    if moves.iter().any(|&(kind, old, new)| old != new && !q.get(kind, new).allows(clusters)) {
+        return false;
+    }
+    for &(kind, old, new) in moves.iter() {
+        if old != new {
+            q.update(kind, old, |quota| quota.used = quota.used.saturating_sub(clusters as u32));
+            q.update(kind, new, |quota| quota.used += clusters as u32);
+        }
+    }
+    true
+}
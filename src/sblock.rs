@@ -1,9 +1,28 @@
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use super::BLOCK_SIZE;
+use super::MAX_SECTOR_SIZE;
 use super::cache::get_block_cache;
+use super::crc::{
+    stamp_sector,
+    verify_sector,
+};
 use super::device::BlockDevice;
 
 const FEFS_MAGIC: [u8; 4] = [0x66, 0x65, 0x66, 0x73];
 
+/// the superblock, FAT and directory sectors end with a CRC32C
+pub const FEATURE_CHECKSUMS: u32 = 1;
+
+/// files written keep the runs of their data chain in the inode
+pub const FEATURE_EXTENTS: u32 = 2;
+
+/// everything was written back since the last change,
+/// so the counters and the next free hint can be trusted
+pub const STATE_CLEAN: u32 = 1;
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct SuperBlock {
@@ -12,6 +31,24 @@ pub struct SuperBlock {
     pub(crate) sector_per_cluster: usize,
     pub(crate) sector_per_fat: usize,
     pub(crate) root_cluster: usize,
+    pub(crate) label: [u8; 16],
+    pub(crate) journal_cluster: usize,
+    pub(crate) journal_sectors: usize,
+    pub(crate) refcount_cluster: usize,
+    pub(crate) snapshot_cluster: usize,
+    pub(crate) features: u32,
+    // last nonce handed to an encrypted file
+    pub(crate) next_nonce: u64,
+    pub(crate) quota_cluster: usize,
+    // kept up to date by the FAT and the directories, so statfs needs no scan
+    pub(crate) free_clusters: usize,
+    pub(crate) used_inodes: usize,
+    // where allocation goes on from after a mount
+    pub(crate) next_free: usize,
+    pub(crate) state: u32,
+    // clusters the device holds past the FAT, the FAT may have room for more,
+    // zero on a volume formatted before it was recorded
+    pub(crate) clusters: usize,
 }
 
 impl SuperBlock {
@@ -19,9 +56,136 @@ impl SuperBlock {
         self.magic == FEFS_MAGIC
     }
 
+    pub fn has_feature(&self, feature: u32) -> bool {
+        self.features & feature != 0
+    }
+
+    /// the last entry of each FAT sector holds the checksum of the sector,
+    /// so the cluster it stands for is never used
+    pub fn is_reserved(&self, cluster: usize) -> bool {
+        self.has_feature(FEATURE_CHECKSUMS) && self.fat_entry(cluster).1 == self.byte_per_sector - 4
+    }
+
+    /// `len` bytes of empty metadata sectors, stamped on a checksummed volume
+    /// so that one nothing was written to yet still verifies
+    pub fn empty_sectors(&self, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        if self.has_feature(FEATURE_CHECKSUMS) {
+            for sector in buf.chunks_mut(self.byte_per_sector) {
+                stamp_sector(sector);
+            }
+        }
+        buf
+    }
+
+    /// end of the blocks checksummed by address, the superblock and the FAT
+    pub fn meta_end(&self) -> usize {
+        self.offset(self.root_cluster)
+    }
+
+    /// the FAT starts from the second sector
     pub fn fat(&self) -> usize {
-        512
-    } 
+        self.byte_per_sector
+    }
+
+    /// number of entries the FAT region holds, the first cluster is `root_cluster`,
+    /// entries for clusters past the end of the device are left out
+    /// when the volume knows where the device ends
+    pub fn fat_entries(&self) -> usize {
+        let entries = (self.sector_per_fat * self.byte_per_sector - self.fat()) / 4;
+        match self.clusters {
+            0 => entries,
+            clusters => entries.min(self.root_cluster + clusters),
+        }
+    }
+
+    pub fn byte_per_sector(&self) -> usize {
+        self.byte_per_sector
+    }
+
+    pub fn sector_per_cluster(&self) -> usize {
+        self.sector_per_cluster
+    }
+
+    pub fn byte_per_cluster(&self) -> usize {
+        self.byte_per_sector * self.sector_per_cluster
+    }
+
+    /// the FAT takes the sectors before this one, clusters start from it
+    pub fn sector_per_fat(&self) -> usize {
+        self.sector_per_fat
+    }
+
+    pub fn root_cluster(&self) -> usize {
+        self.root_cluster
+    }
+
+    /// first cluster of the journal, the journal clusters are contiguous
+    pub fn journal_cluster(&self) -> usize {
+        self.journal_cluster
+    }
+
+    /// sectors taken by the journal, zero when there is none
+    pub fn journal_sectors(&self) -> usize {
+        self.journal_sectors
+    }
+
+    /// first cluster of the refcount table, zero until a cluster is shared
+    pub fn refcount_cluster(&self) -> usize {
+        self.refcount_cluster
+    }
+
+    /// first cluster of the hidden directory holding the snapshots, zero when there is none
+    pub fn snapshot_cluster(&self) -> usize {
+        self.snapshot_cluster
+    }
+
+    /// clusters the FAT can hand out, without the checksum slots
+    pub fn total_clusters(&self) -> usize {
+        let (start, end) = (self.root_cluster, self.fat_entries());
+        // one slot in each FAT sector, the last one
+        let entries = self.byte_per_sector / 4;
+        let reserved = match self.has_feature(FEATURE_CHECKSUMS) {
+            true => end / entries - start / entries,
+            false => 0,
+        };
+        end - start - reserved
+    }
+
+    pub fn free_clusters(&self) -> usize {
+        self.free_clusters
+    }
+
+    /// entries in every directory, snapshots included
+    pub fn used_inodes(&self) -> usize {
+        self.used_inodes
+    }
+
+    /// where the search for a free cluster starts
+    pub fn next_free(&self) -> usize {
+        self.next_free
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.state & STATE_CLEAN != 0
+    }
+
+    /// first cluster of the quota file, zero when usage is not tracked
+    pub fn quota_cluster(&self) -> usize {
+        self.quota_cluster
+    }
+
+    pub fn label(&self) -> String {
+        let len = self.label.iter().position(|&b| b == 0).unwrap_or(self.label.len());
+        String::from_utf8_lossy(&self.label[0..len]).into()
+    }
+
+    /// address of the FAT sector holding `cluster` and its offset in that sector
+    pub fn fat_entry(&self, cluster: usize) -> (usize, usize) {
+        let loc = cluster * 4;
+        let bps = self.byte_per_sector;
+        (self.fat() + loc / bps * bps, loc % bps)
+    }
 
     pub fn offset(&self, cluster: usize) -> usize {
         (self.sector_per_fat + (cluster - self.root_cluster) * self.sector_per_cluster)
@@ -29,15 +193,63 @@ impl SuperBlock {
     }
 }
 
+/// sector sizes a volume can have
+pub fn is_sector_size(size: usize) -> bool {
+    size.is_power_of_two() && (BLOCK_SIZE..=MAX_SECTOR_SIZE).contains(&size)
+}
+
+/// the sector size of the volume on `device`, read around the cache,
+/// which has to know it before it holds the superblock
+pub fn probe_sector_size(device: &Arc<dyn BlockDevice>) -> usize {
+    let mut buf = vec![0; device.sector_size()];
+    device.read(0, &mut buf);
+    let sblock = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const SuperBlock) };
+    assert!(sblock.is_valid(), "Error, Not FEFS");
+    assert!(is_sector_size(sblock.byte_per_sector), "Error, bad sector size {}", sblock.byte_per_sector);
+    sblock.byte_per_sector
+}
+
 pub fn get_sblock(device: &Arc<dyn BlockDevice>) -> SuperBlock {
-    get_block_cache(0, &Arc::clone(&device)).lock().read(0, |sblock: &SuperBlock| {
-        assert!(sblock.is_valid(), "Error, Not FEFS");
-        sblock.clone()
-    })
+    let cache = get_block_cache(0, device);
+    let cache = cache.lock();
+    let sblock = cache.read(0, |sblock: &SuperBlock| *sblock);
+    assert!(sblock.is_valid(), "Error, Not FEFS");
+    // a modified superblock is checksummed when written back
+    assert!(
+        !sblock.has_feature(FEATURE_CHECKSUMS) || cache.is_modified() || verify_sector(cache.data()),
+        "Error, superblock checksum mismatch"
+    );
+    sblock
+}
+
+/// change the superblock where it is cached,
+/// the fields the caller does not touch are kept as they are now,
+/// a clean volume is marked dirty on the device before the first change,
+/// so a crash never leaves stale counters marked clean
+pub(crate) fn modify_sblock<V>(device: &Arc<dyn BlockDevice>, f: impl FnOnce(&mut SuperBlock) -> V) -> V {
+    let cache = get_block_cache(0, device);
+    let mut cache = cache.lock();
+    // the change itself is written with the transaction it belongs to
+    if cache.read(0, |sblock: &SuperBlock| sblock.is_clean()) {
+        cache.modify(0, |sblock: &mut SuperBlock| sblock.state &= !STATE_CLEAN);
+        cache.sync();
+    }
+    cache.modify(0, f)
+}
+
+// written last, once everything else is on the device
+pub(crate) fn mark_clean(device: &Arc<dyn BlockDevice>, next_free: usize) {
+    let cache = get_block_cache(0, device);
+    let mut cache = cache.lock();
+    cache.modify(0, |sblock: &mut SuperBlock| {
+        sblock.next_free = next_free;
+        sblock.state |= STATE_CLEAN;
+    });
+    cache.sync();
 }
 
 pub fn write_sblock(sblock: SuperBlock, device: &Arc<dyn BlockDevice>) {
-    get_block_cache(0, &Arc::clone(&device)).lock().modify(0, |s: &mut SuperBlock| {
+    get_block_cache(0, device).lock().modify(0, |s: &mut SuperBlock| {
         *s = sblock;
     })
 }
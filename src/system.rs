@@ -1,39 +1,223 @@
 use alloc::sync::Arc;
+use alloc::vec;
 use spin::Mutex;
-use super::fat::read_clusters;
-use super::dir::DirEntry;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::BLOCK_SIZE;
+use super::div_ceil;
+use super::fat::{
+    alloc_clusters,
+    create_refcounts,
+    next_free,
+    read_clusters,
+};
+use super::chacha::Key;
+use super::journal;
+use super::keyring;
+use super::quota::{
+    self,
+    Quota,
+    QuotaKind,
+};
+use super::cache::{
+    enable_checksums,
+    set_sector_size,
+    sync_all,
+    write_blocks,
+};
+use super::dir::{
+    DirEntry,
+    DirError,
+};
+use super::inode::NAME_LEN;
 use super::sblock::SuperBlock;
 use super::device::BlockDevice;
 use super::fat::{
     create_fat,
     init_fat_manager,
+    write_fat,
 };
 use super::sblock::{
     get_sblock,
+    is_sector_size,
+    mark_clean,
+    modify_sblock,
+    probe_sector_size,
     write_sblock,
+    FEATURE_CHECKSUMS,
+    FEATURE_EXTENTS,
+    STATE_CLEAN,
 };
 
+/// what `df` needs, sizes are in clusters of `cluster_size` bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    pub(crate) clusters: usize,
+    pub(crate) free_clusters: usize,
+    pub(crate) cluster_size: usize,
+    pub(crate) inodes: usize,
+    pub(crate) name_len: usize,
+}
+
+impl StatFs {
+    pub fn clusters(&self) -> usize {
+        self.clusters
+    }
+
+    pub fn free_clusters(&self) -> usize {
+        self.free_clusters
+    }
+
+    pub fn used_clusters(&self) -> usize {
+        self.clusters - self.free_clusters
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.cluster_size
+    }
+
+    /// entries in use, there is no fixed number of them
+    pub fn inodes(&self) -> usize {
+        self.inodes
+    }
+
+    /// longest name an entry can have, in bytes
+    pub fn name_len(&self) -> usize {
+        self.name_len
+    }
+}
+
 pub struct FileSystem {
     device: Arc<dyn BlockDevice>,
     sblock: SuperBlock,
 }
 
+/// sectors the FAT takes on a device of `size` bytes, the most whose clusters
+/// all fit in the device, the FAT starts from sector 1 and every cluster takes
+/// 4 bytes of it, the entries before the root cluster are reserved,
+/// none when the superblock, one FAT sector, the root cluster
+/// and the cluster of the quota file do not fit
+pub fn fat_sectors_for(size: usize, byte_per_sector: usize, sector_per_cluster: usize) -> Option<usize> {
+    let sectors = size / byte_per_sector;
+    if sectors < 2 + 2 * sector_per_cluster {
+        return None;
+    }
+    let entries = byte_per_sector / 4;
+    // (fat_sectors * entries - 2) * sector_per_cluster <= sectors - 1 - fat_sectors
+    let fat_sectors = (sectors + 2 * sector_per_cluster - 1) / (entries * sector_per_cluster + 1);
+    Some(fat_sectors.max(1))
+}
+
 impl FileSystem {
+    /// format the whole device, with a FAT sized to it,
+    /// panics when the device is too small, `fat_sectors_for` tells beforehand,
+    /// a device that does not tell its size gets a FAT of `2 * sector_per_cluster - 1` sectors
     pub fn create(
         device: Arc<dyn BlockDevice>, 
         byte_per_sector: usize,
         sector_per_cluster: usize,
     ) -> Arc<Mutex<Self>> {
-        let sblock = SuperBlock {
+        let fat_sectors = match device.size() {
+            usize::MAX => 2 * sector_per_cluster - 1,
+            size => fat_sectors_for(size, byte_per_sector, sector_per_cluster)
+                .expect("device is too small for a FAT, the root and the quota file"),
+        };
+        Self::format(device, byte_per_sector, sector_per_cluster, 1 + fat_sectors, "", 0)
+    }
+
+    /// `byte_per_sector` is a power of two from 512 to 4096 bytes,
+    /// and a multiple of the sector size of the device,
+    /// `sector_per_fat` is the first sector after the FAT,
+    /// the FAT itself starts from the second sector, `fat_sectors_for` gives
+    /// the size that fits the device, the clusters past the device are never used,
+    /// the journal takes whole clusters right after the root, zero means no journal,
+    /// more than `JOURNAL_SECTORS` would never be used
+    pub fn format(
+        device: Arc<dyn BlockDevice>, 
+        byte_per_sector: usize,
+        sector_per_cluster: usize,
+        sector_per_fat: usize,
+        label: &str,
+        journal_sectors: usize,
+    ) -> Arc<Mutex<Self>> {
+        assert!(label.len() <= 16, "label is longer than 16 bytes");
+        assert!(
+            is_sector_size(byte_per_sector) && byte_per_sector % device.sector_size() == 0,
+            "sector size {} does not fit the device",
+            byte_per_sector
+        );
+        set_sector_size(byte_per_sector);
+        let mut sblock = SuperBlock {
             magic: [0x66, 0x65, 0x66, 0x73],
             byte_per_sector,
             sector_per_cluster,
-            sector_per_fat: sector_per_cluster * 2,
+            sector_per_fat,
             root_cluster: 2,
+            label: [0; 16],
+            journal_cluster: 0,
+            journal_sectors: 0,
+            refcount_cluster: 0,
+            snapshot_cluster: 0,
+            features: FEATURE_CHECKSUMS,
+            next_nonce: 0,
+            quota_cluster: 0,
+            free_clusters: 0,
+            used_inodes: 0,
+            next_free: 0,
+            state: STATE_CLEAN,
+            clusters: 0,
+        };
+        // a device that does not tell its size holds every cluster of the FAT
+        sblock.clusters = match device.size() {
+            usize::MAX => sblock.fat_entries() - sblock.root_cluster,
+            size => (size / byte_per_sector).saturating_sub(sector_per_fat) / sector_per_cluster,
         };
+        sblock.label[0..label.len()].copy_from_slice(label.as_bytes());
+        let journal_sectors = journal_sectors.min(journal::JOURNAL_SECTORS);
+        let journal_clusters = div_ceil(journal_sectors, sector_per_cluster);
+        assert!(
+            // the root, the quota file and the journal
+            sblock.total_clusters() >= 2 + journal_clusters,
+            "FAT or device is too small"
+        );
+        // an old volume may be left on the device, the FAT is cleared
+        // a cluster at a time, it takes megabytes on a large device
+        let empty = sblock.empty_sectors(sblock.byte_per_cluster());
+        let mut addr = sblock.fat();
+        while addr < sblock.meta_end() {
+            let len = empty.len().min(sblock.meta_end() - addr);
+            write_blocks(addr, &empty[0..len], &device);
+            addr += len;
+        }
+        write_blocks(sblock.offset(sblock.root_cluster), &empty, &device);
+        enable_checksums(sblock.meta_end());
         create_fat(sblock.fat(), &device);
+        if journal_clusters > 0 {
+            sblock.journal_cluster = sblock.root_cluster + 1;
+            sblock.journal_sectors = journal_clusters * sector_per_cluster;
+            let start = sblock.journal_cluster;
+            for cluster in start..start + journal_clusters {
+                let next = if cluster + 1 == start + journal_clusters { 0x0FFFFFFF } else { cluster + 1 };
+                write_fat(cluster, next, &sblock, &device);
+            }
+            write_blocks(
+                sblock.offset(start),
+                &vec![0; journal_clusters * sblock.byte_per_cluster()],
+                &device,
+            );
+        }
+        // the root and the journal are taken
+        sblock.free_clusters = sblock.total_clusters() - 1 - journal_clusters;
+        sblock.next_free = sblock.root_cluster + 1 + journal_clusters;
         write_sblock(sblock, &device);
         init_fat_manager(&device);
+        // usage is tracked from the start, the quota file begins empty
+        let cluster = alloc_clusters(BLOCK_SIZE)[0];
+        write_blocks(sblock.offset(cluster), &vec![0; sblock.byte_per_cluster()], &device);
+        modify_sblock(&device, |sblock| sblock.quota_cluster = cluster);
+        let sblock = get_sblock(&device);
+        quota::init_quota(&sblock, &device);
+        journal::init_journal(&sblock, &device);
         Arc::new(Mutex::new(Self {
             device,
             sblock,
@@ -41,8 +225,16 @@ impl FileSystem {
     }
 
     pub fn open(device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
+        set_sector_size(probe_sector_size(&device));
         let sblock = get_sblock(&device);
+        match sblock.has_feature(FEATURE_CHECKSUMS) {
+            true => enable_checksums(sblock.meta_end()),
+            false => enable_checksums(0),
+        }
+        // the FAT may be among the blocks replayed
+        journal::init_journal(&sblock, &device);
         init_fat_manager(&device);
+        quota::init_quota(&sblock, &device);
         let fs = Self {
             device,
             sblock,
@@ -50,11 +242,148 @@ impl FileSystem {
         Arc::new(Mutex::new(fs))
     }
 
+    pub fn sblock(&self) -> SuperBlock {
+        self.sblock
+    }
+
+    /// make `key` usable for the files and directories encrypted with `id`,
+    /// id 0 means no key
+    pub fn add_key(&self, id: u32, key: Key) {
+        keyring::add_key(id, key)
+    }
+
+    pub fn remove_key(&self, id: u32) {
+        keyring::remove_key(id)
+    }
+
+    /// limits of the owner `id` in clusters, 0 for none,
+    /// the soft limit is only reported, writes fail past the hard one
+    pub fn set_quota(&self, kind: QuotaKind, id: u16, soft: usize, hard: usize) -> bool {
+        journal::begin();
+        let ret = quota::set_quota(kind, id, soft, hard);
+        journal::commit();
+        ret
+    }
+
+    /// clusters used by the owner `id` and its limits
+    pub fn quota(&self, kind: QuotaKind, id: u16) -> Quota {
+        quota::quota(kind, id)
+    }
+
+    pub fn quotas(&self) -> Vec<(QuotaKind, u16, Quota)> {
+        quota::quotas()
+    }
+
+    /// keep the runs of the data chain in the inode of every file written from now on,
+    /// opening or seeking a large file then costs its runs rather than its clusters,
+    /// directories opened before keep writing files the old way
+    pub fn enable_extents(&mut self) {
+        modify_sblock(&self.device, |sblock| sblock.features |= FEATURE_EXTENTS);
+        self.sblock = get_sblock(&self.device);
+    }
+
+    /// space and entries of the volume, from the counters of the superblock
+    pub fn statfs(&self) -> StatFs {
+        let sblock = get_sblock(&self.device);
+        StatFs {
+            clusters: sblock.total_clusters(),
+            free_clusters: sblock.free_clusters,
+            cluster_size: sblock.byte_per_cluster(),
+            inodes: sblock.used_inodes,
+            name_len: NAME_LEN,
+        }
+    }
+
+    /// write every modified block back to the device, the volume is clean
+    /// until the next change, so the next mount trusts its free count and hint
+    pub fn sync(&self) {
+        sync_all();
+        mark_clean(&self.device, next_free());
+    }
+
     pub fn root(&self) -> DirEntry {
         DirEntry {
             device: Arc::clone(&self.device),
             clusters: read_clusters(self.sblock.root_cluster),
             sblock: self.sblock,
+            read_only: false,
+            key_id: 0,
+        }
+    }
+
+    /// freeze the current tree as `name`, files share their clusters
+    /// with the snapshot until one side writes to them
+    pub fn snapshot(&mut self, name: &str) -> Result<(), DirError> {
+        journal::begin();
+        let mut snapshots = self.snapshot_dir();
+        let ret = snapshots.mkdir(name).map(|mut snapshot| snapshot.copy_from(&self.root()));
+        journal::commit();
+        ret
+    }
+
+    pub fn snapshots(&self) -> Vec<String> {
+        if self.sblock.snapshot_cluster == 0 {
+            return Vec::new();
+        }
+        self.snapshot_entry(false).ls().iter().map(|inode| inode.name()).collect()
+    }
+
+    /// root of the snapshot `name`, nothing under it can be changed
+    pub fn open_snapshot(&self, name: &str) -> Result<DirEntry, DirError> {
+        if self.sblock.snapshot_cluster == 0 {
+            return Err(DirError::NotFoundDir);
+        }
+        self.snapshot_entry(true).cd(name)
+    }
+
+    /// clusters only the snapshot still references are freed
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<(), DirError> {
+        self.open_snapshot(name)?;
+        self.snapshot_entry(false).delete(name)
+    }
+
+    /// replace the current tree with a copy of the snapshot `name`,
+    /// the snapshot itself is kept
+    pub fn rollback(&mut self, name: &str) -> Result<(), DirError> {
+        let snapshot = self.open_snapshot(name)?;
+        let mut root = self.root();
+        // a delete only fails on a corrupted root, found before anything changes
+        root.verify()?;
+        journal::begin();
+        let deleted = root.ls().iter().try_for_each(|inode| root.delete(&inode.name()));
+        if deleted.is_ok() {
+            root.copy_from(&snapshot);
+        }
+        // the transaction is closed on an error as well
+        journal::commit();
+        deleted
+    }
+
+    fn snapshot_entry(&self, read_only: bool) -> DirEntry {
+        DirEntry {
+            device: Arc::clone(&self.device),
+            clusters: read_clusters(self.sblock.snapshot_cluster),
+            sblock: self.sblock,
+            read_only,
+            key_id: 0,
+        }
+    }
+
+    // the directory holding the snapshots is not reachable from the root,
+    // it is created with the refcount table on the first snapshot
+    fn snapshot_dir(&mut self) -> DirEntry {
+        if self.sblock.snapshot_cluster == 0 {
+            let refcount_cluster = create_refcounts();
+            let cluster = alloc_clusters(BLOCK_SIZE)[0];
+            let empty = self.sblock.empty_sectors(self.sblock.byte_per_cluster());
+            write_blocks(self.sblock.offset(cluster), &empty, &self.device);
+            // the counters may have moved since the volume was opened
+            modify_sblock(&self.device, |sblock| {
+                sblock.refcount_cluster = refcount_cluster;
+                sblock.snapshot_cluster = cluster;
+            });
+            self.sblock = get_sblock(&self.device);
         }
+        self.snapshot_entry(false)
     }
 }
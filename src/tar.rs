@@ -0,0 +1,380 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::min;
+use super::BLOCK_SIZE;
+use super::div_ceil;
+use super::dir::DirEntry;
+use super::dir::DirError;
+use super::file::FileError;
+use super::file::WriteType;
+use super::inode::INode;
+
+const USTAR_MAGIC: &[u8] = b"ustar";
+
+/// where an archive is read from, `read` fills `buf` as far as it can
+/// and returns how many bytes it got, 0 means the end of the archive
+pub trait TarSource {
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+}
+
+impl TarSource for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let len = min(buf.len(), self.len());
+        buf[0..len].copy_from_slice(&self[0..len]);
+        *self = &self[len..];
+        len
+    }
+}
+
+/// where an archive is written to
+pub trait TarSink {
+    fn write(&mut self, buf: &[u8]);
+}
+
+impl TarSink for Vec<u8> {
+    fn write(&mut self, buf: &[u8]) {
+        self.extend_from_slice(buf);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TarError {
+    BadHeader,
+    BadChecksum,
+    UnexpectedEof,
+    PathTooLong,
+    /// a member of a type FEFS has nothing to map to, with its typeflag
+    Unsupported(u8),
+    Dir(DirError),
+    File(FileError),
+}
+
+impl From<DirError> for TarError {
+    fn from(err: DirError) -> Self {
+        TarError::Dir(err)
+    }
+}
+
+impl From<FileError> for TarError {
+    fn from(err: FileError) -> Self {
+        TarError::File(err)
+    }
+}
+
+/// an archive member `unpack` could not put into the volume
+#[derive(Debug)]
+pub struct Skipped {
+    pub path: String,
+    pub reason: TarError,
+}
+
+#[repr(C)]
+struct Header {
+    name: [u8; 100],
+    mode: [u8; 8],
+    uid: [u8; 8],
+    gid: [u8; 8],
+    size: [u8; 12],
+    mtime: [u8; 12],
+    chksum: [u8; 8],
+    typeflag: u8,
+    linkname: [u8; 100],
+    magic: [u8; 6],
+    version: [u8; 2],
+    uname: [u8; 32],
+    gname: [u8; 32],
+    devmajor: [u8; 8],
+    devminor: [u8; 8],
+    prefix: [u8; 155],
+    pad: [u8; 12],
+}
+
+impl Header {
+    fn empty() -> Self {
+        unsafe { core::mem::zeroed() }
+    }
+
+    fn from_block(block: &[u8; BLOCK_SIZE]) -> Self {
+        let mut header = Self::empty();
+        header.as_bytes_mut().copy_from_slice(block);
+        header
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, BLOCK_SIZE) }
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self as *mut _ as *mut u8, BLOCK_SIZE) }
+    }
+
+    // the checksum field counts as spaces
+    fn checksum(&self) -> usize {
+        let sum: usize = self.as_bytes().iter().map(|&b| b as usize).sum();
+        let field: usize = self.chksum.iter().map(|&b| b as usize).sum();
+        sum - field + 8 * b' ' as usize
+    }
+
+    fn path(&self) -> String {
+        let name = String::from_utf8_lossy(trim(&self.name));
+        if self.magic.starts_with(USTAR_MAGIC) && self.prefix[0] != 0 {
+            format!("{}/{}", String::from_utf8_lossy(trim(&self.prefix)), name)
+        } else {
+            name.into()
+        }
+    }
+
+    fn set_path(&mut self, path: &str) -> Result<(), TarError> {
+        let bytes = path.as_bytes();
+        if bytes.len() <= self.name.len() {
+            self.name[0..bytes.len()].copy_from_slice(bytes);
+            return Ok(());
+        }
+        // ustar keeps the leading directories in prefix, split at a '/'
+        for (idx, &b) in bytes.iter().enumerate() {
+            if b == b'/' && idx <= self.prefix.len() && bytes.len() - idx - 1 <= self.name.len() {
+                self.prefix[0..idx].copy_from_slice(&bytes[0..idx]);
+                self.name[0..bytes.len() - idx - 1].copy_from_slice(&bytes[idx + 1..]);
+                return Ok(());
+            }
+        }
+        Err(TarError::PathTooLong)
+    }
+}
+
+fn trim(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[0..len]
+}
+
+// numbers are octal text, big ones may use the base-256 extension
+fn parse_number(field: &[u8]) -> Result<usize, TarError> {
+    if field[0] & 0x80 != 0 {
+        let mut value = (field[0] & 0x7F) as usize;
+        for &b in field[1..].iter() {
+            value = (value << 8) | b as usize;
+        }
+        return Ok(value);
+    }
+
+    let mut value = 0;
+    for &b in field.iter() {
+        match b {
+            b'0'..=b'7' => value = value * 8 + (b - b'0') as usize,
+            b' ' | 0 if value == 0 => continue,
+            b' ' | 0 => break,
+            _ => return Err(TarError::BadHeader),
+        }
+    }
+    Ok(value)
+}
+
+fn write_number(field: &mut [u8], value: usize) {
+    let digits = field.len() - 1;
+    let text = format!("{:0width$o}", value, width = digits);
+    field[0..digits].copy_from_slice(&text.as_bytes()[text.len() - digits..]);
+    field[digits] = 0;
+}
+
+fn read_exact(source: &mut impl TarSource, buf: &mut [u8]) -> Result<(), TarError> {
+    let mut done = 0;
+    while done < buf.len() {
+        let len = source.read(&mut buf[done..]);
+        if len == 0 {
+            return Err(TarError::UnexpectedEof);
+        }
+        done += len;
+    }
+    Ok(())
+}
+
+fn skip(source: &mut impl TarSource, size: usize) -> Result<(), TarError> {
+    let mut block = [0; BLOCK_SIZE];
+    for _ in 0..div_ceil(size, BLOCK_SIZE) {
+        read_exact(source, &mut block)?;
+    }
+    Ok(())
+}
+
+// walk to the parent directory of `path`, making missing ones on the way,
+// none when it is `dir` itself: a clone of the handle goes stale once
+// another copy grows the directory
+fn parent<'a>(dir: &mut DirEntry, path: &'a str) -> Result<(Option<DirEntry>, &'a str), DirError> {
+    let mut comps: Vec<&str> = path
+        .split('/')
+        .filter(|comp| !comp.is_empty() && *comp != ".")
+        .collect();
+    let name = comps.pop().ok_or(DirError::NotFound)?;
+    let mut current: Option<DirEntry> = None;
+    for comp in comps {
+        let at = current.as_mut().unwrap_or(&mut *dir);
+        current = Some(match at.cd(comp) {
+            Ok(sub) => sub,
+            Err(_) => at.mkdir(comp)?,
+        });
+    }
+    Ok((current, name))
+}
+
+fn set_attrs(dir: &mut DirEntry, name: &str, header: &Header) -> Result<(), TarError> {
+    dir.set_mode(name, parse_number(&header.mode)? as u16)?;
+    dir.set_owner(name, parse_number(&header.uid)? as u16, parse_number(&header.gid)? as u16)?;
+    let mtime = parse_number(&header.mtime)? as u32;
+    dir.set_times(name, mtime, mtime)?;
+    Ok(())
+}
+
+/// unpack a tar stream into `dir`, existing files are replaced,
+/// links, devices, pax records and names FEFS cannot represent
+/// are left out and reported
+pub fn unpack(dir: &mut DirEntry, source: &mut impl TarSource) -> Result<Vec<Skipped>, TarError> {
+    let mut skipped = Vec::new();
+    let mut block = [0; BLOCK_SIZE];
+
+    loop {
+        if source.read(&mut block[0..1]) == 0 {
+            break;
+        }
+        read_exact(source, &mut block[1..])?;
+        if block.iter().all(|&b| b == 0) {
+            break;
+        }
+
+        let header = Header::from_block(&block);
+        if parse_number(&header.chksum)? != header.checksum() {
+            return Err(TarError::BadChecksum);
+        }
+        let size = parse_number(&header.size)?;
+        let path = header.path();
+        // the archive root is `dir` itself
+        if path.split('/').all(|comp| comp.is_empty() || comp == ".") {
+            skip(source, size)?;
+            continue;
+        }
+
+        let (mut sub, name) = match parent(dir, &path) {
+            Ok(ret) => ret,
+            Err(reason) => {
+                skipped.push(Skipped { path, reason: reason.into() });
+                skip(source, size)?;
+                continue;
+            }
+        };
+        let target = match sub.as_mut() {
+            Some(sub) => sub,
+            None => &mut *dir,
+        };
+
+        let ret = match header.typeflag {
+            b'0' | 0 | b'7' => unpack_file(target, name, size, source)?,
+            b'5' => match target.cd(name) {
+                Ok(_) => Ok(()),
+                Err(_) => target.mkdir(name).map(|_| ()),
+            },
+            // pax records, links and devices have nothing to map to
+            typeflag => {
+                skipped.push(Skipped { path, reason: TarError::Unsupported(typeflag) });
+                skip(source, size)?;
+                continue;
+            }
+        };
+
+        match ret {
+            Ok(()) => set_attrs(target, name, &header)?,
+            Err(reason) => skipped.push(Skipped { path, reason: reason.into() }),
+        }
+    }
+
+    Ok(skipped)
+}
+
+fn unpack_file(
+    dir: &mut DirEntry,
+    name: &str,
+    size: usize,
+    source: &mut impl TarSource,
+) -> Result<Result<(), DirError>, TarError> {
+    if matches!(dir.lookup(name), Some(inode) if inode.is_file()) {
+        dir.delete(name)?;
+    }
+    let mut file = match dir.create_file(name) {
+        Ok(file) => file,
+        Err(err) => {
+            skip(source, size)?;
+            return Ok(Err(err));
+        }
+    };
+
+    // data comes in chunks so a large member never sits in memory at once
+    let mut buf = vec![0; 64 * BLOCK_SIZE];
+    let mut left = size;
+    while left > 0 {
+        let len = min(left, buf.len());
+        read_exact(source, &mut buf[0..div_ceil(len, BLOCK_SIZE) * BLOCK_SIZE])?;
+        match file.write(&buf[0..len], WriteType::Append) {
+            Err(FileError::QuotaExceeded) => {
+                skip(source, left - len)?;
+                return Ok(Err(DirError::QuotaExceeded));
+            }
+            ret => ret?,
+        };
+        left -= len;
+    }
+    Ok(Ok(()))
+}
+
+/// write every directory and file under `dir` as a tar stream,
+/// paths are relative to `dir`
+pub fn pack(dir: &DirEntry, sink: &mut impl TarSink) -> Result<(), TarError> {
+    pack_inner(dir, "", sink)?;
+    sink.write(&[0; 2 * BLOCK_SIZE]);
+    Ok(())
+}
+
+fn pack_inner(dir: &DirEntry, prefix: &str, sink: &mut impl TarSink) -> Result<(), TarError> {
+    for inode in dir.ls().iter() {
+        let path = format!("{}{}", prefix, inode.name());
+        if inode.is_dir() {
+            let path = format!("{}/", path);
+            sink.write(header(&path, inode, 0)?.as_bytes());
+            pack_inner(&dir.cd(&inode.name())?, &path, sink)?;
+        } else {
+            let file = dir.open_file(&inode.name())?;
+            sink.write(header(&path, inode, file.size())?.as_bytes());
+            let mut buf = vec![0; 64 * BLOCK_SIZE];
+            let mut offset = 0;
+            while offset < file.size() {
+                let len = file.read_at(offset, &mut buf)?;
+                let padded = div_ceil(len, BLOCK_SIZE) * BLOCK_SIZE;
+                buf[len..padded].fill(0);
+                sink.write(&buf[0..padded]);
+                offset += len;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn header(path: &str, inode: &INode, size: usize) -> Result<Header, TarError> {
+    let mut header = Header::empty();
+    header.set_path(path)?;
+    let mode = match inode.mode() {
+        0 if inode.is_dir() => 0o755,
+        0 => 0o644,
+        mode => mode as usize,
+    };
+    write_number(&mut header.mode, mode);
+    write_number(&mut header.uid, inode.uid() as usize);
+    write_number(&mut header.gid, inode.gid() as usize);
+    write_number(&mut header.size, size);
+    write_number(&mut header.mtime, inode.mtime() as usize);
+    header.typeflag = if inode.is_dir() { b'5' } else { b'0' };
+    header.magic[0..5].copy_from_slice(USTAR_MAGIC);
+    header.version = *b"00";
+    let chksum = header.checksum();
+    write_number(&mut header.chksum[0..7], chksum);
+    header.chksum[7] = b' ';
+    Ok(header)
+}
@@ -0,0 +1,129 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use super::cache::{
+    get_meta_cache,
+    read_blocks,
+    write_blocks,
+};
+use super::div_ceil;
+use super::device::BlockDevice;
+use super::fat::{
+    alloc_clusters,
+    dealloc_clusters,
+    read_clusters,
+};
+use super::inode::{
+    INode,
+    XATTR_INLINE,
+};
+use super::journal;
+use super::sblock::SuperBlock;
+
+/// longest attribute name, in bytes
+pub const XATTR_NAME_LEN: usize = 255;
+/// longest attribute value, in bytes
+pub const XATTR_VALUE_LEN: usize = 0xFFFF;
+
+// name length, value length
+const HEADER_SIZE: usize = 3;
+
+pub(crate) type Attrs = BTreeMap<String, Vec<u8>>;
+
+pub(crate) fn is_valid(name: &str, value: &[u8]) -> bool {
+    !name.is_empty() && name.len() <= XATTR_NAME_LEN && value.len() <= XATTR_VALUE_LEN
+}
+
+// entries follow each other, a zero name length ends them
+fn parse(data: &[u8], attrs: &mut Attrs) {
+    let mut pos = 0;
+    while pos + HEADER_SIZE <= data.len() && data[pos] != 0 {
+        let name_len = data[pos] as usize;
+        let value_len = u16::from_le_bytes([data[pos + 1], data[pos + 2]]) as usize;
+        let start = pos + HEADER_SIZE;
+        let end = start + name_len + value_len;
+        if end > data.len() {
+            break;
+        }
+        let name = String::from_utf8_lossy(&data[start..start + name_len]).into();
+        attrs.insert(name, data[start + name_len..end].to_vec());
+        pos = end;
+    }
+}
+
+fn push_entry(data: &mut Vec<u8>, name: &str, value: &[u8]) {
+    data.push(name.len() as u8);
+    data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    data.extend_from_slice(name.as_bytes());
+    data.extend_from_slice(value);
+}
+
+pub(crate) fn read_attrs(inode: &INode, sblock: &SuperBlock, device: &Arc<dyn BlockDevice>) -> Attrs {
+    let mut attrs = BTreeMap::new();
+    parse(&inode.i_xattr, &mut attrs);
+    if inode.xattr_cluster() != 0 {
+        let bpc = sblock.byte_per_cluster();
+        let clusters = read_clusters(inode.xattr_cluster());
+        let mut data = vec![0; clusters.len() * bpc];
+        for (idx, &cluster) in clusters.iter().enumerate() {
+            read_blocks(sblock.offset(cluster), &mut data[idx * bpc..(idx + 1) * bpc], device);
+        }
+        parse(&data, &mut attrs);
+    }
+    attrs
+}
+
+// the entries kept in the inode, and the ones past them
+fn split(attrs: &Attrs) -> (Vec<u8>, Vec<u8>) {
+    let mut inline = Vec::new();
+    let mut rest = Vec::new();
+    for (name, value) in attrs.iter() {
+        match inline.len() + HEADER_SIZE + name.len() + value.len() <= XATTR_INLINE {
+            true => push_entry(&mut inline, name, value),
+            false => push_entry(&mut rest, name, value),
+        }
+    }
+    (inline, rest)
+}
+
+/// clusters storing `attrs` takes beyond the chain `inode` has now,
+/// 0 while they fit in the inode
+pub(crate) fn growth(inode: &INode, attrs: &Attrs, sblock: &SuperBlock) -> usize {
+    let old = match inode.xattr_cluster() {
+        0 => 0,
+        cluster => read_clusters(cluster).len(),
+    };
+    div_ceil(split(attrs).1.len(), sblock.byte_per_cluster()).saturating_sub(old)
+}
+
+/// store `attrs` for the inode at `addr`, the attributes that fit stay in the inode,
+/// the others go to a new chain, so a chain shared with a snapshot is left alone
+pub(crate) fn write_attrs(addr: usize, attrs: &Attrs, sblock: &SuperBlock, device: &Arc<dyn BlockDevice>) {
+    let (inline, mut rest) = split(attrs);
+
+    journal::begin();
+    let old = get_meta_cache(addr, device).lock().read(0, |inode: &INode| inode.xattr_cluster());
+    if old != 0 {
+        dealloc_clusters(old);
+    }
+    let cluster = match rest.is_empty() {
+        true => 0,
+        false => {
+            let bpc = sblock.byte_per_cluster();
+            let clusters = alloc_clusters(rest.len());
+            rest.resize(clusters.len() * bpc, 0);
+            for (idx, &cluster) in clusters.iter().enumerate() {
+                write_blocks(sblock.offset(cluster), &rest[idx * bpc..(idx + 1) * bpc], device);
+            }
+            clusters[0]
+        }
+    };
+    get_meta_cache(addr, device).lock().modify(0, |inode: &mut INode| {
+        inode.i_xattr = [0; XATTR_INLINE];
+        inode.i_xattr[0..inline.len()].copy_from_slice(&inline);
+        inode.i_xattr_cluster = cluster as u32;
+    });
+    journal::commit();
+}
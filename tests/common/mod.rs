@@ -0,0 +1,192 @@
+// every test binary keeps one disk, the block cache is keyed by address alone,
+// so tests that format it run one at a time
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use lazy_static::lazy_static;
+use fefs::cache::get_block_cache;
+use fefs::device::{AsyncBlockDevice, BlockDevice, BlockFuture};
+use fefs::host::RamDisk;
+use fefs::system::{fat_sectors_for, FileSystem};
+
+/// a RAM disk counting the requests it gets, writes are dropped while it is frozen
+/// or once its write limit is reached
+pub struct Disk {
+    ram: RamDisk,
+    sector: usize,
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+    limit: AtomicUsize,
+    frozen: AtomicBool,
+}
+
+impl Disk {
+    pub fn new(size: usize, sector: usize) -> Self {
+        Self {
+            ram: RamDisk::new(size),
+            sector,
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+            limit: AtomicUsize::new(usize::MAX),
+            frozen: AtomicBool::new(false),
+        }
+    }
+
+    pub fn reads(&self) -> usize {
+        self.reads.load(Ordering::SeqCst)
+    }
+
+    pub fn writes(&self) -> usize {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    /// thawing lifts the write limit as well
+    pub fn freeze(&self, frozen: bool) {
+        self.frozen.store(frozen, Ordering::SeqCst);
+        if !frozen {
+            self.limit.store(usize::MAX, Ordering::SeqCst);
+        }
+    }
+
+    /// drop every write after the next `writes` ones
+    pub fn cut_after(&self, writes: usize) {
+        self.limit.store(self.writes() + writes, Ordering::SeqCst);
+    }
+}
+
+impl BlockDevice for Disk {
+    fn read(&self, addr: usize, buf: &mut [u8]) {
+        BlockDevice::read_blocks(self, addr, buf)
+    }
+
+    fn write(&self, addr: usize, buf: &[u8]) {
+        BlockDevice::write_blocks(self, addr, buf)
+    }
+
+    fn size(&self) -> usize {
+        self.ram.size()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector
+    }
+
+    fn read_blocks(&self, addr: usize, buf: &mut [u8]) {
+        assert!(addr % self.sector == 0 && buf.len() % self.sector == 0);
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.ram.read_blocks(addr, buf)
+    }
+
+    fn write_blocks(&self, addr: usize, buf: &[u8]) {
+        assert!(addr % self.sector == 0 && buf.len() % self.sector == 0);
+        let count = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+        if !self.frozen.load(Ordering::SeqCst) && count <= self.limit.load(Ordering::SeqCst) {
+            self.ram.write_blocks(addr, buf)
+        }
+    }
+
+    fn as_async(&self) -> Option<&dyn AsyncBlockDevice> {
+        Some(self)
+    }
+}
+
+// pending once, as a device still busy with the request
+struct Busy(bool);
+
+impl Future for Busy {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        match self.0 {
+            true => Poll::Ready(()),
+            false => {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncBlockDevice for Disk {
+    fn read<'a>(&'a self, addr: usize, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            Busy(false).await;
+            BlockDevice::read_blocks(self, addr, buf)
+        })
+    }
+
+    fn write<'a>(&'a self, addr: usize, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            Busy(false).await;
+            BlockDevice::write_blocks(self, addr, buf)
+        })
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector
+    }
+}
+
+/// run `future` to the end, polling it again whenever it is pending
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    fn noop(_: *const ()) {}
+    fn clone(data: *const ()) -> RawWaker {
+        RawWaker::new(data, &VTABLE)
+    }
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    let waker = unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) };
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+            return value;
+        }
+    }
+}
+
+lazy_static! {
+    static ref LOCK: Mutex<()> = Mutex::new(());
+    pub static ref DISK: Arc<Disk> = Arc::new(Disk::new(8 << 20, 512));
+}
+
+/// held by a test for as long as it uses the disk
+pub fn lock() -> MutexGuard<'static, ()> {
+    LOCK.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+pub fn device() -> Arc<dyn BlockDevice> {
+    DISK.clone()
+}
+
+/// a fresh volume of 512-byte clusters over the whole disk, with a journal
+pub fn format() -> Arc<spin::Mutex<FileSystem>> {
+    format_clusters(1)
+}
+
+/// the same with clusters of `sector_per_cluster` sectors
+pub fn format_clusters(sector_per_cluster: usize) -> Arc<spin::Mutex<FileSystem>> {
+    let device = device();
+    let fat = fat_sectors_for(device.size(), 512, sector_per_cluster).unwrap();
+    FileSystem::format(device, 512, sector_per_cluster, 1 + fat, "test", 16)
+}
+
+/// lose whatever did not reach the disk yet, as a power cut would,
+/// the blocks still cached are pushed out while nothing gets written
+pub fn power_cut(disk: &Arc<Disk>) {
+    let device: Arc<dyn BlockDevice> = disk.clone();
+    let sector = fefs::cache::BLOCK_CACHE_MANAGER.lock().sector_size();
+    disk.freeze(true);
+    for idx in 1..=64 {
+        get_block_cache(device.size() - idx * sector, &device);
+    }
+    disk.freeze(false);
+}
+
+pub fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use fefs::device::BlockDevice;
+use fefs::file::{FileError, WriteType};
+use fefs::host::{FileDevice, RamDisk};
+use common::{block_on, lock, format, pattern, DISK};
+
+#[test]
+fn read_ahead_batches_sequential_reads() {
+    let _lock = lock();
+    let fs = format();
+    let fs = fs.lock();
+    let mut root = fs.root();
+    // two files written in turns, so their chains are runs of 8 clusters
+    let mut a = root.create_file("a").unwrap();
+    let mut b = root.create_file("b").unwrap();
+    let data = pattern(200 * 512);
+    for chunk in data.chunks(4096) {
+        a.write(chunk, WriteType::Append).unwrap();
+        b.write(chunk, WriteType::Append).unwrap();
+    }
+    fs.sync();
+
+    let a = root.open_file("a").unwrap();
+    let before = DISK.reads();
+    let mut got = vec![0; data.len()];
+    let mut offset = 0;
+    while offset < data.len() {
+        let end = (offset + 100).min(data.len());
+        offset += a.read_at(offset, &mut got[offset..end]).unwrap();
+    }
+    assert_eq!(got, data);
+    assert!(DISK.reads() - before < 200 / 2, "{} reads for 200 sectors", DISK.reads() - before);
+}
+
+#[test]
+fn cluster_runs_move_in_one_request() {
+    let _lock = lock();
+    let fs = format();
+    let fs = fs.lock();
+    let mut root = fs.root();
+    let mut f = root.create_file("f").unwrap();
+    let data = pattern(64 * 512);
+    f.write(&vec![0; data.len()], WriteType::Append).unwrap();
+    fs.sync();
+
+    let before = DISK.writes();
+    f.write_at(0, &data).unwrap();
+    fs.sync();
+    // the run, then the inode and the journal
+    assert!(DISK.writes() - before < 16, "{} writes for 64 sectors", DISK.writes() - before);
+
+    let before = DISK.reads();
+    let mut got = vec![0; data.len()];
+    f.read_at(0, &mut got).unwrap();
+    assert_eq!(got, data);
+    assert!(DISK.reads() - before <= 2, "{} reads for 64 sectors", DISK.reads() - before);
+}
+
+#[test]
+fn async_paths_match_the_sync_ones() {
+    let _lock = lock();
+    let fs = format();
+    let fs = fs.lock();
+    let mut root = fs.root();
+    root.mkdir("sub").unwrap();
+    let mut sub = block_on(root.cd_async("sub")).unwrap();
+    sub.create_file("x").unwrap();
+    let mut f = block_on(sub.open_file_async("x")).unwrap();
+    let data = pattern(50000);
+    assert_eq!(block_on(f.write_at_async(0, &data)).unwrap(), data.len());
+    block_on(f.write_at_async(49990, b"0123456789abcdef")).unwrap();
+
+    assert_eq!(block_on(f.write_at_async(60000, b"x")).err(), Some(FileError::SeekValueOverFlow));
+
+    let f = sub.open_file("x").unwrap();
+    assert_eq!(f.size(), 50006);
+    let mut got = vec![0; 100];
+    assert_eq!(block_on(f.read_at_async(49950, &mut got)).unwrap(), 56);
+    assert_eq!(&got[..40], &data[49950..49990]);
+    assert_eq!(&got[40..56], b"0123456789abcdef");
+    let mut got = vec![0; 30000];
+    f.read_at(1234, &mut got).unwrap();
+    assert_eq!(&got[..], &data[1234..31234]);
+    assert!(block_on(root.lookup_async("sub")).unwrap().is_dir());
+}
+
+#[test]
+fn file_device_keeps_what_was_written() {
+    let path = std::env::temp_dir().join(format!("fefs-device-{}.img", std::process::id()));
+    {
+        let device = FileDevice::create(&path, 1 << 20).unwrap();
+        assert_eq!(device.size(), 1 << 20);
+        device.write_blocks(4096, &pattern(2048));
+    }
+    let device = FileDevice::open(&path).unwrap();
+    assert_eq!(device.size(), 1 << 20);
+    let mut got = vec![0; 2048];
+    device.read_blocks(4096, &mut got);
+    assert_eq!(got, pattern(2048));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn ram_disk_round_trips_its_data() {
+    let disk = RamDisk::from_vec(vec![0; 4096]);
+    disk.write(512, &[1; 512]);
+    assert_eq!(disk.size(), 4096);
+    assert_eq!(&disk.to_vec()[512..1024], &[1; 512][..]);
+    let copy = RamDisk::read_only(disk.to_vec());
+    let mut got = [0; 512];
+    copy.read(512, &mut got);
+    assert_eq!(got, [1; 512]);
+    assert!(copy.is_read_only());
+}
+
+#[test]
+#[should_panic(expected = "read-only")]
+fn read_only_ram_disk_refuses_writes() {
+    RamDisk::read_only(vec![0; 4096]).write(0, &[1; 512]);
+}
@@ -0,0 +1,163 @@
+mod common;
+
+use std::sync::Arc;
+use fefs::cache::{read_blocks, write_blocks};
+use fefs::fat::read_fat;
+use fefs::file::WriteType;
+use fefs::fsck::{check, repair, Action, Problem};
+use fefs::journal;
+use fefs::sblock::SuperBlock;
+use fefs::system::FileSystem;
+use common::{lock, device, format, pattern, power_cut, DISK};
+
+// point the FAT entry of `cluster` to `next` behind the back of the file system
+fn set_fat(sblock: &SuperBlock, cluster: usize, next: u32) {
+    let device = device();
+    let (addr, offset) = sblock.fat_entry(cluster);
+    let mut sector = vec![0; sblock.byte_per_sector()];
+    read_blocks(addr, &mut sector, &device);
+    sector[offset..offset + 4].copy_from_slice(&next.to_le_bytes());
+    write_blocks(addr, &sector, &device);
+}
+
+fn chain(sblock: &SuperBlock, cluster: usize) -> Vec<usize> {
+    let mut chain = vec![cluster];
+    loop {
+        match read_fat(*chain.last().unwrap(), sblock, &device()) {
+            0x0FFFFFFF => return chain,
+            next => chain.push(next),
+        }
+    }
+}
+
+#[test]
+fn check_reports_loops_short_chains_and_lost_clusters() {
+    let _lock = lock();
+    let device = device();
+    let (sblock, a, b) = {
+        let fs = format();
+        let fs = fs.lock();
+        let mut root = fs.root();
+        root.create_file("a").unwrap().write(&pattern(3000), WriteType::Append).unwrap();
+        root.create_file("b").unwrap().write(&pattern(3000), WriteType::Append).unwrap();
+        fs.sync();
+        (fs.sblock(), root.lookup("a").unwrap().cluster(), root.lookup("b").unwrap().cluster())
+    };
+    let report = check(&device);
+    assert!(report.is_clean(), "{:?}", report.problems);
+    assert_eq!((report.dirs, report.files), (0, 2));
+
+    // the second cluster of "a" leads back to its first,
+    // "b" ends after two clusters, the tails of both are lost
+    let a_chain = chain(&sblock, a);
+    let b_chain = chain(&sblock, b);
+    set_fat(&sblock, a_chain[1], a as u32);
+    set_fat(&sblock, b_chain[1], 0x0FFFFFFF);
+
+    let report = check(&device);
+    assert!(report.problems.iter().any(|problem| matches!(
+        problem,
+        Problem::ChainLoop { path, last: Some(last), cluster, .. }
+            if path == "/a" && *last == a_chain[1] && *cluster == a
+    )), "{:?}", report.problems);
+    assert!(report.problems.iter().any(|problem| matches!(
+        problem,
+        Problem::SizeMismatch { path, size: 3000, clusters: 2, .. } if path == "/b"
+    )));
+    let mut lost: Vec<usize> = report.problems.iter().filter_map(|problem| match problem {
+        Problem::LostCluster { cluster } => Some(*cluster),
+        _ => None,
+    }).collect();
+    lost.sort_unstable();
+    let mut tails: Vec<usize> = a_chain[2..].iter().chain(&b_chain[2..]).copied().collect();
+    tails.sort_unstable();
+    assert_eq!(lost, tails);
+
+    // each problem is one line of `key=value` pairs
+    let line = Problem::LostCluster { cluster: b_chain[2] }.to_string();
+    assert_eq!(line, format!("lost_cluster cluster={}", b_chain[2]));
+}
+
+#[test]
+fn repair_salvages_lost_chains_and_fixes_sizes() {
+    let _lock = lock();
+    let device = device();
+    let (sblock, addr, b) = {
+        let fs = format();
+        let fs = fs.lock();
+        let mut root = fs.root();
+        root.create_file("a").unwrap().write(&pattern(3000), WriteType::Append).unwrap();
+        root.create_file("b").unwrap().write(&pattern(3000), WriteType::Append).unwrap();
+        fs.sync();
+        let sblock = fs.sblock();
+        // one inode per sector, "a" is the first entry of the root
+        (sblock, sblock.offset(sblock.root_cluster()), root.lookup("b").unwrap().cluster())
+    };
+    // "a" loses its entry, "b" ends after two clusters
+    write_blocks(addr, &vec![0; sblock.byte_per_sector()], &device);
+    let b_chain = chain(&sblock, b);
+    set_fat(&sblock, b_chain[1], 0x0FFFFFFF);
+
+    // a dry run plans without writing
+    let (report, planned, _) = repair(&device, true, true);
+    assert!(!report.is_clean());
+    assert!(planned.iter().any(|action| matches!(action, Action::Salvage { .. })), "{:?}", planned);
+    assert!(planned.iter().any(|action| matches!(
+        action,
+        Action::SetSize { path, size: 1024, .. } if path == "/b"
+    )), "{:?}", planned);
+    assert_eq!(check(&device).problems, report.problems);
+
+    let (_, _, left) = repair(&device, true, false);
+    assert!(left.is_empty(), "{:?}", left);
+    let report = check(&device);
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    let fs = FileSystem::open(device);
+    let fs = fs.lock();
+    let root = fs.root();
+    assert!(!root.exist("a"));
+    assert_eq!(root.lookup("b").unwrap().size(), 1024);
+    let lost_found = root.cd("lost+found").unwrap();
+    let names: Vec<String> = lost_found.ls().iter().map(|inode| inode.name()).collect();
+    assert_eq!(names.len(), 2, "{:?}", names);
+    // the chains are kept whole, so the data of "a" is in one of them
+    let mut got = Vec::new();
+    let found = names.iter().any(|name| {
+        lost_found.open_file(name).unwrap().read_to_vec(&mut got).unwrap();
+        got.starts_with(&pattern(3000))
+    });
+    assert!(found);
+}
+
+#[test]
+fn journal_is_replayed_after_a_cut_past_the_commit() {
+    let _lock = lock();
+    let device = device();
+    {
+        let fs = format();
+        let fs = fs.lock();
+        let mut root = fs.root();
+        fs.sync();
+        journal::begin();
+        root.create_file("a").unwrap().write(&pattern(3000), WriteType::Append).unwrap();
+        // the block copies and the header reach the disk, nothing after them does
+        DISK.cut_after(2);
+        journal::commit();
+        power_cut(&DISK);
+    }
+
+    let report = check(&device);
+    assert!(matches!(report.problems[..], [Problem::PendingJournal { .. }]), "{:?}", report.problems);
+    let (_, planned, _) = repair(&device, false, true);
+    assert_eq!(planned, vec![Action::ReplayJournal]);
+
+    let fs = FileSystem::open(Arc::clone(&device));
+    let fs = fs.lock();
+    let mut got = Vec::new();
+    fs.root().open_file("a").unwrap().read_to_vec(&mut got).unwrap();
+    assert_eq!(got, pattern(3000));
+    drop(fs);
+    let report = check(&device);
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
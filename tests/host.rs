@@ -0,0 +1,131 @@
+mod common;
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use fefs::dir::DirError;
+use fefs::file::WriteType;
+use fefs::host::{extract, populate};
+use fefs::tar::{pack, unpack, TarError};
+use common::{lock, format, pattern};
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fefs-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn populate_copies_a_tree_and_reports_bad_names() {
+    let _lock = lock();
+    let host = scratch("populate");
+    std::fs::create_dir_all(host.join("etc/conf")).unwrap();
+    std::fs::write(host.join("etc/conf/a"), pattern(3000)).unwrap();
+    std::fs::write(host.join("top"), b"top").unwrap();
+    std::fs::set_permissions(host.join("top"), std::fs::Permissions::from_mode(0o640)).unwrap();
+    std::fs::write(host.join("a:b"), b"").unwrap();
+    std::fs::write(host.join("a-name-far-too-long"), b"").unwrap();
+
+    let fs = format();
+    let fs = fs.lock();
+    let mut root = fs.root();
+    let mut skipped = populate(&mut root, &host).unwrap();
+    skipped.sort_by(|a, b| a.path.cmp(&b.path));
+    let reasons: Vec<_> = skipped.into_iter().map(|s| (s.path, s.reason)).collect();
+    assert_eq!(reasons, vec![
+        (host.join("a-name-far-too-long"), DirError::NameTooLong),
+        (host.join("a:b"), DirError::IllegalChar),
+    ]);
+
+    let mut got = Vec::new();
+    root.cd("etc").unwrap().cd("conf").unwrap().open_file("a").unwrap().read_to_vec(&mut got).unwrap();
+    assert_eq!(got, pattern(3000));
+    assert_eq!(root.lookup("top").unwrap().mode() & 0o777, 0o640);
+
+    // a second run merges into what is there
+    std::fs::write(host.join("etc/conf/a"), b"new").unwrap();
+    populate(&mut root, &host).unwrap();
+    root.cd("etc").unwrap().cd("conf").unwrap().open_file("a").unwrap().read_to_vec(&mut got).unwrap();
+    assert_eq!(got, b"new");
+    std::fs::remove_dir_all(&host).unwrap();
+}
+
+#[test]
+fn extract_copies_the_volume_out() {
+    let _lock = lock();
+    let host = scratch("extract");
+    let fs = format();
+    let fs = fs.lock();
+    let mut root = fs.root();
+    let mut sub = root.mkdir("sub").unwrap();
+    sub.create_file("data").unwrap().write(&pattern(5000), WriteType::Append).unwrap();
+    sub.set_mode("data", 0o600).unwrap();
+    sub.set_times("data", 1_000_000, 1_600_000_000).unwrap();
+    root.create_file("empty").unwrap();
+
+    extract(&root, &host).unwrap();
+    assert_eq!(std::fs::read(host.join("sub/data")).unwrap(), pattern(5000));
+    assert!(std::fs::read(host.join("empty")).unwrap().is_empty());
+    let meta = std::fs::metadata(host.join("sub/data")).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+    let mtime = meta.modified().unwrap().duration_since(UNIX_EPOCH).unwrap();
+    assert_eq!(mtime.as_secs(), 1_600_000_000);
+
+    // a file that cannot be read stops the copy
+    fs.add_key(1, [9; 32]);
+    root.open_file("empty").unwrap().set_key(1).unwrap();
+    fs.remove_key(1);
+    assert!(extract(&root, host.join("again")).is_err());
+    std::fs::remove_dir_all(&host).unwrap();
+}
+
+// a ustar header with no data
+fn tar_header(name: &str, typeflag: u8) -> Vec<u8> {
+    let mut block = vec![0; 512];
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    block[100..107].copy_from_slice(b"0000644");
+    block[124..135].copy_from_slice(b"00000000000");
+    block[156] = typeflag;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    block[148..156].copy_from_slice(b"        ");
+    let sum: usize = block.iter().map(|&b| b as usize).sum();
+    block[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    block
+}
+
+#[test]
+fn tar_round_trips_and_skips_links() {
+    let _lock = lock();
+    let fs = format();
+    let fs = fs.lock();
+    let mut root = fs.root();
+    let mut src = root.mkdir("src").unwrap();
+    src.mkdir("dir").unwrap().create_file("big").unwrap().write(&pattern(70000), WriteType::Append).unwrap();
+    src.create_file("small").unwrap().write(b"small", WriteType::Append).unwrap();
+    src.set_mode("small", 0o600).unwrap();
+
+    let mut archive = Vec::new();
+    pack(&src, &mut archive).unwrap();
+    assert_eq!(archive.len() % 512, 0);
+    // a symlink before the end of the archive
+    let end = archive.len() - 1024;
+    archive.splice(end..end, tar_header("link", b'2'));
+
+    let mut copy = root.mkdir("copy").unwrap();
+    let skipped = unpack(&mut copy, &mut &archive[..]).unwrap();
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].path, "link");
+    assert_eq!(skipped[0].reason, TarError::Unsupported(b'2'));
+    let mut got = Vec::new();
+    copy.cd("dir").unwrap().open_file("big").unwrap().read_to_vec(&mut got).unwrap();
+    assert_eq!(got, pattern(70000));
+    copy.open_file("small").unwrap().read_to_vec(&mut got).unwrap();
+    assert_eq!(got, b"small");
+    assert_eq!(copy.lookup("small").unwrap().mode() & 0o777, 0o600);
+
+    // a damaged header is refused
+    archive[0] ^= 1;
+    assert_eq!(unpack(&mut copy, &mut &archive[..]).err(), Some(TarError::BadChecksum));
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use std::sync::Arc;
+use lazy_static::lazy_static;
+use fefs::device::BlockDevice;
+use fefs::file::WriteType;
+use fefs::fsck::check;
+use fefs::system::{fat_sectors_for, FileSystem};
+use common::{lock, pattern, Disk};
+
+lazy_static! {
+    // takes whole 4K sectors only
+    static ref DISK: Arc<Disk> = Arc::new(Disk::new(8 << 20, 4096));
+}
+
+fn device() -> Arc<dyn BlockDevice> {
+    DISK.clone()
+}
+
+#[test]
+fn volumes_of_4k_sectors_round_trip() {
+    let _lock = lock();
+    let device = device();
+    let text: Vec<u8> = b"the quick brown fox ".iter().copied().cycle().take(50000).collect();
+    {
+        let fat = fat_sectors_for(device.size(), 4096, 1).unwrap();
+        let fs = FileSystem::format(Arc::clone(&device), 4096, 1, 1 + fat, "big", 16);
+        let mut fs = fs.lock();
+        let mut root = fs.root();
+        let mut sub = root.mkdir("sub").unwrap();
+        for idx in 0..20 {
+            sub.create_file(&format!("f{}", idx)).unwrap()
+                .write(&pattern(5000 + idx), WriteType::Append).unwrap();
+        }
+        let mut c = root.create_file("c").unwrap();
+        c.set_compressed(true).unwrap();
+        c.set_checksummed(true).unwrap();
+        c.write(&text, WriteType::Append).unwrap();
+        c.write_at(7777, b"patched").unwrap();
+        fs.snapshot("s").unwrap();
+        sub.delete("f3").unwrap();
+        fs.sync();
+    }
+    let report = check(&device);
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    // the sector size is read back from the volume
+    let fs = FileSystem::open(Arc::clone(&device));
+    let fs = fs.lock();
+    assert_eq!(fs.sblock().byte_per_sector(), 4096);
+    assert_eq!(fs.statfs().cluster_size(), 4096);
+    let root = fs.root();
+    let mut got = Vec::new();
+    root.open_file("c").unwrap().read_to_vec(&mut got).unwrap();
+    let mut want = text;
+    want[7777..7784].copy_from_slice(b"patched");
+    assert_eq!(got, want);
+    let sub = root.cd("sub").unwrap();
+    assert!(!sub.exist("f3"));
+    sub.open_file("f19").unwrap().read_to_vec(&mut got).unwrap();
+    assert_eq!(got, pattern(5019));
+    fs.open_snapshot("s").unwrap().cd("sub").unwrap().open_file("f3").unwrap().read_to_vec(&mut got).unwrap();
+    assert_eq!(got, pattern(5003));
+}
+
+#[test]
+#[should_panic(expected = "sector size 512 does not fit the device")]
+fn volume_sectors_smaller_than_the_device_are_refused() {
+    let _lock = lock();
+    let device = device();
+    let fat = fat_sectors_for(device.size(), 512, 8).unwrap();
+    FileSystem::format(device, 512, 8, 1 + fat, "small", 0);
+}
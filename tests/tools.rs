@@ -0,0 +1,86 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// a scratch directory of its own for each test
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fefs-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run(bin: &str, args: &[&str]) -> Output {
+    Command::new(bin).args(args).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+const MKFS: &str = env!("CARGO_BIN_EXE_mkfs-fefs");
+const FSCK: &str = env!("CARGO_BIN_EXE_fsck-fefs");
+
+#[test]
+fn mkfs_formats_an_image_fsck_accepts() {
+    let dir = scratch("mkfs");
+    let image = dir.join("a.img");
+    let image = image.to_str().unwrap();
+    let output = run(MKFS, &["-s", "4M", "-L", "disk", "-c", "1024", image]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("label          disk"));
+    // the clusters listed are those the volume hands out
+    let df = stdout(&run(FEFS, &[image, "df"]));
+    let size = df.lines().nth(1).unwrap().split_whitespace().next().unwrap();
+    assert!(stdout(&output).contains(&format!("clusters ({} bytes)", size)), "{}", df);
+    assert_eq!(std::fs::metadata(image).unwrap().len(), 4 << 20);
+    let output = run(FSCK, &[image]);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout(&output));
+
+    let output = run(MKFS, &["-s", "2K", image]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("too small"));
+    let output = run(MKFS, &["-S", "1000", image]);
+    assert!(!output.status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+const FEFS: &str = env!("CARGO_BIN_EXE_fefs");
+
+#[test]
+fn fefs_cli_edits_an_image() {
+    let dir = scratch("cli");
+    let image = dir.join("a.img");
+    let image = image.to_str().unwrap();
+    let host = dir.join("hello.txt");
+    std::fs::write(&host, b"hello fefs\n").unwrap();
+    assert!(run(MKFS, &["-s", "4M", image]).status.success());
+
+    let fefs = |args: &[&str]| {
+        let mut all = vec![image];
+        all.extend_from_slice(args);
+        let output = run(FEFS, &all);
+        assert!(output.status.success(), "fefs {:?}: {}", args, stderr(&output));
+        stdout(&output)
+    };
+    fefs(&["mkdir", "-p", "a/b"]);
+    fefs(&["put", host.to_str().unwrap(), "a/b/hello"]);
+    assert!(fefs(&["ls", "a/b"]).contains("hello"));
+    assert_eq!(fefs(&["cat", "a/b/hello"]), "hello fefs\n");
+    fefs(&["mv", "a/b/hello", "a/greeting"]);
+    assert!(!fefs(&["ls", "a/b"]).contains("hello"));
+    let out = dir.join("out.txt");
+    fefs(&["get", "a/greeting", out.to_str().unwrap()]);
+    assert_eq!(std::fs::read(&out).unwrap(), b"hello fefs\n");
+    fefs(&["rm", "-r", "a"]);
+    assert!(!fefs(&["ls"]).contains('a'));
+
+    let output = run(FEFS, &[image, "cat", "missing"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).starts_with("fefs: missing:"));
+    assert_eq!(run(FSCK, &[image]).status.code(), Some(0));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,565 @@
+mod common;
+
+use std::sync::Arc;
+use fefs::cache::{read_blocks, write_blocks};
+use fefs::device::BlockDevice;
+use fefs::dir::DirError;
+use fefs::fat::{read_clusters, read_fat};
+use fefs::file::{FileError, WriteType};
+use fefs::fsck::{check, Problem};
+use fefs::inode::{FLAG_EXTENTS, NAME_LEN};
+use fefs::journal;
+use fefs::quota::QuotaKind;
+use fefs::sblock::get_sblock;
+use fefs::system::{fat_sectors_for, FileSystem};
+use common::{lock, device, format, format_clusters, pattern, power_cut, DISK};
+
+// flip a bit of the sector at `addr` behind the back of the file system
+fn flip(addr: usize) {
+    let device = device();
+    let mut sector = vec![0; device.sector_size()];
+    read_blocks(addr, &mut sector, &device);
+    sector[100] ^= 0x40;
+    write_blocks(addr, &sector, &device);
+}
+
+#[test]
+fn a_directory_is_not_moved_into_itself() {
+    let _lock = lock();
+    let fs = format();
+    let fs = fs.lock();
+    let mut root = fs.root();
+    let mut a = root.mkdir("a").unwrap();
+    let mut b = a.mkdir("b").unwrap();
+    let mut c = b.mkdir("c").unwrap();
+    assert_eq!(root.move_to("a", &mut a, "a").err(), Some(DirError::IntoItself));
+    assert_eq!(root.move_to("a", &mut c, "a").err(), Some(DirError::IntoItself));
+    assert_eq!(a.move_to("b", &mut c, "b").err(), Some(DirError::IntoItself));
+    assert_eq!(root.ls().len(), 1);
+    assert!(root.cd("a").unwrap().cd("b").unwrap().exist("c"));
+
+    // a sibling or a parent is fine
+    b.move_to("c", &mut root, "c").unwrap();
+    root.move_to("a", &mut c, "a").unwrap();
+    assert!(root.cd("c").unwrap().cd("a").unwrap().exist("b"));
+    fs.sync();
+    drop(fs);
+    let report = check(&device());
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn snapshots_keep_the_tree_they_froze() {
+    let _lock = lock();
+    let fs = format();
+    let mut fs = fs.lock();
+    let mut root = fs.root();
+    root.create_file("f").unwrap().write(&pattern(5000), WriteType::Append).unwrap();
+    fs.snapshot("s").unwrap();
+    assert_eq!(fs.snapshot("s").err(), Some(DirError::DirExist));
+    assert_eq!(fs.snapshots(), vec!["s".to_string()]);
+
+    // the live file copies its chain, the snapshot keeps the old one
+    let used = fs.statfs().used_clusters();
+    let mut f = root.open_file("f").unwrap();
+    f.write_at(5, b"hello").unwrap();
+    assert!(fs.statfs().used_clusters() > used);
+    let mut want = pattern(5000);
+    want[5..10].copy_from_slice(b"hello");
+    let mut got = Vec::new();
+    root.open_file("f").unwrap().read_to_vec(&mut got).unwrap();
+    assert_eq!(got, want);
+
+    let mut snapshot = fs.open_snapshot("s").unwrap();
+    let mut old = snapshot.open_file("f").unwrap();
+    old.read_to_vec(&mut got).unwrap();
+    assert_eq!(got, pattern(5000));
+    assert_eq!(old.write_at(0, b"x").err(), Some(FileError::ReadOnly));
+    assert_eq!(snapshot.mkdir("d").err(), Some(DirError::ReadOnly));
+
+    fs.rollback("s").unwrap();
+    fs.root().open_file("f").unwrap().read_to_vec(&mut got).unwrap();
+    assert_eq!(got, pattern(5000));
+    fs.delete_snapshot("s").unwrap();
+    assert!(fs.snapshots().is_empty());
+    fs.sync();
+    drop(fs);
+    let report = check(&device());
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn a_rollback_that_fails_changes_nothing() {
+    let _lock = lock();
+    let fs = format();
+    let mut fs = fs.lock();
+    let mut root = fs.root();
+    root.create_file("a").unwrap().write(&pattern(3000), WriteType::Append).unwrap();
+    fs.snapshot("s").unwrap();
+    root.create_file("b").unwrap().write(&pattern(3000), WriteType::Append).unwrap();
+    fs.sync();
+
+    // the root can not be emptied while a sector of it is corrupted
+    let addr = fs.sblock().offset(fs.sblock().root_cluster());
+    flip(addr);
+    assert_eq!(fs.rollback("s").err(), Some(DirError::Corrupted));
+    flip(addr);
+    assert!(root.exist("a") && root.exist("b"));
+
+    // no transaction is left open, a change reaches the disk with its commit
+    root.create_file("committed").unwrap();
+    let on_disk = read_clusters(fs.sblock().root_cluster()).iter().any(|&cluster| {
+        let mut raw = vec![0; 512];
+        device().read_blocks(fs.sblock().offset(cluster), &mut raw);
+        raw.windows(9).any(|name| name == b"committed")
+    });
+    assert!(on_disk);
+    fs.sync();
+    drop(fs);
+    let report = check(&device());
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn corrupted_metadata_is_reported_not_trusted() {
+    let _lock = lock();
+    let fs = format();
+    let fs = fs.lock();
+    let mut root = fs.root();
+    root.create_file("big").unwrap().write(&pattern(300 * 512), WriteType::Append).unwrap();
+    root.create_file("small").unwrap().write(b"hello", WriteType::Append).unwrap();
+    root.mkdir("d").unwrap().create_file("x").unwrap();
+    fs.sync();
+    let sblock = fs.sblock();
+    // the FAT sector holding the middle of "big", the sector of "x"
+    let (fat, _) = sblock.fat_entry(root.lookup("big").unwrap().cluster() + 150);
+    let dir = sblock.offset(root.lookup("d").unwrap().cluster());
+    flip(fat);
+    flip(dir);
+
+    let mut big = root.open_file("big").unwrap();
+    let mut got = Vec::new();
+    assert_eq!(big.read_to_vec(&mut got).err(), Some(FileError::Corrupted));
+    assert_eq!(big.write(b"x", WriteType::Append).err(), Some(FileError::Corrupted));
+    assert_eq!(root.cd("d").unwrap().open_file("x").err(), Some(DirError::Corrupted));
+    // the rest of the volume is still usable
+    root.open_file("small").unwrap().read_to_vec(&mut got).unwrap();
+    assert_eq!(got, b"hello");
+    let mut new = root.create_file("new").unwrap();
+    new.write(&pattern(300 * 512), WriteType::Append).unwrap();
+    new.read_to_vec(&mut got).unwrap();
+    assert_eq!(got, pattern(300 * 512));
+
+    fs.sync();
+    drop(fs);
+    let report = check(&device());
+    assert!(report.problems.contains(&Problem::BadChecksum { addr: fat }), "{:?}", report.problems);
+    assert!(report.problems.contains(&Problem::BadChecksum { addr: dir }), "{:?}", report.problems);
+}
+
+#[test]
+fn checksummed_files_catch_bit_rot() {
+    let _lock = lock();
+    let fs = format();
+    let fs = fs.lock();
+    let mut root = fs.root();
+    let mut f = root.create_file("f").unwrap();
+    f.set_checksummed(true).unwrap();
+    f.write(&pattern(3000), WriteType::Append).unwrap();
+    assert!(f.is_checksummed());
+    f.verify().unwrap();
+    fs.sync();
+
+    // the third cluster of data rots
+    let sblock = fs.sblock();
+    let f = root.open_file("f").unwrap();
+    let first = root.lookup("f").unwrap().cluster();
+    let second = read_fat(first, &sblock, &device());
+    flip(sblock.offset(read_fat(second, &sblock, &device())));
+    assert_eq!(f.verify().err(), Some(FileError::Corrupted));
+    let mut buf = [0; 100];
+    assert_eq!(f.read_at(0, &mut buf).unwrap(), 100);
+    assert_eq!(&buf[..], &pattern(100)[..]);
+    assert_eq!(f.read_at(1024, &mut buf).err(), Some(FileError::Corrupted));
+}
+
+#[test]
+fn compressed_files_read_back_and_seek() {
+    let _lock = lock();
+    // chunks start on a sector, so only clusters of several sectors save space
+    let fs = format_clusters(8);
+    let fs = fs.lock();
+    let mut root = fs.root();
+    let text: Vec<u8> = b"the quick brown fox jumps over the lazy dog\n".iter().copied().cycle().take(20000).collect();
+    let mut f = root.create_file("f").unwrap();
+    f.set_compressed(true).unwrap();
+    f.write(&text, WriteType::Append).unwrap();
+    f.write_at(7000, b"patched").unwrap();
+    let mut want = text;
+    want[7000..7007].copy_from_slice(b"patched");
+
+    let f = root.open_file("f").unwrap();
+    assert!(f.is_compressed());
+    let metadata = f.metadata();
+    assert_eq!(metadata.size(), 20000);
+    assert!(metadata.physical() < 20000 / 4, "{}", metadata.physical());
+    let mut buf = [0; 20];
+    assert_eq!(f.read_at(6995, &mut buf).unwrap(), 20);
+    assert_eq!(&buf[..], &want[6995..7015]);
+    let mut got = Vec::new();
+    f.read_to_vec(&mut got).unwrap();
+    assert_eq!(got, want);
+
+    fs.sync();
+    drop(fs);
+    let report = check(&device());
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn encrypted_files_need_their_key() {
+    let _lock = lock();
+    let fs = format();
+    let fs = fs.lock();
+    let mut root = fs.root();
+    let secret: Vec<u8> = b"hunter2 ".iter().copied().cycle().take(2000).collect();
+    fs.add_key(1, [7; 32]);
+    root.mkdir("safe").unwrap();
+    root.set_key("safe", 1).unwrap();
+    let mut safe = root.cd("safe").unwrap();
+    let mut f = safe.create_file("pw").unwrap();
+    f.write(&secret, WriteType::Append).unwrap();
+    assert!(f.is_encrypted());
+    assert_eq!(f.key_id(), 1);
+    fs.sync();
+
+    // what reaches the device is not the plain text
+    let device = device();
+    let mut raw = vec![0; 512];
+    read_blocks(fs.sblock().offset(safe.lookup("pw").unwrap().cluster()), &mut raw, &device);
+    assert_ne!(raw, secret[..512]);
+    let mut got = Vec::new();
+    safe.open_file("pw").unwrap().read_to_vec(&mut got).unwrap();
+    assert_eq!(got, secret);
+
+    // names stay readable, the data and new entries do not
+    fs.remove_key(1);
+    assert_eq!(safe.ls().iter().map(|inode| inode.name()).collect::<Vec<_>>(), vec!["pw"]);
+    assert_eq!(safe.open_file("pw").unwrap().read_to_vec(&mut got).err(), Some(FileError::NoKey));
+    assert_eq!(safe.create_file("other").err(), Some(DirError::NoKey));
+    assert_eq!(root.set_key("safe", 1).err(), Some(DirError::NoKey));
+
+    fs.add_key(1, [7; 32]);
+    safe.open_file("pw").unwrap().read_to_vec(&mut got).unwrap();
+    assert_eq!(got, secret);
+}
+
+#[test]
+fn rewritten_ciphertext_does_not_repeat_a_keystream() {
+    let _lock = lock();
+    let fs = format();
+    let fs = fs.lock();
+    let mut root = fs.root();
+    fs.add_key(1, [7; 32]);
+    let sealed = |name: &str| {
+        let mut raw = vec![0; 512];
+        read_blocks(fs.sblock().offset(fs.root().lookup(name).unwrap().cluster()), &mut raw, &device());
+        raw
+    };
+    let old = pattern(2000);
+    for &(name, compressed) in [("plain", false), ("packed", true)].iter() {
+        let mut f = root.create_file(name).unwrap();
+        f.set_key(1).unwrap();
+        f.set_compressed(compressed).unwrap();
+        f.write(&old, WriteType::Append).unwrap();
+        let before = sealed(name);
+        // the same bytes sealed again with the same keystream would give the same ciphertext
+        f.write_at(0, &old[..512]).unwrap();
+        assert!(sealed(name) != before, "{}", name);
+
+        f.write_at(100, b"new").unwrap();
+        let mut want = old.clone();
+        want[100..103].copy_from_slice(b"new");
+        let mut got = Vec::new();
+        root.open_file(name).unwrap().read_to_vec(&mut got).unwrap();
+        assert_eq!(got, want);
+    }
+    fs.sync();
+    drop(fs);
+    let report = check(&device());
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn extended_attributes_are_kept_and_charged() {
+    let _lock = lock();
+    {
+        let fs = format();
+        let fs = fs.lock();
+        let mut root = fs.root();
+        root.mkdir("d").unwrap();
+        root.set_xattr("d", "security.label", b"system").unwrap();
+        let mut f = root.create_file("f").unwrap();
+        f.write(&pattern(1024), WriteType::Append).unwrap();
+        f.set_xattr("user.short", b"inline").unwrap();
+        f.set_xattr("user.long", &pattern(800)).unwrap();
+        f.set_xattr("user.gone", b"x").unwrap();
+        f.remove_xattr("user.gone").unwrap();
+        assert_eq!(f.remove_xattr("user.gone").err(), Some(FileError::NoAttr));
+        assert_eq!(f.set_xattr("", b"x").err(), Some(FileError::BadAttr));
+
+        // the owner pays for the attribute cluster, and only once
+        root.set_owner("f", 7, 8).unwrap();
+        let used = fs.quota(QuotaKind::User, 7).used();
+        assert!(fs.set_quota(QuotaKind::User, 7, 0, used));
+        let mut f = root.open_file("f").unwrap();
+        f.set_xattr("user.long", &pattern(700)).unwrap();
+        root.set_xattr("f", "user.tiny", b"t").unwrap();
+        assert_eq!(f.set_xattr("user.huge", &pattern(2000)).err(), Some(FileError::QuotaExceeded));
+        assert_eq!(fs.quota(QuotaKind::User, 7).used(), used);
+        fs.sync();
+    }
+
+    let fs = FileSystem::open(device());
+    let fs = fs.lock();
+    let root = fs.root();
+    assert_eq!(root.get_xattr("d", "security.label").unwrap(), b"system");
+    let f = root.open_file("f").unwrap();
+    let mut keys = f.list_xattr();
+    keys.sort();
+    assert_eq!(keys, vec!["user.long", "user.short", "user.tiny"]);
+    assert_eq!(f.get_xattr("user.short").unwrap(), b"inline");
+    assert_eq!(f.get_xattr("user.long").unwrap(), pattern(700));
+    assert_eq!(root.get_xattr("f", "user.gone").err(), Some(DirError::NoAttr));
+    drop(fs);
+    let report = check(&device());
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn quotas_follow_owners_and_stop_at_the_hard_limit() {
+    let _lock = lock();
+    {
+        let fs = format();
+        let fs = fs.lock();
+        let mut root = fs.root();
+        root.create_file("f").unwrap().write(&pattern(5000), WriteType::Append).unwrap();
+        let before = fs.quota(QuotaKind::User, 0).used();
+        root.set_owner("f", 7, 8).unwrap();
+        assert_eq!(fs.quota(QuotaKind::User, 0).used(), before - 10);
+        assert_eq!(fs.quota(QuotaKind::User, 7).used(), 10);
+        assert_eq!(fs.quota(QuotaKind::Group, 8).used(), 10);
+
+        assert!(fs.set_quota(QuotaKind::User, 7, 12, 16));
+        let mut f = root.open_file("f").unwrap();
+        f.write(&pattern(2000), WriteType::Append).unwrap();
+        assert!(fs.quota(QuotaKind::User, 7).over_soft());
+        assert_eq!(f.write(&pattern(2000), WriteType::Append).err(), Some(FileError::QuotaExceeded));
+        assert_eq!(f.size(), 7000);
+        assert_eq!(fs.quota(QuotaKind::User, 7).used(), 14);
+        fs.sync();
+    }
+
+    // the limits and usage are kept on the volume
+    let fs = FileSystem::open(device());
+    let fs = fs.lock();
+    let quota = fs.quota(QuotaKind::User, 7);
+    assert_eq!((quota.used(), quota.soft(), quota.hard()), (14, 12, 16));
+    fs.root().delete("f").unwrap();
+    assert_eq!(fs.quota(QuotaKind::User, 7).used(), 0);
+    assert_eq!(fs.quota(QuotaKind::Group, 8).used(), 0);
+    fs.sync();
+    drop(fs);
+    let report = check(&device());
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn statfs_counts_clusters_and_entries() {
+    let _lock = lock();
+    let fs = format();
+    let fs = fs.lock();
+    let mut root = fs.root();
+    let empty = fs.statfs();
+    assert_eq!(empty.cluster_size(), 512);
+    assert!(empty.clusters() * 512 < device().size());
+    assert_eq!(empty.name_len(), NAME_LEN);
+
+    root.create_file("f").unwrap().write(&pattern(5000), WriteType::Append).unwrap();
+    root.mkdir("d").unwrap();
+    let stat = fs.statfs();
+    assert_eq!(stat.clusters(), empty.clusters());
+    // ten data clusters, one for "d", and the root grows by one for the second entry
+    assert_eq!(stat.free_clusters(), empty.free_clusters() - 12);
+    assert_eq!(stat.inodes(), empty.inodes() + 2);
+    root.delete("f").unwrap();
+    assert_eq!(fs.statfs().free_clusters(), empty.free_clusters() - 2);
+    fs.sync();
+
+    // the counters are kept in the superblock
+    drop(fs);
+    let fs = FileSystem::open(device());
+    let stat = fs.lock().statfs();
+    assert_eq!(stat.free_clusters(), empty.free_clusters() - 2);
+    assert_eq!(stat.inodes(), empty.inodes() + 1);
+}
+
+#[test]
+fn the_free_hint_and_count_survive_a_remount() {
+    let _lock = lock();
+    let (hint, free) = {
+        let fs = format();
+        let fs = fs.lock();
+        let mut root = fs.root();
+        root.create_file("a").unwrap().write(&pattern(5000), WriteType::Append).unwrap();
+        root.create_file("b").unwrap().write(&pattern(5000), WriteType::Append).unwrap();
+        root.delete("a").unwrap();
+        fs.sync();
+        let sblock = get_sblock(&device());
+        assert!(sblock.is_clean());
+        (sblock.next_free(), fs.statfs().free_clusters())
+    };
+
+    // the freed clusters are still counted, new chains go on from the hint
+    let fs = FileSystem::open(device());
+    let fs = fs.lock();
+    assert_eq!(fs.statfs().free_clusters(), free);
+    let mut root = fs.root();
+    root.create_file("c").unwrap().write(&pattern(5000), WriteType::Append).unwrap();
+    assert_eq!(root.lookup("c").unwrap().cluster(), hint);
+}
+
+#[test]
+fn a_crash_before_the_commit_leaves_the_volume_as_it_was() {
+    let _lock = lock();
+    let device = device();
+    let free = {
+        let fs = format();
+        let fs = fs.lock();
+        let mut root = fs.root();
+        fs.sync();
+        let free = fs.statfs().free_clusters();
+        journal::begin();
+        root.create_file("a").unwrap().write(&pattern(3000), WriteType::Append).unwrap();
+        // only the block copies reach the disk
+        DISK.cut_after(1);
+        journal::commit();
+        power_cut(&DISK);
+        free
+    };
+
+    // marked dirty, but the counters are those of the volume before the change
+    assert!(!get_sblock(&device).is_clean());
+    let report = check(&device);
+    assert!(report.is_clean(), "{:?}", report.problems);
+    let fs = FileSystem::open(device);
+    let fs = fs.lock();
+    assert!(!fs.root().exist("a"));
+    assert_eq!(fs.statfs().free_clusters(), free);
+}
+
+// how many contiguous runs the chain from `cluster` makes
+fn runs(cluster: usize) -> usize {
+    let chain = read_clusters(cluster);
+    1 + chain.windows(2).filter(|pair| pair[1] != pair[0] + 1).count()
+}
+
+#[test]
+fn chains_are_allocated_in_runs() {
+    let _lock = lock();
+    let fs = format();
+    let fs = fs.lock();
+    let mut root = fs.root();
+    let mut f = root.create_file("f").unwrap();
+    for _ in 0..50 {
+        f.write(&pattern(700), WriteType::Append).unwrap();
+    }
+    assert_eq!(runs(root.lookup("f").unwrap().cluster()), 1);
+
+    // a file growing past a neighbour gets one more run, not one per cluster
+    let mut g = root.create_file("g").unwrap();
+    g.write(&pattern(1024), WriteType::Append).unwrap();
+    f.write(&pattern(10 * 512), WriteType::Append).unwrap();
+    assert_eq!(runs(root.lookup("f").unwrap().cluster()), 2);
+    let mut got = Vec::new();
+    f.read_to_vec(&mut got).unwrap();
+    assert_eq!(got.len(), 50 * 700 + 10 * 512);
+}
+
+#[test]
+fn extent_files_keep_their_runs_in_the_inode() {
+    let _lock = lock();
+    let mut want = Vec::new();
+    let runs = {
+        let fs = format();
+        let mut fs = fs.lock();
+        fs.enable_extents();
+        let mut root = fs.root();
+        let mut a = root.create_file("a").unwrap();
+        let mut b = root.create_file("b").unwrap();
+        // growing in turn leaves each file with many runs
+        for idx in 0..60 {
+            let chunk = vec![idx as u8; 700];
+            a.write(&chunk, WriteType::Append).unwrap();
+            b.write(&chunk, WriteType::Append).unwrap();
+            want.extend_from_slice(&chunk);
+        }
+        assert!(a.extents().len() > 20, "{}", a.extents().len());
+        assert_ne!(root.lookup("a").unwrap().flags() & FLAG_EXTENTS, 0);
+        fs.sync();
+        a.extents().to_vec()
+    };
+
+    let fs = FileSystem::open(device());
+    let fs = fs.lock();
+    let a = fs.root().open_file("a").unwrap();
+    assert_eq!(a.extents(), &runs[..]);
+    let mut got = Vec::new();
+    a.read_to_vec(&mut got).unwrap();
+    assert_eq!(got, want);
+    let mut buf = [0; 1000];
+    a.read_at(21234, &mut buf).unwrap();
+    assert_eq!(&buf[..], &want[21234..22234]);
+    drop(fs);
+    let report = check(&device());
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+// a device that does not tell its size
+struct Sizeless(Arc<dyn BlockDevice>);
+
+impl BlockDevice for Sizeless {
+    fn read(&self, addr: usize, buf: &mut [u8]) {
+        self.0.read(addr, buf)
+    }
+
+    fn write(&self, addr: usize, buf: &[u8]) {
+        self.0.write(addr, buf)
+    }
+}
+
+#[test]
+fn the_fat_is_sized_to_the_device() {
+    let _lock = lock();
+    // the most FAT sectors whose clusters all fit
+    for &(size, bps, spc) in &[(8 << 20, 512, 1), (8 << 20, 4096, 2), (32 << 30, 4096, 8), (1 << 20, 1024, 4)] {
+        let fat = fat_sectors_for(size, bps, spc).unwrap();
+        let clusters = |fat: usize| (fat * bps / 4 - 2) * spc;
+        assert!(1 + fat + clusters(fat) <= size / bps);
+        assert!(1 + fat + 1 + clusters(fat + 1) > size / bps);
+    }
+    // the superblock, a FAT sector, the root and the quota file at the least
+    assert_eq!(fat_sectors_for(2048, 512, 1), Some(1));
+    assert_eq!(fat_sectors_for(1536, 512, 1), None);
+
+    let device = device();
+    let sblock = FileSystem::create(Arc::clone(&device), 512, 1).lock().sblock();
+    assert_eq!(sblock.sector_per_fat(), 1 + fat_sectors_for(device.size(), 512, 1).unwrap());
+    let last = sblock.fat_entries() - 1;
+    assert!(sblock.offset(last) + sblock.byte_per_cluster() <= device.size());
+
+    // without a size the FAT takes 2 * sector_per_cluster - 1 sectors
+    assert_eq!(Sizeless(Arc::clone(&device)).size(), usize::MAX);
+    let sblock = FileSystem::create(Arc::new(Sizeless(device)), 512, 1).lock().sblock();
+    assert_eq!(sblock.sector_per_fat(), 2);
+}